@@ -0,0 +1,301 @@
+use common_lib::datagram_auth::{Direction, SessionKey};
+use common_lib::framing::{FrameReader, write_frame};
+use common_lib::udp_batch::ReceiveBatch;
+#[cfg(unix)]
+use common_lib::{UDP_CLIENT_RECEIVE_PERIOD, UNIX_CONNECTION_WAIT_PERIOD};
+use common_lib::UNIX_STREAM_PREFIX;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::{fs, path::Path, thread, time::Instant};
+
+/// Размер буфера для одной udp датаграммы
+pub const UDP_BUFFER_SIZE: usize = 2048;
+
+/// Права на файл unix сокета. Подключиться к сокету может только владелец и его группа
+#[cfg(unix)]
+const UNIX_SOCKET_MODE: u32 = 0o660;
+
+/// Соединение, через которое клиент получает котировки: udp сокет, tcp соединение запроса STREAM
+/// или unix сокет, к которому подключился сервер
+pub enum ClientSocket {
+    Udp {
+        socket: UdpSocket,
+        /// Датаграммы, прочитанные одним системным вызовом
+        batch: ReceiveBatch,
+        /// Ключ подписи датаграмм из ответа OK. В multicast режиме это ключ, общий для всех групп сервера
+        key: Option<SessionKey>,
+        /// Сколько датаграмм отброшено из-за неверной подписи или повторного номера
+        rejected: u64,
+    },
+    Tcp {
+        stream: TcpStream,
+        reader: FrameReader,
+        peer: SocketAddr,
+    },
+    #[cfg(unix)]
+    Unix {
+        stream: UnixStream,
+        reader: FrameReader,
+        path: PathBuf,
+    },
+}
+
+/// Откуда пришло сообщение
+pub enum Peer {
+    Inet(SocketAddr),
+    /// Сервер подключился к unix сокету клиента по этому пути
+    Unix(PathBuf),
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Inet(address) => write!(f, "{address}"),
+            Peer::Unix(path) => write!(f, "{UNIX_STREAM_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+impl ClientSocket {
+    ///
+    /// Создать udp соединение для получения котировок. Датаграммы читаются пачками
+    ///
+    /// # Arguments
+    ///
+    /// * `socket`: udp сокет
+    ///
+    /// returns: ClientSocket
+    ///
+    pub fn udp(socket: UdpSocket) -> Self {
+        ClientSocket::Udp {
+            socket,
+            batch: ReceiveBatch::new(UDP_BUFFER_SIZE),
+            key: None,
+            rejected: 0,
+        }
+    }
+
+    ///
+    /// Подписывать датаграммы ключом сессии и принимать только датаграммы с верной подписью.
+    /// Для потоковых соединений ничего не меняет
+    ///
+    /// # Arguments
+    ///
+    /// * `session_key`: ключ из ответа OK
+    ///
+    pub fn set_key(&mut self, session_key: SessionKey) {
+        if let ClientSocket::Udp { key, .. } = self {
+            *key = Some(session_key);
+        }
+    }
+
+    ///
+    /// Создать tcp соединение для получения котировок
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: соединение, по которому отправили запрос STREAM
+    /// * `pending`: байты, которые прочитали из соединения вместе с ответом OK
+    ///
+    /// returns: io::Result<ClientSocket>
+    ///
+    pub fn tcp(stream: TcpStream, pending: Vec<u8>) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        let peer = stream.peer_addr()?;
+        Ok(ClientSocket::Tcp {
+            stream,
+            reader: FrameReader::new(pending),
+            peer,
+        })
+    }
+
+    ///
+    /// Создать unix сокет, к которому подключится сервер. Сокет создается до запроса STREAM,
+    /// что бы серверу было куда подключаться. Старый файл сокета, оставшийся от прошлого запуска, удаляется
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: путь до файла сокета
+    ///
+    /// returns: io::Result<UnixListener>
+    ///
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("Файл {} существует и не является сокетом", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(UNIX_SOCKET_MODE))?;
+        Ok(listener)
+    }
+
+    ///
+    /// Дождаться подключения сервера к unix сокету. Файл сокета после этого больше не нужен и удаляется
+    ///
+    /// # Arguments
+    ///
+    /// * `listener`: сокет созданный через bind_unix
+    /// * `path`: путь до файла сокета
+    ///
+    /// returns: io::Result<ClientSocket>
+    ///
+    #[cfg(unix)]
+    pub fn unix(listener: UnixListener, path: &Path) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let deadline = Instant::now() + UNIX_CONNECTION_WAIT_PERIOD;
+        let accepted = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break Ok(stream),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                    thread::sleep(UDP_CLIENT_RECEIVE_PERIOD);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "Сервер не подключился к {UNIX_STREAM_PREFIX}{} за {} секунд",
+                            path.display(),
+                            UNIX_CONNECTION_WAIT_PERIOD.as_secs()
+                        ),
+                    ));
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        let _ = fs::remove_file(path);
+        let stream = accepted?;
+        stream.set_nonblocking(true)?;
+        Ok(ClientSocket::Unix {
+            stream,
+            reader: FrameReader::default(),
+            path: path.to_path_buf(),
+        })
+    }
+
+    ///
+    /// Получить сообщение. Если сообщений нет, то возвращается ошибка WouldBlock
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: буфер для сообщения
+    ///
+    /// returns: io::Result<(usize, Peer)> - длина сообщения и адрес отправителя
+    ///
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, Peer)> {
+        match self {
+            ClientSocket::Udp {
+                socket,
+                batch,
+                key,
+                rejected,
+            } => loop {
+                let (n, from) = batch.recv_from(socket, buf)?;
+                let Some(key) = key else {
+                    return Ok((n, Peer::Inet(from)));
+                };
+                // Поддельные и повторные котировки и ответы отбрасываются, сообщение остается в начале буфера
+                match key.verify(Direction::ToClient, &buf[..n]) {
+                    Some(message) => return Ok((message.len(), Peer::Inet(from))),
+                    None => {
+                        *rejected += 1;
+                        log::warn!(
+                            "Отброшена датаграмма с неверной подписью или повторным номером от {from}. Всего отброшено {rejected}"
+                        );
+                    }
+                }
+            },
+            ClientSocket::Tcp {
+                stream,
+                reader,
+                peer,
+            } => Ok((receive_frame(reader, &*stream, buf)?, Peer::Inet(*peer))),
+            #[cfg(unix)]
+            ClientSocket::Unix {
+                stream,
+                reader,
+                path,
+            } => Ok((
+                receive_frame(reader, &*stream, buf)?,
+                Peer::Unix(path.clone()),
+            )),
+        }
+    }
+
+    ///
+    /// Отправить сообщение серверу. Для tcp адрес не нужен, сообщение уходит в соединение
+    ///
+    /// # Arguments
+    ///
+    /// * `message`: сообщение
+    /// * `address`: адрес сервера для udp
+    ///
+    pub fn send_to(&self, message: &[u8], address: &str) -> io::Result<()> {
+        match self {
+            ClientSocket::Udp {
+                socket, key: Some(key), ..
+            } => socket
+                .send_to(&key.sign(Direction::ToServer, message), address)
+                .map(|_| ()),
+            ClientSocket::Udp { socket, .. } => socket.send_to(message, address).map(|_| ()),
+            ClientSocket::Tcp { stream, .. } => write_frame(stream, message),
+            #[cfg(unix)]
+            ClientSocket::Unix { stream, .. } => write_frame(stream, message),
+        }
+    }
+
+    ///
+    /// Копия соединения для отправки сообщений из других потоков
+    ///
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            ClientSocket::Udp { socket, key, .. } => Ok(ClientSocket::Udp {
+                socket: socket.try_clone()?,
+                batch: ReceiveBatch::new(UDP_BUFFER_SIZE),
+                key: key.clone(),
+                rejected: 0,
+            }),
+            ClientSocket::Tcp { stream, peer, .. } => Ok(ClientSocket::Tcp {
+                stream: stream.try_clone()?,
+                reader: FrameReader::default(),
+                peer: *peer,
+            }),
+            #[cfg(unix)]
+            ClientSocket::Unix { stream, path, .. } => Ok(ClientSocket::Unix {
+                stream: stream.try_clone()?,
+                reader: FrameReader::default(),
+                path: path.clone(),
+            }),
+        }
+    }
+}
+
+///
+/// Прочитать кадр из потокового соединения. Если кадр еще не пришел целиком, то возвращается ошибка WouldBlock
+///
+fn receive_frame(reader: &mut FrameReader, stream: impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let Some(frame) = reader.read_frame(stream)? else {
+        return Err(io::ErrorKind::WouldBlock.into());
+    };
+    if frame.len() > buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Сервер прислал сообщение длиной {}", frame.len()),
+        ));
+    }
+    buf[..frame.len()].copy_from_slice(&frame);
+    Ok(frame.len())
+}
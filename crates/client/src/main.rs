@@ -1,21 +1,36 @@
+mod client_socket;
 mod udp_client_reader;
 
+use common_lib::datagram_auth::SessionKey;
 use common_lib::errors::ErrType;
-use common_lib::{OK_REQUEST, STREAM_REQUEST, TCP_CONNECTION_WAIT_PERIOD};
+use common_lib::{
+    AUTH_FAILED_ERROR, AUTH_REQUEST, AUTH_REQUIRED_ERROR, FILTER_OPTION, MAX_PACKETS_OPTION, MULTICAST_STREAM, NAT_STREAM,
+    NOT_ENTITLED_ERROR, OK_REQUEST, QUEUE_POLICY_OPTION,
+    QUEUE_SIZE_OPTION, QUOTES_WAIT_PERIOD, SESSION_KEY_OPTION, STREAM_REQUEST, TCP_CONNECTION_WAIT_PERIOD, TCP_STREAM,
+    TICKER_INTERVAL_OPTION, UDP_STREAM_PREFIX, UNIX_STREAM_PREFIX,
+};
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Write};
-use std::net::TcpStream;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs};
 
+#[cfg(unix)]
+use crate::client_socket::ClientSocket;
 use crate::udp_client_reader::ClientReader;
 use anyhow::{Result, bail};
 use clap::Parser;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 
 use fern::Dispatch;
 use log::{Level, LevelFilter};
 use std::fs::OpenOptions;
 
+/// Схема адреса сервера в параметре server
+const SERVER_URL_SCHEME: &str = "quotes://";
+
 #[derive(Debug, Parser)]
 #[command(
     name = "quote_client",
@@ -26,17 +41,74 @@ struct Cli {
     #[arg(long)]
     tickers_file: PathBuf,
 
+    #[arg(long, required_unless_present_any = ["multicast", "tcp", "unix_socket", "nat"])]
+    client_ip: Option<IpAddr>,
+
+    #[arg(long, required_unless_present_any = ["multicast", "tcp", "unix_socket", "nat"])]
+    client_port: Option<u16>,
+
+    /// Адрес сервера в виде `quotes://host:port` или `host:port`. Заменяет server-ip и server-port
+    #[arg(long, conflicts_with_all = ["server_ip", "server_port"])]
+    server: Option<String>,
+
+    /// Имя или ip адрес сервера, ipv4 или ipv6
+    #[arg(long, required_unless_present = "server")]
+    server_ip: Option<String>,
+
+    #[arg(long, required_unless_present = "server")]
+    server_port: Option<u16>,
+
+    /// Не чаще одного обновления каждой котировки за указанное количество миллисекунд
+    #[arg(long)]
+    ticker_interval_ms: Option<u64>,
+
+    /// Не больше указанного количества пакетов с котировками в секунду
     #[arg(long)]
-    client_ip: String,
+    max_packets_per_sec: Option<u32>,
 
+    /// Условие отправки котировки сервером, например `AAPL:move>0.5`. Можно передать несколько раз
     #[arg(long)]
-    client_port: u16,
+    filter: Vec<String>,
 
+    /// Размер очереди пакетов котировок на сервере
     #[arg(long)]
-    server_ip: String,
+    queue_size: Option<usize>,
 
+    /// Что делать серверу, если клиент не успевает забирать котировки: drop_oldest, conflate или disconnect
     #[arg(long)]
-    server_port: u16,
+    queue_policy: Option<String>,
+
+    /// Получать котировки через multicast группы сервера. Параметры подписки в этом режиме не поддерживаются
+    #[arg(
+        long,
+        conflicts_with_all = ["ticker_interval_ms", "max_packets_per_sec", "filter", "queue_size", "queue_policy"]
+    )]
+    multicast: bool,
+
+    /// Получать котировки через tcp соединение с сервером вместо udp. Нужно, если udp не проходит через firewall
+    #[arg(long, conflicts_with = "multicast")]
+    tcp: bool,
+
+    /// Получать котировки через unix сокет по указанному пути, если клиент работает на одной машине с сервером
+    #[arg(long, conflicts_with_all = ["multicast", "tcp"])]
+    unix_socket: Option<PathBuf>,
+
+    /// Клиент за NAT. Сервер отправляет котировки на адрес, с которого пришла регистрация клиента.
+    /// client-ip и client-port необязательные, по умолчанию порт выдает ОС
+    #[arg(long, conflicts_with_all = ["multicast", "tcp", "unix_socket"])]
+    nat: bool,
+
+    /// Интерфейс через который клиент подключается к multicast группам
+    #[arg(long, default_value_t = Ipv4Addr::UNSPECIFIED)]
+    multicast_iface: Ipv4Addr,
+
+    /// Токен для авторизации на сервере. Виден в списке процессов, поэтому лучше использовать token-file
+    #[arg(long, conflicts_with = "token_file")]
+    token: Option<String>,
+
+    /// Файл, в котором лежит токен для авторизации на сервере
+    #[arg(long)]
+    token_file: Option<PathBuf>,
 }
 
 fn read_tickers(file_name: &PathBuf) -> Result<HashSet<String>, ErrType> {
@@ -60,7 +132,7 @@ fn read_tickers(file_name: &PathBuf) -> Result<HashSet<String>, ErrType> {
     Ok(tickers)
 }
 
-fn setup_logger(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+fn setup_logger(file_log_name: String) -> Result<(), Box<dyn std::error::Error>> {
     // Общий формат
     let base_config = Dispatch::new()
         .format(|out, message, record| {
@@ -74,8 +146,6 @@ fn setup_logger(port: u16) -> Result<(), Box<dyn std::error::Error>> {
         })
         .level(LevelFilter::Debug); // максимально возможный уровень
 
-    let file_log_name = format!("client_{}.log", port);
-
     let file_logger = Dispatch::new()
         .level(LevelFilter::Debug)
         .chain(
@@ -99,32 +169,57 @@ fn setup_logger(port: u16) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-
-    let tickers = match read_tickers(&cli.tickers_file) {
-        Ok(tickers) => tickers,
-        Err(e) => {
-            bail!(e.to_string());
+///
+/// Получить все адреса сервера. Имя сервера разрешается через DNS, поэтому адресов может быть несколько
+///
+/// # Arguments
+///
+/// * `cli`: параметры клиента. Адрес берется из server или из server-ip и server-port
+///
+/// returns: Result<Vec<SocketAddr>>
+///
+fn resolve_server(cli: &Cli) -> Result<Vec<SocketAddr>> {
+    let resolved = match (&cli.server, &cli.server_ip, cli.server_port) {
+        (Some(url), _, _) => {
+            let host_port = url.strip_prefix(SERVER_URL_SCHEME).unwrap_or(url);
+            host_port.trim_end_matches('/').to_socket_addrs()
         }
+        (None, Some(host), Some(port)) => (host.as_str(), port).to_socket_addrs(),
+        _ => bail!("Не передан адрес сервера"),
     };
-    let tickers_join = tickers
-        .iter()
-        .map(String::as_str)
-        .collect::<Vec<&str>>()
-        .join(",");
-
-    if !cli
-        .server_ip
-        .chars()
-        .all(|c| c.is_ascii_digit() || c == '.')
-    {
-        bail!("IP адрес сервера должен содержать только числа и .");
+    let addresses = match resolved {
+        Ok(addresses) => addresses.collect::<Vec<SocketAddr>>(),
+        Err(e) => bail!("Не удалось получить адрес сервера. {e}"),
+    };
+    if addresses.is_empty() {
+        bail!("Имя сервера не разрешилось ни в один адрес");
     }
+    Ok(addresses)
+}
 
-    let server = format!("{}:{}", &cli.server_ip, cli.server_port);
-    let Ok(stream) = &mut TcpStream::connect(server.clone()) else {
-        bail!("Не удалось установить соединение с {server}");
+///
+/// Подключиться к серверу по tcp и запросить котировки
+///
+/// # Arguments
+///
+/// * `server`: адреса сервера. Подключение идет к первому доступному
+/// * `request`: запрос STREAM
+/// * `token`: токен авторизации. Если передан, то перед запросом STREAM отправляется AUTH
+///
+/// returns: Result<(String, TcpStream, Vec<u8>)> - то, что сервер передал в ответе после OK, само соединение и
+///     байты, которые прочитали из соединения вслед за ответом. Соединение нужно, если котировки идут по tcp
+///
+fn request_stream(
+    server: &[SocketAddr],
+    request: &str,
+    token: Option<&str>,
+) -> Result<(String, TcpStream, Vec<u8>)> {
+    let Ok(mut stream) = TcpStream::connect(server) else {
+        bail!("Не удалось установить соединение с {server:?}");
+    };
+    let server = match stream.peer_addr() {
+        Ok(address) => address.to_string(),
+        Err(_) => format!("{server:?}"),
     };
 
     let Ok(_) = stream.set_read_timeout(Some(TCP_CONNECTION_WAIT_PERIOD)) else {
@@ -141,9 +236,24 @@ fn main() -> Result<()> {
         bail!("Не удалось прочитать приветственное сообщение сервера")
     };
 
+    if let Some(token) = token {
+        let Ok(_) = stream.write_all(format!("{AUTH_REQUEST} {token}\n").as_bytes()) else {
+            bail!("Не удалось отправить токен серверу {server}")
+        };
+        stream.flush()?;
+        line.clear();
+        let Ok(_) = reader.read_line(&mut line) else {
+            bail!("Не удалось прочитать ответ от сервера {server} на авторизацию")
+        };
+        if line.trim_end() != OK_REQUEST.trim_end() {
+            if line.contains(AUTH_FAILED_ERROR) {
+                bail!("Сервер {server} не принял токен");
+            }
+            bail!("В ответ на авторизацию сервер прислал ответ {line}. Ожидалось OK");
+        }
+    }
+
     // Отправляем сообщение, что бы начать получать котировки
-    let address_udp = format!("{}:{}", &cli.client_ip, &cli.client_port);
-    let request = format!("{STREAM_REQUEST} udp://{address_udp} {tickers_join}\n");
     let Ok(_) = stream.write_all(request.as_bytes()) else {
         bail!("Не удалось отправить сообщение {request} серверу")
     };
@@ -153,44 +263,442 @@ fn main() -> Result<()> {
         bail!("Не удалось прочитать ответ от сервера {server}")
     };
 
-    // Если серверу все понравилось тогда запускаем udp соединение
-    if line != OK_REQUEST {
+    if line.contains(AUTH_REQUIRED_ERROR) {
+        bail!("Сервер {server} требует авторизацию. Передайте токен через token или token-file");
+    }
+    if let Some((_, denied)) = line.trim_end().split_once(NOT_ENTITLED_ERROR) {
+        bail!("Сервер {server} отказал в подписке.{denied}");
+    }
+    // Если серверу все понравилось тогда можно получать котировки
+    let Some(reply) = line.trim_end().strip_prefix(OK_REQUEST.trim_end()) else {
         bail!("В ответ на сообщение {request} сервер прислал ответ {line}. Ожидалось OK");
+    };
+    let pending = reader.buffer().to_vec();
+    Ok((reply.trim().to_string(), stream, pending))
+}
+
+///
+/// Собрать необязательные параметры подписки для запроса STREAM
+///
+fn subscription_options(cli: &Cli) -> Result<String> {
+    let mut options = String::new();
+    if let Some(interval) = cli.ticker_interval_ms {
+        options.push_str(&format!(" {TICKER_INTERVAL_OPTION}={interval}"));
+    }
+    if let Some(packets) = cli.max_packets_per_sec {
+        options.push_str(&format!(" {MAX_PACKETS_OPTION}={packets}"));
+    }
+    if let Some(size) = cli.queue_size {
+        options.push_str(&format!(" {QUEUE_SIZE_OPTION}={size}"));
+    }
+    if let Some(policy) = &cli.queue_policy {
+        options.push_str(&format!(" {QUEUE_POLICY_OPTION}={policy}"));
+    }
+    for filter in &cli.filter {
+        if filter.contains(char::is_whitespace) {
+            bail!("Фильтр {filter} не должен содержать пробелов");
+        }
+        options.push_str(&format!(" {FILTER_OPTION}={filter}"));
+    }
+    Ok(options)
+}
+
+///
+/// Токен авторизации из параметра token или из файла token-file
+///
+fn auth_token(cli: &Cli) -> Result<Option<String>> {
+    if let Some(path) = &cli.token_file {
+        let token = match std::fs::read_to_string(path) {
+            Ok(token) => token.trim().to_string(),
+            Err(e) => bail!("Не удалось прочитать токен из файла {}. {e}", path.display()),
+        };
+        if token.is_empty() || token.contains(char::is_whitespace) {
+            bail!("Файл {} должен содержать токен без пробелов", path.display());
+        }
+        return Ok(Some(token));
+    }
+    if let Some(token) = &cli.token
+        && (token.is_empty() || token.contains(char::is_whitespace))
+    {
+        bail!("Токен не должен быть пустым и содержать пробелы");
+    }
+    Ok(cli.token.clone())
+}
+
+///
+/// Сколько ждать котировки, прежде чем считать их потерянными, с учетом параметров подписки
+///
+fn quotes_wait_period(cli: &Cli) -> Option<Duration> {
+    if !cli.filter.is_empty() {
+        // С фильтрами котировки могут не приходить долго, это не считается потерей
+        None
+    } else if let Some(interval) = cli.ticker_interval_ms {
+        // Сервер специально присылает котировки реже, поэтому и ждем их дольше
+        Some(QUOTES_WAIT_PERIOD + Duration::from_millis(interval))
+    } else {
+        Some(QUOTES_WAIT_PERIOD)
+    }
+}
+
+///
+/// Получать котировки через unix сокет. Сокет создается до запроса, сервер подключается к нему сам
+///
+/// # Arguments
+///
+/// * `cli`: параметры клиента
+/// * `server`: адрес сервера для запроса STREAM
+/// * `path`: путь до файла сокета
+/// * `tickers`: список котировок
+/// * `tickers_join`: список котировок для запроса
+/// * `stoper`: атомик по которому завершает работу клиент
+///
+/// returns: Result<()>
+///
+#[cfg(unix)]
+fn read_unix(
+    cli: &Cli,
+    server: &[SocketAddr],
+    path: &PathBuf,
+    tickers: HashSet<String>,
+    tickers_join: &str,
+    stoper: Arc<AtomicBool>,
+) -> Result<()> {
+    let listener = match ClientSocket::bind_unix(path) {
+        Ok(listener) => listener,
+        Err(e) => bail!("Не удалось создать unix сокет {}. {e}", path.display()),
+    };
+
+    let options = subscription_options(cli)?;
+    let request = format!(
+        "{STREAM_REQUEST} {UNIX_STREAM_PREFIX}{} {tickers_join}{options}\n",
+        path.display()
+    );
+    if let Err(e) = request_stream(server, &request, auth_token(cli)?.as_deref()) {
+        let _ = std::fs::remove_file(path);
+        return Err(e);
+    }
+
+    let mut unix_reader = match ClientReader::new_unix(listener, path, tickers, stoper) {
+        Ok(reader) => reader,
+        Err(e) => {
+            bail!(e.to_string());
+        }
+    };
+    unix_reader.set_quotes_wait_period(quotes_wait_period(cli));
+    // Как и для tcp, разрыв соединения замечает ОС, поэтому ping не нужен
+    if let Err(e) = unix_reader.command_reader() {
+        bail!(e.to_string());
+    }
+    if let Err(e) = unix_reader.start() {
+        bail!(e.to_string());
     }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn read_unix(
+    _cli: &Cli,
+    _server: &[SocketAddr],
+    _path: &PathBuf,
+    _tickers: HashSet<String>,
+    _tickers_join: &str,
+    _stoper: Arc<AtomicBool>,
+) -> Result<()> {
+    bail!("Unix сокеты не поддерживаются на этой платформе")
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let tickers = match read_tickers(&cli.tickers_file) {
+        Ok(tickers) => tickers,
+        Err(e) => {
+            bail!(e.to_string());
+        }
+    };
+    let tickers_join = tickers
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .join(",");
 
     let stoper = match common_lib::ctrlc::ctrlc_handler() {
         Ok(stoper) => stoper,
         Err(e) => bail!(e.to_string()),
     };
 
-    let Ok(_) = setup_logger(cli.client_port) else {
+    let server = resolve_server(&cli)?;
+    let server_ips = server.iter().map(SocketAddr::ip).collect::<Vec<IpAddr>>();
+
+    if cli.multicast {
+        let Ok(_) = setup_logger(format!("client_multicast_{}.log", std::process::id())) else {
+            bail!("Не удалось запустить логер")
+        };
+
+        // Сервер отвечает списком групп в которые рассылаются запрошенные котировки
+        let request = format!("{STREAM_REQUEST} {MULTICAST_STREAM} {tickers_join}\n");
+        let (reply, _, _) = request_stream(&server, &request, auth_token(&cli)?.as_deref())?;
+        let (groups, key) = parse_multicast_reply(&reply)?;
+
+        let mut udp_reader = match ClientReader::new_multicast(
+            &groups,
+            cli.multicast_iface,
+            server_ips,
+            tickers,
+            stoper,
+        ) {
+            Ok(reader) => reader,
+            Err(e) => {
+                bail!(e.to_string());
+            }
+        };
+        udp_reader.set_session_key(key);
+        // Рассылка общая, поэтому ни ping, ни запроса снимка в этом режиме нет
+        if let Err(e) = udp_reader.start() {
+            bail!(e.to_string());
+        }
+        return Ok(());
+    }
+
+    if cli.tcp {
+        let Ok(_) = setup_logger(format!("client_tcp_{}.log", std::process::id())) else {
+            bail!("Не удалось запустить логер")
+        };
+
+        let options = subscription_options(&cli)?;
+        let request = format!("{STREAM_REQUEST} {TCP_STREAM} {tickers_join}{options}\n");
+        let (_, stream, pending) = request_stream(&server, &request, auth_token(&cli)?.as_deref())?;
+
+        let mut tcp_reader = match ClientReader::new_tcp(stream, pending, tickers, stoper) {
+            Ok(reader) => reader,
+            Err(e) => {
+                bail!(e.to_string());
+            }
+        };
+        tcp_reader.set_quotes_wait_period(quotes_wait_period(&cli));
+        // Живо ли соединение проверяет tcp, поэтому ping не нужен
+        if let Err(e) = tcp_reader.command_reader() {
+            bail!(e.to_string());
+        }
+        if let Err(e) = tcp_reader.start() {
+            bail!(e.to_string());
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = &cli.unix_socket {
+        let Ok(_) = setup_logger(format!("client_unix_{}.log", std::process::id())) else {
+            bail!("Не удалось запустить логер")
+        };
+        return read_unix(&cli, &server, path, tickers, &tickers_join, stoper);
+    }
+
+    if cli.nat {
+        let Ok(_) = setup_logger(format!("client_nat_{}.log", std::process::id())) else {
+            bail!("Не удалось запустить логер")
+        };
+        return read_nat(&cli, &server, tickers, &tickers_join, stoper);
+    }
+
+    let (Some(client_ip), Some(client_port)) = (&cli.client_ip, cli.client_port) else {
+        bail!("Не передан адрес клиента для udp соединения");
+    };
+
+    let Ok(_) = setup_logger(format!("client_{}.log", client_port)) else {
         bail!("Не удалось запустить логер")
     };
 
-    let thread_handler;
-    match ClientReader::new(address_udp, cli.server_ip, tickers, stoper) {
-        Ok(mut reader) => {
-            // Запускаем ping
-            match reader.ping_sender() {
-                Ok(jh) => {
-                    thread_handler = jh;
-                }
-                Err(e) => {
-                    bail!(e.to_string());
-                }
-            }
-            // Запускаем udp соединение
-            match reader.start() {
-                Ok(_) => {}
-                Err(e) => {
-                    bail!(e.to_string());
-                }
+    // Udp сокет открываем до запроса котировок, что бы не потерять снимок, который сервер присылает сразу
+    let address_udp = SocketAddr::new(*client_ip, client_port);
+    let mut udp_reader =
+        match ClientReader::new(address_udp, server_ips, tickers, stoper) {
+            Ok(reader) => reader,
+            Err(e) => {
+                bail!(e.to_string());
             }
+        };
+
+    udp_reader.set_quotes_wait_period(quotes_wait_period(&cli));
+
+    let options = subscription_options(&cli)?;
+    let request =
+        format!("{STREAM_REQUEST} {UDP_STREAM_PREFIX}{address_udp} {tickers_join}{options}\n");
+    let (reply, stream, _) = request_stream(&server, &request, auth_token(&cli)?.as_deref())?;
+    // Сервер сообщает адрес, с которого пойдут котировки, поэтому ping можно отправлять сразу
+    let (endpoint, _, key) = parse_udp_reply(&reply)?;
+    let endpoint = reachable_endpoint(endpoint, &stream);
+    drop(stream);
+    if let Err(e) = udp_reader.set_server_endpoint(endpoint) {
+        bail!(e.to_string());
+    }
+    udp_reader.set_session_key(key);
+
+    read_udp(udp_reader)
+}
+
+///
+/// Разобрать ответ OK на запрос котировок по udp
+///
+/// # Arguments
+///
+/// * `reply`: ответ сервера после OK в виде `udp://адрес [токен] key=ключ`
+///
+/// returns: Result<(SocketAddr, Option<&str>, SessionKey)> - адрес udp сокета сервера, токен регистрации
+///     для клиента за NAT и ключ подписи датаграмм
+///
+fn parse_udp_reply(reply: &str) -> Result<(SocketAddr, Option<&str>, SessionKey)> {
+    let mut parts = reply.split_whitespace();
+    let Some(address) = parts.next() else {
+        bail!("Сервер не прислал адрес udp сокета");
+    };
+    let endpoint = address.strip_prefix(UDP_STREAM_PREFIX).unwrap_or(address);
+    let Ok(endpoint) = endpoint.parse::<SocketAddr>() else {
+        bail!("Сервер прислал неверный адрес udp сокета {address}");
+    };
+
+    let mut token = None;
+    let mut key = None;
+    for part in parts {
+        match part.split_once('=') {
+            Some((SESSION_KEY_OPTION, value)) => match value.parse::<SessionKey>() {
+                Ok(value) => key = Some(value),
+                Err(e) => bail!("Сервер прислал неверный ключ сессии. {e}"),
+            },
+            _ => token = Some(part),
+        }
+    }
+    let Some(key) = key else {
+        bail!("Сервер не прислал ключ подписи датаграмм в ответе {reply}");
+    };
+    Ok((endpoint, token, key))
+}
+
+///
+/// Разобрать ответ OK на запрос котировок через multicast
+///
+/// # Arguments
+///
+/// * `reply`: ответ сервера после OK в виде `адрес,адрес key=ключ`
+///
+/// returns: Result<(Vec<SocketAddrV4>, SessionKey)> - адреса групп и ключ подписи датаграмм, общий для всех групп
+///
+fn parse_multicast_reply(reply: &str) -> Result<(Vec<SocketAddrV4>, SessionKey)> {
+    let mut parts = reply.split_whitespace();
+    let mut groups = Vec::new();
+    for group in parts.next().unwrap_or_default().split(',') {
+        let Ok(group) = group.parse::<SocketAddrV4>() else {
+            bail!("Сервер прислал неверный адрес multicast группы {group}");
+        };
+        groups.push(group);
+    }
+    let key = match parts.next().and_then(|part| part.split_once('=')) {
+        Some((SESSION_KEY_OPTION, value)) => match value.parse::<SessionKey>() {
+            Ok(key) => key,
+            Err(e) => bail!("Сервер прислал неверный ключ multicast рассылки. {e}"),
+        },
+        _ => bail!("Сервер не прислал ключ подписи датаграмм в ответе {reply}"),
+    };
+    Ok((groups, key))
+}
+
+///
+/// Адрес udp сокета сервера, доступный клиенту. Сервер сообщает адрес, к которому привязан его сокет, но за NAT
+/// или в docker это внутренний адрес сервера. Тогда берется адрес, к которому клиент подключился по tcp,
+/// с портом из ответа
+///
+/// # Arguments
+///
+/// * `endpoint`: адрес из ответа OK
+/// * `stream`: tcp соединение, по которому запрашивали котировки
+///
+/// returns: SocketAddr
+///
+fn reachable_endpoint(endpoint: SocketAddr, stream: &TcpStream) -> SocketAddr {
+    let Ok(connected) = stream.peer_addr() else {
+        return endpoint;
+    };
+    if connected.ip().to_canonical() == endpoint.ip().to_canonical() {
+        return endpoint;
+    }
+    let reachable = SocketAddr::new(connected.ip(), endpoint.port());
+    log::info!("Сервер сообщил внутренний адрес {endpoint}, котировки ожидаются с {reachable}");
+    reachable
+}
+
+///
+/// Получать котировки из-за NAT. Сервер не может отправить котировки на адрес клиента, поэтому клиент сам
+/// отправляет регистрацию с токеном из ответа OK, а сервер отвечает на адрес, с которого она пришла
+///
+/// # Arguments
+///
+/// * `cli`: параметры клиента. Адрес и порт клиента необязательные, по умолчанию порт выдает ОС
+/// * `server`: адреса сервера
+/// * `tickers`: список котировок
+/// * `tickers_join`: список котировок для запроса
+/// * `stoper`: атомик по которому завершает работу клиент
+///
+/// returns: Result<()>
+///
+fn read_nat(
+    cli: &Cli,
+    server: &[SocketAddr],
+    tickers: HashSet<String>,
+    tickers_join: &str,
+    stoper: Arc<AtomicBool>,
+) -> Result<()> {
+    let options = subscription_options(cli)?;
+    let request = format!("{STREAM_REQUEST} {NAT_STREAM} {tickers_join}{options}\n");
+    let (reply, stream, _) = request_stream(server, &request, auth_token(cli)?.as_deref())?;
+    let (endpoint, token, key) = parse_udp_reply(&reply)?;
+    let endpoint = reachable_endpoint(endpoint, &stream);
+    drop(stream);
+    let Some(token) = token else {
+        bail!("Сервер не прислал токен регистрации в ответе {reply}");
+    };
+
+    // Сервер ничего не отправит до регистрации, поэтому сокет можно открыть после запроса
+    let ip = match (cli.client_ip, endpoint) {
+        (Some(ip), _) => ip,
+        (None, SocketAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (None, SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let address = SocketAddr::new(ip, cli.client_port.unwrap_or(0));
+    let server_ips = server.iter().map(SocketAddr::ip).collect::<Vec<IpAddr>>();
+    let mut udp_reader = match ClientReader::new(address, server_ips, tickers, stoper) {
+        Ok(reader) => reader,
+        Err(e) => {
+            bail!(e.to_string());
         }
+    };
+    udp_reader.set_quotes_wait_period(quotes_wait_period(cli));
+    if let Err(e) = udp_reader.set_server_endpoint(endpoint) {
+        bail!(e.to_string());
+    }
+    udp_reader.set_session_key(key);
+    udp_reader.set_registration(token);
+
+    read_udp(udp_reader)
+}
+
+///
+/// Получать котировки по udp. Вместе с чтением запускается поток ping сообщений и команды пользователя
+///
+fn read_udp(mut udp_reader: ClientReader) -> Result<()> {
+    // Запускаем ping
+    let thread_handler = match udp_reader.ping_sender() {
+        Ok(jh) => jh,
         Err(e) => {
             bail!(e.to_string());
         }
     };
+    // Команды пользователя. Поток не дожидаемся, потому что он заблокирован на чтении консоли
+    if let Err(e) = udp_reader.command_reader() {
+        bail!(e.to_string());
+    }
+    // Запускаем udp соединение
+    if let Err(e) = udp_reader.start() {
+        bail!(e.to_string());
+    }
 
     // Завершаем работу потока для отправки ping запроса
     match thread_handler.join() {
@@ -1,29 +1,49 @@
 use chrono::{DateTime, Utc};
+use common_lib::bye::ByeReason;
+use common_lib::datagram_auth::SessionKey;
 use common_lib::errors::ErrType;
 use common_lib::stock_quote::StockQuote;
-use common_lib::{DATA_REQUEST, PING_REQUEST, PONG_REQUEST, QUOTE_GENERATOR_PERIOD, QUOTES_WAIT_PERIOD, PING_SEND_PERIOD, MAX_NUMBER_IGNORED_PING};
+use common_lib::{DATA_REQUEST, DENIED_REQUEST, PING_REQUEST, PONG_REQUEST, QUOTES_WAIT_PERIOD, PING_SEND_PERIOD, MAX_NUMBER_IGNORED_PING, REGISTER_REQUEST, SNAPSHOT_REQUEST, SUBSCRIBE_REQUEST, UDP_CLIENT_RECEIVE_PERIOD, UNSUBSCRIBE_REQUEST};
 use std::collections::HashSet;
 use std::io;
-use std::net::UdpSocket;
+use std::io::BufRead;
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::client_socket::{ClientSocket, Peer, UDP_BUFFER_SIZE};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 struct ServerInfo {
-    ip: String,
+    /// Все адреса, в которые разрешилось имя сервера. Котировки могут прийти с любого из них
+    ips: Vec<IpAddr>,
+    /// Адрес, с которого сервер отправляет котировки. Приходит в ответе OK
+    endpoint: Option<SocketAddr>,
     socket: Arc<Mutex<String>>,
     is_set: Arc<AtomicBool>,
 }
 
 pub struct ClientReader {
-    socket: UdpSocket,
-    tickers: HashSet<String>,
+    socket: ClientSocket,
+    /// Котировки подписки. Меняются командами sub и unsub из консоли
+    tickers: Arc<RwLock<HashSet<String>>>,
     local_address: String,
     stoper: Arc<AtomicBool>,
     expect_pong: Arc<AtomicBool>,
     remote_server_info: ServerInfo,
+    quotes_wait_period: Option<Duration>,
+    /// Котировки приходят через multicast группы, в которых могут быть и чужие котировки
+    multicast: bool,
+    /// Сообщение регистрации клиента за NAT
+    registration: Option<Vec<u8>>,
+    /// Сервер ответил на регистрацию
+    registered: Arc<AtomicBool>,
 }
 
 impl ClientReader {
@@ -33,20 +53,20 @@ impl ClientReader {
     /// # Arguments
     ///
     /// * `address`: адрес для сокета
-    /// * `server_ip`: ip адрес сервера. Порта нет так как сервер создает новый сокет и пока не знает какой порт ему выдадут
+    /// * `server_ips`: ip адреса сервера. Порта нет так как сервер создает новый сокет и пока не знает какой порт ему выдадут
     /// * `tickers`: список котировок который клиент запрашивает у сервера. Нужно для проверки.
     /// * `stop`: атомик по которому завершает работу клиент
     ///
     /// returns: Result<ClientReader, ErrType>
     ///
     pub fn new(
-        address: String,
-        server_ip: String,
+        address: SocketAddr,
+        server_ips: Vec<IpAddr>,
         tickers: HashSet<String>,
         stop: Arc<AtomicBool>,
     ) -> Result<Self, ErrType> {
         // Сокет создаем на адресе который отправили серверу
-        let socket = match UdpSocket::bind(address.clone()) {
+        let socket = match UdpSocket::bind(address) {
             Ok(socket) => socket,
             Err(e) => {
                 log::error!("Не удалось запустить udp сервер на сокете {address}. {e}");
@@ -57,72 +77,314 @@ impl ClientReader {
         };
 
         let Ok(_) = socket.set_nonblocking(true) else {
-            log::error!(
-                "Не удалось сделать upd сокет c {} не блокирующимся",
-                address.clone()
-            );
+            log::error!("Не удалось сделать upd сокет c {address} не блокирующимся");
             return Err(ErrType::ConnectionError(format!(
                 "Не удалось сделать upd сокет c {address} не блокирующимся"
             )));
         };
 
+        // Порт может выдать ОС, поэтому в логах указываем реальный адрес сокета
+        let local_address = match socket.local_addr() {
+            Ok(local) => local.to_string(),
+            Err(_) => address.to_string(),
+        };
+
         Ok(Self {
-            socket,
-            tickers,
-            local_address: address,
+            socket: ClientSocket::udp(socket),
+            tickers: Arc::new(RwLock::new(tickers)),
+            local_address,
+            stoper: stop,
+            expect_pong: Arc::new(AtomicBool::new(false)),
+            remote_server_info: ServerInfo {
+                ips: server_ips,
+                endpoint: None,
+                socket: Arc::new(Mutex::new("".to_string())),
+                is_set: Arc::new(AtomicBool::new(false)),
+            },
+            quotes_wait_period: Some(QUOTES_WAIT_PERIOD),
+            multicast: false,
+            registration: None,
+            registered: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    ///
+    /// Создает сокет и подключается к multicast группам сервера
+    ///
+    /// # Arguments
+    ///
+    /// * `groups`: адреса групп. Все группы должны использовать один порт
+    /// * `interface`: интерфейс через который подключаемся к группам
+    /// * `server_ips`: ip адреса сервера
+    /// * `tickers`: список котировок клиента. Остальные котировки групп отбрасываются
+    /// * `stop`: атомик по которому завершает работу клиент
+    ///
+    /// returns: Result<ClientReader, ErrType>
+    ///
+    pub fn new_multicast(
+        groups: &[SocketAddrV4],
+        interface: Ipv4Addr,
+        server_ips: Vec<IpAddr>,
+        tickers: HashSet<String>,
+        stop: Arc<AtomicBool>,
+    ) -> Result<Self, ErrType> {
+        let Some(port) = groups.first().map(|g| g.port()) else {
+            return Err(ErrType::ConnectionError(
+                "Сервер не прислал multicast группы".to_string(),
+            ));
+        };
+        if groups.iter().any(|g| g.port() != port) {
+            return Err(ErrType::NotSupported(
+                "Multicast группы должны использовать один порт".to_string(),
+            ));
+        }
+
+        let error = |e: io::Error| {
+            log::error!("Не удалось подключиться к multicast группам. {e}");
+            ErrType::ConnectionError(format!("Не удалось подключиться к multicast группам. {e}"))
+        };
+        // На одной машине может работать несколько клиентов, поэтому порт переиспользуется
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).map_err(error)?;
+        socket.set_reuse_address(true).map_err(error)?;
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())
+            .map_err(error)?;
+        let socket: UdpSocket = socket.into();
+        for group in groups {
+            socket
+                .join_multicast_v4(group.ip(), &interface)
+                .map_err(error)?;
+            log::info!("Клиент подключился к multicast группе {group}");
+        }
+        socket.set_nonblocking(true).map_err(error)?;
+
+        Ok(Self {
+            socket: ClientSocket::udp(socket),
+            tickers: Arc::new(RwLock::new(tickers)),
+            local_address: format!("{interface}:{port}"),
             stoper: stop,
             expect_pong: Arc::new(AtomicBool::new(false)),
             remote_server_info: ServerInfo {
-                ip: server_ip,
+                ips: server_ips,
+                endpoint: None,
                 socket: Arc::new(Mutex::new("".to_string())),
                 is_set: Arc::new(AtomicBool::new(false)),
             },
+            quotes_wait_period: Some(QUOTES_WAIT_PERIOD),
+            multicast: true,
+            registration: None,
+            registered: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    ///
+    /// Получать котировки через tcp соединение, по которому был отправлен запрос STREAM
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: tcp соединение с сервером
+    /// * `pending`: байты, которые прочитали из соединения вместе с ответом OK
+    /// * `tickers`: список котировок клиента
+    /// * `stop`: атомик по которому завершает работу клиент
+    ///
+    /// returns: Result<ClientReader, ErrType>
+    ///
+    pub fn new_tcp(
+        stream: TcpStream,
+        pending: Vec<u8>,
+        tickers: HashSet<String>,
+        stop: Arc<AtomicBool>,
+    ) -> Result<Self, ErrType> {
+        let error = |e: io::Error| {
+            log::error!("Не удалось настроить tcp соединение для получения котировок. {e}");
+            ErrType::ConnectionError(format!(
+                "Не удалось настроить tcp соединение для получения котировок. {e}"
+            ))
+        };
+        let local_address = stream.local_addr().map_err(error)?.to_string();
+        // Адрес сервера известен сразу, это адрес tcp соединения
+        let peer = stream.peer_addr().map_err(error)?;
+        let socket = ClientSocket::tcp(stream, pending).map_err(error)?;
+        let server_ips = vec![peer.ip()];
+        let server_address = peer.to_string();
+
+        Ok(Self {
+            socket,
+            tickers: Arc::new(RwLock::new(tickers)),
+            local_address,
+            stoper: stop,
+            expect_pong: Arc::new(AtomicBool::new(false)),
+            remote_server_info: ServerInfo {
+                ips: server_ips,
+                endpoint: None,
+                socket: Arc::new(Mutex::new(server_address)),
+                is_set: Arc::new(AtomicBool::new(true)),
+            },
+            quotes_wait_period: Some(QUOTES_WAIT_PERIOD),
+            multicast: false,
+            registration: None,
+            registered: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    ///
+    /// Получать котировки через unix сокет, к которому подключается сервер на этой же машине
+    ///
+    /// # Arguments
+    ///
+    /// * `listener`: сокет, созданный до запроса STREAM
+    /// * `path`: путь до файла сокета
+    /// * `tickers`: список котировок клиента
+    /// * `stop`: атомик по которому завершает работу клиент
+    ///
+    /// returns: Result<ClientReader, ErrType>
+    ///
+    #[cfg(unix)]
+    pub fn new_unix(
+        listener: UnixListener,
+        path: &Path,
+        tickers: HashSet<String>,
+        stop: Arc<AtomicBool>,
+    ) -> Result<Self, ErrType> {
+        let socket = ClientSocket::unix(listener, path).map_err(|e| {
+            log::error!("Не удалось получить подключение сервера к unix сокету. {e}");
+            ErrType::ConnectionError(format!(
+                "Не удалось получить подключение сервера к unix сокету. {e}"
+            ))
+        })?;
+        let local_address = Peer::Unix(path.to_path_buf()).to_string();
+
+        Ok(Self {
+            socket,
+            tickers: Arc::new(RwLock::new(tickers)),
+            local_address: local_address.clone(),
+            stoper: stop,
+            expect_pong: Arc::new(AtomicBool::new(false)),
+            // Сервер подключен к сокету, поэтому его адрес проверять не нужно
+            remote_server_info: ServerInfo {
+                ips: Vec::new(),
+                endpoint: None,
+                socket: Arc::new(Mutex::new(local_address)),
+                is_set: Arc::new(AtomicBool::new(true)),
+            },
+            quotes_wait_period: Some(QUOTES_WAIT_PERIOD),
+            multicast: false,
+            registration: None,
+            registered: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    ///
+    /// Задать адрес, с которого сервер отправляет котировки. Он известен из ответа OK, поэтому ping
+    /// начинает отправляться сразу, а сообщения с других адресов отбрасываются
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint`: адрес udp сокета сервера
+    ///
+    pub fn set_server_endpoint(&mut self, endpoint: SocketAddr) -> Result<(), ErrType> {
+        let Ok(mut s) = self.remote_server_info.socket.lock() else {
+            return Err(ErrType::NoAccess(format!(
+                "Не удалось зафиксировать адрес сервера {endpoint}"
+            )));
+        };
+        *s = endpoint.to_string();
+        self.remote_server_info.endpoint = Some(endpoint);
+        self.remote_server_info.is_set.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    ///
+    /// Задать ключ подписи датаграмм из ответа OK. Датаграммы без верной подписи отбрасываются,
+    /// поэтому чужая машина не может подделать котировки, даже если отправит их с адреса сервера
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: ключ сессии
+    ///
+    pub fn set_session_key(&mut self, key: SessionKey) {
+        self.socket.set_key(key);
+    }
+
+    ///
+    /// Задать токен регистрации из ответа OK. Клиент за NAT отправляет его серверу, пока тот не ответит,
+    /// и сервер начинает слать котировки на адрес, с которого пришла регистрация
+    ///
+    /// # Arguments
+    ///
+    /// * `token`: токен регистрации
+    ///
+    pub fn set_registration(&mut self, token: &str) {
+        let mut message = REGISTER_REQUEST.to_vec();
+        message.push(b' ');
+        message.extend_from_slice(token.as_bytes());
+        self.registration = Some(message);
+    }
+
+    ///
+    /// Задать сколько ждать котировки, прежде чем считать их потерянными и запросить снимок.
+    /// Нужно когда клиент сам попросил сервер присылать котировки реже. None если котировки могут
+    /// не приходить сколько угодно долго, например когда заданы фильтры
+    ///
+    pub fn set_quotes_wait_period(&mut self, period: Option<Duration>) {
+        self.quotes_wait_period = period;
+    }
+
     ///
     /// Метод для в котором крутится цикл и проверяет udp запросы. Без отдельного потомка потому что именно этот цикл обеспечивает непрерывную работу клиента
     ///
     pub fn start(&mut self) -> Result<(), ErrType> {
-        let mut buf = [0u8; 2048];
+        let mut buf = [0u8; UDP_BUFFER_SIZE];
 
-        let mut deadline = Instant::now() + QUOTES_WAIT_PERIOD;
+        let mut deadline = self.quotes_wait_period.map(|period| Instant::now() + period);
 
         loop {
             // Если установили флаг завершения работы
             if self.stoper.load(Ordering::Acquire) {
                 log::info!("Закрываем соединение");
+                // Сообщаем серверу, что бы он сразу перестал присылать котировки
+                self.say_bye(ByeReason::ClientShutdown);
                 return Ok(());
             }
 
             match self.socket.recv_from(&mut buf) {
                 Ok((n, from)) => {
-                    // Поскольку порт udp сокета сервера не известен ждем запрос с ip сервера, далее получаем его порт.
-                    // Все запросы с других ip игнорируем
-                    if from.ip().to_string() != self.remote_server_info.ip {
-                        log::error!(
-                            "Пришел запрос от неизвестной машины {from}: {}",
-                            String::from_utf8_lossy(&buf[..n])
-                        );
-                        continue;
-                    }
-
-                    if !self.remote_server_info.is_set.load(Ordering::Acquire) {
-                        if let Ok(mut s) = self.remote_server_info.socket.lock() {
-                            // фиксируем хост и порт сервера и помечаем что бы началась отправка ping сообщений
-                            *s = from.to_string();
-                            self.remote_server_info
-                                .is_set
-                                .store(true, Ordering::Release);
-                        } else {
+                    // В multicast режиме рассылка идет с интерфейса сервера, а не с адреса подключения.
+                    // Адрес сервера не фиксируем, потому что ping и снимков в этом режиме нет
+                    // Сообщения из unix сокета может прислать только подключившийся сервер
+                    if let Peer::Inet(address) = &from
+                        && !self.multicast
+                    {
+                        // Адрес udp сокета сервера известен из ответа OK. Если сервер его не прислал, то ждем запрос
+                        // с ip сервера, далее получаем его порт. Все запросы с других адресов игнорируем
+                        if !self.is_server_endpoint(*address) {
                             log::error!(
-                                "Не удалось зафиксировать адрес удаленной машины {from}: {}",
+                                "Пришел запрос от неизвестной машины {from}: {}",
                                 String::from_utf8_lossy(&buf[..n])
                             );
                             continue;
                         }
+
+                        if !self.remote_server_info.is_set.load(Ordering::Acquire) {
+                            if let Ok(mut s) = self.remote_server_info.socket.lock() {
+                                // фиксируем хост и порт сервера и помечаем что бы началась отправка ping сообщений
+                                *s = from.to_string();
+                                self.remote_server_info
+                                    .is_set
+                                    .store(true, Ordering::Release);
+                            } else {
+                                log::error!(
+                                    "Не удалось зафиксировать адрес удаленной машины {from}: {}",
+                                    String::from_utf8_lossy(&buf[..n])
+                                );
+                                continue;
+                            }
+                        }
                     }
 
+                    // Сервер ответил, значит регистрация за NAT дошла
+                    self.registered.store(true, Ordering::Release);
+
                     // Проверяем полученный запрос и отсеиваем неизвестные
                     if &buf[..DATA_REQUEST.len()] == DATA_REQUEST {
                         log::info!(
@@ -136,6 +398,26 @@ impl ClientReader {
                         );
                         self.expect_pong.store(false, Ordering::Release);
                         continue;
+                    } else if let Some(list) = buf[..n].strip_prefix(DENIED_REQUEST.as_slice()) {
+                        // Сервер не добавил в подписку котировки, на которые у клиента нет прав
+                        let denied = String::from_utf8_lossy(list)
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|t| !t.is_empty())
+                            .map(String::from)
+                            .collect::<Vec<String>>();
+                        log::warn!("Сервер {from} отказал в котировках {}", denied.join(","));
+                        println!("Сервер отказал в котировках {}", denied.join(","));
+                        if let Ok(mut tickers) = self.tickers.write() {
+                            tickers.retain(|t| !denied.contains(t));
+                        }
+                        continue;
+                    } else if let Some(reason) = ByeReason::from_message(&buf[..n]) {
+                        // Сервер закрыл соединение, ждать больше нечего
+                        log::warn!("Сервер {from} закрыл соединение. Причина: {reason}");
+                        println!("Сервер закрыл соединение. Причина: {reason}");
+                        self.stoper.store(true, Ordering::Release);
+                        return Ok(());
                     } else {
                         log::error!(
                             "От {from} пришел неизвестный запрос {}",
@@ -144,14 +426,19 @@ impl ClientReader {
                         continue;
                     }
 
-                    deadline = Instant::now() + QUOTES_WAIT_PERIOD;
+                    deadline = self.quotes_wait_period.map(|period| Instant::now() + period);
                     // Если прислали данные, то пробуем их десириализовать и выводим в консоль
                     match bincode::deserialize::<Vec<StockQuote>>(&buf[DATA_REQUEST.len()..n]) {
                         Ok(quotes) => {
-                            if self.tickers.len() != quotes.len() {
-                                log::error!(
-                                    "Сервер вернул не все запрашиваемые значения Запрашивали: {}; Пришло:{}",
-                                    self.tickers
+                            let Ok(tickers) = self.tickers.read() else {
+                                log::error!("Не удалось прочитать список котировок подписки");
+                                continue;
+                            };
+                            // Из-за ограничений частоты и фильтров могут прийти не все котировки
+                            if tickers.len() != quotes.len() {
+                                log::debug!(
+                                    "Сервер прислал не все запрашиваемые значения Запрашивали: {}; Пришло:{}",
+                                    tickers
                                         .iter()
                                         .map(String::as_str)
                                         .collect::<Vec<_>>()
@@ -165,7 +452,11 @@ impl ClientReader {
                             }
                             println!("---");
                             for quote in quotes {
-                                if !self.tickers.contains(&quote.ticker) {
+                                if self.multicast && !tickers.contains(&quote.ticker) {
+                                    // В группе есть котировки других клиентов
+                                    continue;
+                                }
+                                if !tickers.contains(&quote.ticker) {
                                     log::error!(
                                         "Сервер не вернул запрашиваемое значение {}",
                                         quote.ticker
@@ -199,15 +490,20 @@ impl ClientReader {
                 }
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                     // За период не пришли данные котировок
-                    if Instant::now() >= deadline {
+                    if let (Some(period), Some(time)) = (self.quotes_wait_period, deadline)
+                        && Instant::now() >= time
+                    {
                         log::warn!(
                             "Сервер {} не прислал котировки за последние {} секунды",
                             self.local_address,
-                            QUOTES_WAIT_PERIOD.as_secs()
+                            period.as_secs()
                         );
-                        deadline += QUOTES_WAIT_PERIOD;
+                        deadline = Some(time + period);
+                        // Котировки потерялись, поэтому не ждем следующей рассылки, а просим снимок
+                        self.request_snapshot();
                     }
-                    thread::sleep(QUOTE_GENERATOR_PERIOD);
+                    // Спим недолго, что бы снимок котировок сразу после подписки не ждал следующего обновления
+                    thread::sleep(UDP_CLIENT_RECEIVE_PERIOD);
                 }
                 Err(e) => {
                     log::error!(
@@ -222,6 +518,151 @@ impl ClientReader {
         }
     }
 
+    ///
+    /// Пришло ли сообщение с udp сокета сервера. Если сервер не прислал адрес сокета, то проверяется
+    /// только то, что адрес принадлежит серверу. За NAT или в docker сервер видит свой внутренний адрес,
+    /// поэтому порт из ответа OK сравнивается с любым адресом сервера
+    ///
+    fn is_server_endpoint(&self, from: SocketAddr) -> bool {
+        match self.remote_server_info.endpoint {
+            Some(endpoint) => {
+                endpoint.port() == from.port()
+                    && (endpoint.ip().to_canonical() == from.ip().to_canonical()
+                        || self.is_server_ip(from.ip()))
+            }
+            None => self.is_server_ip(from.ip()),
+        }
+    }
+
+    ///
+    /// Пришло ли сообщение с одного из адресов сервера. ipv4 адрес, записанный как ipv6, считается тем же адресом
+    ///
+    fn is_server_ip(&self, ip: IpAddr) -> bool {
+        self.remote_server_info
+            .ips
+            .iter()
+            .any(|server_ip| server_ip.to_canonical() == ip.to_canonical())
+    }
+
+    ///
+    /// Сообщить серверу о закрытии соединения. Если адрес сервера еще не известен, то сообщать некому
+    ///
+    fn say_bye(&self, reason: ByeReason) {
+        if !self.remote_server_info.is_set.load(Ordering::Acquire) {
+            return;
+        }
+        let Ok(server_address) = self.remote_server_info.socket.lock().map(|s| s.clone()) else {
+            return;
+        };
+        match self.socket.send_to(&reason.to_message(), &server_address) {
+            Ok(_) => log::info!("Серверу {server_address} отправлен BYE. Причина: {reason}"),
+            Err(e) => log::error!("Не удалось отправить BYE серверу {server_address}. {e}"),
+        }
+    }
+
+    ///
+    /// Запросить у сервера снимок последних значений котировок. Если адрес сервера еще не известен,
+    /// то запрос не отправляется, сервер сам пришлет снимок при старте рассылки
+    ///
+    pub fn request_snapshot(&self) {
+        if !self.remote_server_info.is_set.load(Ordering::Acquire) {
+            return;
+        }
+        let Ok(server_address) = self.remote_server_info.socket.lock().map(|s| s.clone()) else {
+            log::warn!("Неизвестен адрес удаленный машины что бы запросить снимок котировок");
+            return;
+        };
+        match self.socket.send_to(SNAPSHOT_REQUEST, &server_address) {
+            Ok(_) => log::info!("Запрошен снимок котировок у {server_address}"),
+            Err(e) => log::error!("Не удалось запросить снимок котировок у {server_address}. {e}"),
+        }
+    }
+
+    ///
+    /// Метод в котором запускается поток читающий команды пользователя из консоли. По команде `snap`
+    /// у сервера запрашивается снимок котировок, командами `sub AAPL,MSFT` и `unsub AAPL` меняется подписка.
+    /// Поток блокируется на чтении, поэтому его не дожидаются
+    ///
+    /// returns: поток читающий команды
+    ///
+    pub fn command_reader(&self) -> Result<JoinHandle<()>, ErrType> {
+        let Ok(copy_socket) = self.socket.try_clone() else {
+            Err(ErrType::ConnectionError(
+                "Не удалось создать копию сокета для команд пользователя".to_string(),
+            ))?
+        };
+
+        let local_stoper = Arc::clone(&self.stoper);
+        let server_address = self.remote_server_info.socket.clone();
+        let server_address_set = self.remote_server_info.is_set.clone();
+        let tickers = self.tickers.clone();
+        let multicast = self.multicast;
+
+        Ok(thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                if local_stoper.load(Ordering::Acquire) {
+                    break;
+                }
+                let Ok(line) = line else {
+                    break;
+                };
+                let line = line.trim();
+                let (command, list) = line.split_once(' ').unwrap_or((line, ""));
+                let changed = list
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(String::from)
+                    .collect::<Vec<String>>();
+                let message = match command {
+                    "snap" => SNAPSHOT_REQUEST.to_vec(),
+                    "sub" | "unsub" if multicast => {
+                        println!("В режиме multicast подписку менять нельзя");
+                        continue;
+                    }
+                    "sub" | "unsub" if changed.is_empty() => {
+                        println!("Не передан список котировок, например {command} AAPL,MSFT");
+                        continue;
+                    }
+                    "sub" | "unsub" => {
+                        let Ok(mut tickers) = tickers.write() else {
+                            log::error!("Не удалось изменить список котировок подписки");
+                            continue;
+                        };
+                        // Список меняется до отправки, что бы снимок новых котировок не отбросили
+                        let request = if command == "sub" {
+                            tickers.extend(changed.iter().cloned());
+                            SUBSCRIBE_REQUEST.as_slice()
+                        } else {
+                            tickers.retain(|t| !changed.contains(t));
+                            UNSUBSCRIBE_REQUEST.as_slice()
+                        };
+                        let mut message = request.to_vec();
+                        message.extend_from_slice(format!(" {}", changed.join(",")).as_bytes());
+                        message
+                    }
+                    _ => {
+                        println!(
+                            "Неизвестная команда {line}. Доступны команды snap, sub <котировки>, unsub <котировки>"
+                        );
+                        continue;
+                    }
+                };
+                if !server_address_set.load(Ordering::Acquire) {
+                    log::warn!("Неизвестен адрес удаленный машины что бы отправить команду {command}");
+                    continue;
+                }
+                let Ok(address) = server_address.lock().map(|s| s.clone()) else {
+                    continue;
+                };
+                match copy_socket.send_to(&message, &address) {
+                    Ok(_) => log::info!("Команда {line} отправлена {address}"),
+                    Err(e) => log::error!("Не удалось отправить команду {line} на {address}. {e}"),
+                }
+            }
+        }))
+    }
+
     ///
     /// Метод в котором запускается поток для отправки ping сообщений
     ///
@@ -239,6 +680,8 @@ impl ClientReader {
 
         let server_address = self.remote_server_info.socket.clone(); // Адрес сервера что бы знать куда отправлять ping
         let server_address_set = self.remote_server_info.is_set.clone(); // Флаг установлен если известен адрес сервсера
+        let registration = self.registration.clone(); // Регистрация клиента за NAT
+        let registered = self.registered.clone();
 
         Ok(thread::spawn(move || {
             let mut remote_server_socket = String::new();
@@ -266,6 +709,14 @@ impl ClientReader {
                 }
 
 
+                // Регистрация может потеряться, поэтому повторяем ее, пока сервер не ответит
+                if let Some(message) = &registration
+                    && !registered.load(Ordering::Acquire)
+                    && let Err(e) = copy_socket.send_to(message, &remote_server_socket)
+                {
+                    log::error!("Не удалось отправить регистрацию серверу {remote_server_socket}. {e}");
+                }
+
                 // Если переменная все еще установлена то значит pong не пришел и можно закрывать работу
                 if local_expect_pong.load(Ordering::Acquire) {
                     log::info!(
@@ -282,7 +733,7 @@ impl ClientReader {
                     fail = 0;
                 }
 
-                let Ok(_) = copy_socket.send_to(PING_REQUEST, remote_server_socket.clone()) else {
+                let Ok(_) = copy_socket.send_to(PING_REQUEST, &remote_server_socket) else {
                     log::error!(
                         "Не удалось отправить PING сообщение на адрес {}",
                         remote_server_socket
@@ -0,0 +1,120 @@
+use crate::errors::ErrType;
+use std::fmt;
+use std::str::FromStr;
+
+/// Команда порта управления сервером. Передается одной строкой, ответ заканчивается строкой
+/// `OK` или `ERROR: ...`
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    /// Список активных сессий
+    List,
+    /// Закрыть сессию по ее номеру из списка сессий
+    Kick(u64),
+    /// Добавить котировки в генератор
+    Add(Vec<String>),
+    /// Убрать котировки из генератора
+    Remove(Vec<String>),
+    /// Приостановить обновление котировок
+    Pause,
+    /// Продолжить обновление котировок
+    Resume,
+    /// Состояние сервера в json
+    Dump,
+}
+
+impl AdminCommand {
+    ///
+    /// Разобрать список котировок через запятую
+    ///
+    fn parse_tickers(command: &str, list: &str) -> Result<Vec<String>, ErrType> {
+        let tickers = list
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(String::from)
+            .collect::<Vec<String>>();
+        if tickers.is_empty() {
+            return Err(ErrType::RequestError(format!(
+                "Не передан список котировок, например {command} AAPL,MSFT"
+            )));
+        }
+        Ok(tickers)
+    }
+}
+
+impl FromStr for AdminCommand {
+    type Err = ErrType;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (command, argument) = s.split_once(' ').unwrap_or((s, ""));
+        let argument = argument.trim();
+        match command.to_uppercase().as_str() {
+            "LIST" => Ok(AdminCommand::List),
+            "KICK" => match argument.parse::<u64>() {
+                Ok(session) => Ok(AdminCommand::Kick(session)),
+                Err(_) => Err(ErrType::RequestError(format!(
+                    "Не передан номер сессии, например KICK 1. Передано: {argument}"
+                ))),
+            },
+            "ADD" => Ok(AdminCommand::Add(Self::parse_tickers("ADD", argument)?)),
+            "REMOVE" => Ok(AdminCommand::Remove(Self::parse_tickers("REMOVE", argument)?)),
+            "PAUSE" => Ok(AdminCommand::Pause),
+            "RESUME" => Ok(AdminCommand::Resume),
+            "DUMP" => Ok(AdminCommand::Dump),
+            _ => Err(ErrType::NotSupported(format!(
+                "Неизвестная команда {s}. Доступны LIST, KICK, ADD, REMOVE, PAUSE, RESUME, DUMP"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for AdminCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdminCommand::List => write!(f, "LIST"),
+            AdminCommand::Kick(session) => write!(f, "KICK {session}"),
+            AdminCommand::Add(tickers) => write!(f, "ADD {}", tickers.join(",")),
+            AdminCommand::Remove(tickers) => write!(f, "REMOVE {}", tickers.join(",")),
+            AdminCommand::Pause => write!(f, "PAUSE"),
+            AdminCommand::Resume => write!(f, "RESUME"),
+            AdminCommand::Dump => write!(f, "DUMP"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let commands = [
+            AdminCommand::List,
+            AdminCommand::Kick(42),
+            AdminCommand::Add(vec!["NVDA".to_string(), "AMD".to_string()]),
+            AdminCommand::Remove(vec!["TSLA".to_string()]),
+            AdminCommand::Pause,
+            AdminCommand::Resume,
+            AdminCommand::Dump,
+        ];
+        for command in commands {
+            assert_eq!(command.to_string().parse::<AdminCommand>().unwrap(), command);
+        }
+    }
+
+    #[test]
+    fn lowercase_and_spaces() {
+        assert_eq!(" list ".parse::<AdminCommand>().unwrap(), AdminCommand::List);
+        assert_eq!(
+            "add NVDA, AMD,".parse::<AdminCommand>().unwrap(),
+            AdminCommand::Add(vec!["NVDA".to_string(), "AMD".to_string()])
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        for command in ["KICK", "KICK ", "KICK abc", "KICK -1", "ADD", "ADD ,", "REMOVE", "STOP", ""] {
+            assert!(command.parse::<AdminCommand>().is_err(), "{command}");
+        }
+    }
+}
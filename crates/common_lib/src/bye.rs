@@ -0,0 +1,56 @@
+use crate::BYE_REQUEST;
+use std::fmt;
+
+/// Причина по которой одна из сторон закрывает соединение. Передается одним байтом после BYE
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByeReason {
+    Unknown = 0,
+    ServerShutdown = 1,
+    ClientShutdown = 2,
+    PingTimeout = 3,
+    SlowConsumer = 4,
+    Kicked = 5,
+}
+
+impl ByeReason {
+    /// Сообщение BYE с кодом причины
+    pub fn to_message(self) -> Vec<u8> {
+        let mut message = BYE_REQUEST.to_vec();
+        message.push(self as u8);
+        message
+    }
+
+    ///
+    /// Прочитать причину из сообщения
+    ///
+    /// # Arguments
+    ///
+    /// * `message`: полученное сообщение
+    ///
+    /// returns: Option<ByeReason> - None если сообщение не BYE
+    ///
+    pub fn from_message(message: &[u8]) -> Option<Self> {
+        let code = message.strip_prefix(BYE_REQUEST.as_slice())?;
+        Some(match code.first() {
+            Some(1) => ByeReason::ServerShutdown,
+            Some(2) => ByeReason::ClientShutdown,
+            Some(3) => ByeReason::PingTimeout,
+            Some(4) => ByeReason::SlowConsumer,
+            Some(5) => ByeReason::Kicked,
+            _ => ByeReason::Unknown,
+        })
+    }
+}
+
+impl fmt::Display for ByeReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ByeReason::Unknown => write!(f, "неизвестная причина"),
+            ByeReason::ServerShutdown => write!(f, "сервер остановлен"),
+            ByeReason::ClientShutdown => write!(f, "клиент остановлен"),
+            ByeReason::PingTimeout => write!(f, "не было PING сообщений"),
+            ByeReason::SlowConsumer => write!(f, "клиент не успевает получать котировки"),
+            ByeReason::Kicked => write!(f, "сессия закрыта администратором"),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Подпись udp датаграмм ключом сессии. Ключ сервер передает в ответе OK через tcp соединение запроса STREAM,
+//! поэтому подделать котировки или PING может только тот, кто видел это соединение. К каждой датаграмме
+//! в конце дописывается номер датаграммы в u64 little endian и начало HMAC-SHA256 от направления, номера и
+//! самого сообщения. Номера каждого направления растут, а принимающая сторона помнит последние принятые номера,
+//! поэтому перехваченную датаграмму нельзя отправить повторно
+
+use crate::errors::ErrType;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Длина ключа сессии в байтах
+pub const SESSION_KEY_SIZE: usize = 32;
+/// Сколько байт подписи дописывается к датаграмме
+pub const TAG_SIZE: usize = 16;
+/// Размер номера датаграммы
+pub const COUNTER_SIZE: usize = size_of::<u64>();
+/// На сколько подписанная датаграмма длиннее сообщения
+pub const FRAME_OVERHEAD: usize = COUNTER_SIZE + TAG_SIZE;
+/// Сколько номеров перед самым большим принятым помнит окно повторов
+const REPLAY_WINDOW: u64 = 64;
+
+/// Кто отправил датаграмму. Направление входит в подпись, поэтому датаграмму сервера нельзя
+/// отправить обратно серверу от имени клиента
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    ToClient = 0,
+    ToServer = 1,
+}
+
+/// Ключ, которым сервер и клиент подписывают датаграммы одной сессии. Копии ключа разделяют номера датаграмм,
+/// поэтому один ключ можно использовать из нескольких потоков
+#[derive(Clone)]
+pub struct SessionKey {
+    state: Arc<KeyState>,
+}
+
+struct KeyState {
+    bytes: [u8; SESSION_KEY_SIZE],
+    /// HMAC с уже заданным ключом, для каждой датаграммы используется его копия
+    mac: HmacSha256,
+    /// Номер последней отправленной датаграммы
+    sent: AtomicU64,
+    /// Номера принятых датаграмм
+    received: Mutex<ReplayWindow>,
+}
+
+/// Окно принятых номеров, как в IPsec и DTLS. Датаграммы, которые переставились в сети, принимаются,
+/// а повторы и номера старше окна отбрасываются
+#[derive(Default)]
+struct ReplayWindow {
+    /// Самый большой принятый номер
+    last: u64,
+    /// Бит i означает, что номер last - i уже принят
+    seen: u64,
+}
+
+impl ReplayWindow {
+    ///
+    /// Отметить номер принятым
+    ///
+    /// returns: bool - false если номер уже был или он старше окна
+    ///
+    fn accept(&mut self, counter: u64) -> bool {
+        // Номера начинаются с 1, а 0 пришел бы в пустое окно как новый
+        if counter == 0 {
+            return false;
+        }
+        if counter > self.last {
+            let shift = counter - self.last;
+            self.seen = (if shift < REPLAY_WINDOW { self.seen << shift } else { 0 }) | 1;
+            self.last = counter;
+            return true;
+        }
+        let offset = self.last - counter;
+        if offset >= REPLAY_WINDOW || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+impl SessionKey {
+    ///
+    /// Создать ключ сессии
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes`: случайные байты ключа
+    ///
+    /// returns: Result<SessionKey, ErrType>
+    ///
+    pub fn new(bytes: [u8; SESSION_KEY_SIZE]) -> Result<Self, ErrType> {
+        let mac = HmacSha256::new_from_slice(&bytes)
+            .map_err(|e| ErrType::ParseError(format!("Неверный ключ сессии. {e}")))?;
+        Ok(Self {
+            state: Arc::new(KeyState {
+                bytes,
+                mac,
+                sent: AtomicU64::new(0),
+                received: Mutex::new(ReplayWindow::default()),
+            }),
+        })
+    }
+
+    ///
+    /// Подписать сообщение следующим номером
+    ///
+    /// # Arguments
+    ///
+    /// * `direction`: кто отправляет сообщение
+    /// * `message`: сообщение
+    ///
+    /// returns: Vec<u8> - сообщение с номером и подписью в конце
+    ///
+    pub fn sign(&self, direction: Direction, message: &[u8]) -> Vec<u8> {
+        let counter = self.state.sent.fetch_add(1, Ordering::Relaxed) + 1;
+        let tag = self.mac_for(direction, counter, message).finalize().into_bytes();
+        let mut frame = Vec::with_capacity(message.len() + FRAME_OVERHEAD);
+        frame.extend_from_slice(message);
+        frame.extend_from_slice(&counter.to_le_bytes());
+        frame.extend_from_slice(&tag[..TAG_SIZE]);
+        frame
+    }
+
+    ///
+    /// Проверить подпись и номер датаграммы. Подпись сравнивается за постоянное время, а номер проверяется
+    /// окном последних REPLAY_WINDOW номеров: переставленные датаграммы принимаются, а повторы и слишком
+    /// опоздавшие отбрасываются
+    ///
+    /// # Arguments
+    ///
+    /// * `direction`: кто должен был отправить датаграмму
+    /// * `frame`: датаграмма с номером и подписью в конце
+    ///
+    /// returns: Option<&[u8]> - сообщение без номера и подписи, None если подпись неверная или номер уже был
+    ///
+    pub fn verify<'a>(&self, direction: Direction, frame: &'a [u8]) -> Option<&'a [u8]> {
+        let (signed, tag) = frame.split_at(frame.len().checked_sub(TAG_SIZE)?);
+        let (message, counter) = signed.split_at(signed.len().checked_sub(COUNTER_SIZE)?);
+        let counter = u64::from_le_bytes(counter.try_into().ok()?);
+        self.mac_for(direction, counter, message)
+            .verify_truncated_left(tag)
+            .ok()?;
+        // Номер запоминается только после проверки подписи, иначе чужая датаграмма могла бы сдвинуть окно
+        let mut window = self.state.received.lock().ok()?;
+        window.accept(counter).then_some(message)
+    }
+
+    fn mac_for(&self, direction: Direction, counter: u64, message: &[u8]) -> HmacSha256 {
+        let mut mac = self.state.mac.clone();
+        mac.update(&[direction as u8]);
+        mac.update(&counter.to_le_bytes());
+        mac.update(message);
+        mac
+    }
+}
+
+impl Display for SessionKey {
+    /// Ключ в hex для ответа OK
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for byte in self.state.bytes {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SessionKey {
+    type Err = ErrType;
+
+    ///
+    /// Разобрать ключ из hex
+    ///
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != SESSION_KEY_SIZE * 2 || !s.is_ascii() {
+            return Err(ErrType::ParseError(format!(
+                "Ключ сессии должен быть {} байт в hex",
+                SESSION_KEY_SIZE
+            )));
+        }
+        let mut bytes = [0u8; SESSION_KEY_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+        }
+        Self::new(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; SESSION_KEY_SIZE] = [7; SESSION_KEY_SIZE];
+
+    /// Ключи сервера и клиента одной сессии. Номера у них свои, как у двух сторон соединения
+    fn pair() -> (SessionKey, SessionKey) {
+        (SessionKey::new(KEY).unwrap(), SessionKey::new(KEY).unwrap())
+    }
+
+    #[test]
+    fn valid_frame() {
+        let (server, client) = pair();
+        let frame = server.sign(Direction::ToClient, b"DATA");
+        assert_eq!(frame.len(), 4 + FRAME_OVERHEAD);
+        assert_eq!(client.verify(Direction::ToClient, &frame), Some(&b"DATA"[..]));
+    }
+
+    #[test]
+    fn flipped_byte() {
+        let (server, client) = pair();
+        let frame = server.sign(Direction::ToClient, b"DATA");
+        for i in 0..frame.len() {
+            let mut forged = frame.clone();
+            forged[i] ^= 1;
+            assert_eq!(client.verify(Direction::ToClient, &forged), None, "байт {i}");
+        }
+    }
+
+    #[test]
+    fn wrong_direction() {
+        let (server, client) = pair();
+        let frame = server.sign(Direction::ToClient, b"PING");
+        assert_eq!(client.verify(Direction::ToServer, &frame), None);
+    }
+
+    #[test]
+    fn short_frame() {
+        let (_, client) = pair();
+        assert_eq!(client.verify(Direction::ToClient, &[0; TAG_SIZE - 1]), None);
+        assert_eq!(client.verify(Direction::ToClient, &[0; FRAME_OVERHEAD - 1]), None);
+    }
+
+    #[test]
+    fn replayed_frame() {
+        let (server, client) = pair();
+        let first = server.sign(Direction::ToClient, b"1");
+        let second = server.sign(Direction::ToClient, b"2");
+        assert!(client.verify(Direction::ToClient, &second).is_some());
+        assert_eq!(client.verify(Direction::ToClient, &second), None);
+        // Переставленная датаграмма внутри окна принимается один раз
+        assert!(client.verify(Direction::ToClient, &first).is_some());
+        assert_eq!(client.verify(Direction::ToClient, &first), None);
+    }
+
+    #[test]
+    fn replay_window() {
+        let (server, client) = pair();
+        let frames = (0..REPLAY_WINDOW + 1)
+            .map(|_| server.sign(Direction::ToClient, b"DATA"))
+            .collect::<Vec<Vec<u8>>>();
+        assert!(client.verify(Direction::ToClient, &frames[REPLAY_WINDOW as usize]).is_some());
+        // Номер 1 старше окна, а номер 2 последний, который окно еще помнит
+        assert_eq!(client.verify(Direction::ToClient, &frames[0]), None);
+        assert!(client.verify(Direction::ToClient, &frames[1]).is_some());
+        assert_eq!(client.verify(Direction::ToClient, &frames[1]), None);
+        assert!(client.verify(Direction::ToClient, &frames[30]).is_some());
+        assert_eq!(client.verify(Direction::ToClient, &frames[30]), None);
+    }
+
+    #[test]
+    fn window_shift() {
+        let mut window = ReplayWindow::default();
+        assert!(!window.accept(0));
+        assert!(window.accept(3));
+        assert!(window.accept(1));
+        assert!(!window.accept(3));
+        assert!(window.accept(3 + REPLAY_WINDOW));
+        // Сдвиг на все окно забывает старые номера, поэтому номер 3 теперь за пределами окна
+        assert!(!window.accept(3));
+        assert!(window.accept(4));
+        assert!(window.accept(u64::MAX));
+        assert!(!window.accept(u64::MAX));
+        assert!(!window.accept(4 + REPLAY_WINDOW));
+    }
+
+    #[test]
+    fn clones_share_counters() {
+        let (server, client) = pair();
+        let clone = server.clone();
+        let first = server.sign(Direction::ToClient, b"1");
+        let second = clone.sign(Direction::ToClient, b"2");
+        assert!(client.verify(Direction::ToClient, &first).is_some());
+        assert!(client.verify(Direction::ToClient, &second).is_some());
+    }
+
+    #[test]
+    fn hex_roundtrip() {
+        let key = SessionKey::new(KEY).unwrap();
+        let parsed = key.to_string().parse::<SessionKey>().unwrap();
+        let frame = key.sign(Direction::ToServer, b"REG");
+        assert!(parsed.verify(Direction::ToServer, &frame).is_some());
+    }
+
+    #[test]
+    fn malformed_hex_key() {
+        let valid = SessionKey::new(KEY).unwrap().to_string();
+        assert!(valid[1..].parse::<SessionKey>().is_err());
+        assert!(format!("{valid}00").parse::<SessionKey>().is_err());
+        assert!(format!("zz{}", &valid[2..]).parse::<SessionKey>().is_err());
+        assert!(format!("ж{}", &valid[2..]).parse::<SessionKey>().is_err());
+    }
+}
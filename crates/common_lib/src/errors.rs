@@ -2,7 +2,6 @@ use crate::errors::ErrType::{
     ConnectionError, CtrlcError, NoAccess, NotSupported, ParseError, ReadError, RequestError,
 };
 use std::fmt;
-use std::io::ErrorKind;
 use std::num::{ParseFloatError, ParseIntError};
 
 #[derive(Debug)]
@@ -30,6 +29,8 @@ impl fmt::Display for ErrType {
     }
 }
 
+impl std::error::Error for ErrType {}
+
 impl From<ParseFloatError> for ErrType {
     fn from(value: ParseFloatError) -> Self {
         ParseError(format!("Ошибка парсинга данных {}", value))
@@ -44,6 +45,6 @@ impl From<ParseIntError> for ErrType {
 
 impl From<ErrType> for std::io::Error {
     fn from(value: ErrType) -> Self {
-        std::io::Error::new(ErrorKind::Other, value.to_string())
+        std::io::Error::other(value.to_string())
     }
 }
@@ -0,0 +1,202 @@
+use std::io;
+use std::io::{Read, Write};
+
+/// Размер заголовка кадра. В заголовке длина сообщения в big endian
+const FRAME_HEADER_SIZE: usize = 4;
+/// Максимальный размер сообщения в кадре. Защищает от чтения мусора как длины
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+///
+/// Записать сообщение в поток одним кадром. Длина и сообщение пишутся одним вызовом, что бы кадры из
+/// разных потоков не перемешались
+///
+/// # Arguments
+///
+/// * `stream`: поток для записи
+/// * `message`: сообщение
+///
+/// returns: io::Result<()>
+///
+pub fn write_frame(mut stream: impl Write, message: &[u8]) -> io::Result<()> {
+    stream.write_all(&encode_frame(message)?)?;
+    stream.flush()
+}
+
+///
+/// Упаковать сообщение в кадр. Нужно, когда кадры копятся в буфере не блокирующегося соединения
+///
+/// # Arguments
+///
+/// * `message`: сообщение
+///
+/// returns: io::Result<Vec<u8>> - длина и сообщение
+///
+pub fn encode_frame(message: &[u8]) -> io::Result<Vec<u8>> {
+    if message.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Сообщение длиной {} не помещается в кадр", message.len()),
+        ));
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + message.len());
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    Ok(frame)
+}
+
+/// Собирает кадры из потока, в котором данные могут приходить частями
+#[derive(Default)]
+pub struct FrameReader {
+    pending: Vec<u8>,
+}
+
+impl FrameReader {
+    ///
+    /// # Arguments
+    ///
+    /// * `pending`: байты, которые уже прочитали из потока, например вместе с последней строкой рукопожатия
+    ///
+    pub fn new(pending: Vec<u8>) -> Self {
+        Self { pending }
+    }
+
+    ///
+    /// Прочитать из потока то, что уже пришло, и вернуть следующий кадр если он собрался целиком.
+    /// Поток должен быть не блокирующимся или с ограничением времени чтения
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: поток для чтения
+    ///
+    /// returns: io::Result<Option<Vec<u8>>> - None если кадр еще не собрался.
+    ///     Ошибка UnexpectedEof если другая сторона закрыла соединение
+    ///
+    pub fn read_frame(&mut self, mut stream: impl Read) -> io::Result<Option<Vec<u8>>> {
+        if let Some(frame) = self.take_frame()? {
+            return Ok(Some(frame));
+        }
+        let mut buf = [0u8; 4096];
+        // Читаем, пока кадр не соберется или данные в потоке не закончатся. Тогда None значит,
+        // что прочитано все, что пришло
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Соединение закрыто другой стороной",
+                    ));
+                }
+                Ok(n) => {
+                    self.pending.extend_from_slice(&buf[..n]);
+                    if let Some(frame) = self.take_frame()? {
+                        return Ok(Some(frame));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn take_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.pending.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        header.copy_from_slice(&self.pending[..FRAME_HEADER_SIZE]);
+        let len = u32::from_be_bytes(header) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Пришел кадр длиной {len}, больше допустимого"),
+            ));
+        }
+        if self.pending.len() < FRAME_HEADER_SIZE + len {
+            return Ok(None);
+        }
+        let frame = self.pending[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len].to_vec();
+        self.pending.drain(..FRAME_HEADER_SIZE + len);
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Поток, который отдает данные заранее заданными частями. Между частями чтение возвращает WouldBlock,
+    /// как не блокирующийся сокет, в который еще не пришли следующие данные
+    struct Chunks(VecDeque<Option<Vec<u8>>>);
+
+    impl Chunks {
+        fn new(chunks: &[&[u8]]) -> Self {
+            Self(chunks.iter().flat_map(|chunk| [Some(chunk.to_vec()), None]).collect())
+        }
+    }
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Some(chunk)) => {
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                Some(None) => Err(io::ErrorKind::WouldBlock.into()),
+                None => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        let frame = encode_frame(b"hello").unwrap();
+        let mut stream = Chunks::new(&[&frame[..2], &frame[2..6], &frame[6..]]);
+        let mut reader = FrameReader::default();
+        assert_eq!(reader.read_frame(&mut stream).unwrap(), None);
+        assert_eq!(reader.read_frame(&mut stream).unwrap(), None);
+        assert_eq!(reader.read_frame(&mut stream).unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(reader.read_frame(&mut stream).unwrap(), None);
+        let error = reader.read_frame(&mut stream).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn several_frames_in_one_read() {
+        let mut bytes = encode_frame(b"first").unwrap();
+        bytes.extend(encode_frame(b"").unwrap());
+        bytes.extend(encode_frame(b"third").unwrap());
+        let mut stream = Chunks::new(&[&bytes]);
+        let mut reader = FrameReader::new(Vec::new());
+        assert_eq!(reader.read_frame(&mut stream).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(reader.read_frame(&mut stream).unwrap(), Some(Vec::new()));
+        assert_eq!(reader.read_frame(&mut stream).unwrap(), Some(b"third".to_vec()));
+        assert_eq!(reader.read_frame(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn pending_bytes_from_handshake() {
+        let frame = encode_frame(b"quote").unwrap();
+        let mut reader = FrameReader::new(frame[..3].to_vec());
+        let mut stream = Chunks::new(&[&frame[3..]]);
+        assert_eq!(reader.read_frame(&mut stream).unwrap(), Some(b"quote".to_vec()));
+    }
+
+    #[test]
+    fn oversized_frame() {
+        assert!(encode_frame(&vec![0u8; MAX_FRAME_SIZE]).is_ok());
+        let error = encode_frame(&vec![0u8; MAX_FRAME_SIZE + 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let header = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+        let mut stream = Chunks::new(&[&header]);
+        let error = FrameReader::default().read_frame(&mut stream).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,21 +1,70 @@
 use std::time::Duration;
 
+pub mod admin;
+pub mod bye;
 pub mod ctrlc;
+pub mod datagram_auth;
 pub mod errors;
+pub mod framing;
 pub mod stock_quote;
+pub mod udp_batch;
 
 pub const DATA_REQUEST: &[u8; 4] = b"DATA";
 pub const PING_REQUEST: &[u8; 4] = b"PING";
 pub const PONG_REQUEST: &[u8; 4] = b"PONG";
+pub const SNAPSHOT_REQUEST: &[u8; 4] = b"SNAP";
+pub const BYE_REQUEST: &[u8; 3] = b"BYE";
+pub const REGISTER_REQUEST: &[u8; 3] = b"REG";
+pub const SUBSCRIBE_REQUEST: &[u8; 3] = b"SUB";
+pub const UNSUBSCRIBE_REQUEST: &[u8; 5] = b"UNSUB";
+/// Сервер сообщает котировки, на которые у клиента нет прав
+pub const DENIED_REQUEST: &[u8; 4] = b"DENY";
 pub const STREAM_REQUEST: &str = "STREAM";
+pub const AUTH_REQUEST: &str = "AUTH";
+/// Код ошибки, когда сервер требует авторизацию, а клиент прислал STREAM без AUTH
+pub const AUTH_REQUIRED_ERROR: &str = "AUTH_REQUIRED";
+/// Код ошибки, когда токен клиента не найден
+pub const AUTH_FAILED_ERROR: &str = "AUTH_FAILED";
+/// Код ошибки, когда клиент запросил котировки, на которые у него нет прав
+pub const NOT_ENTITLED_ERROR: &str = "NOT_ENTITLED";
 pub const OK_REQUEST: &str = "OK\n";
+pub const MULTICAST_STREAM: &str = "multicast";
+pub const TCP_STREAM: &str = "tcp";
+pub const NAT_STREAM: &str = "nat";
+pub const UDP_STREAM_PREFIX: &str = "udp://";
+pub const UNIX_STREAM_PREFIX: &str = "unix://";
+pub const TICKER_INTERVAL_OPTION: &str = "interval_ms";
+pub const MAX_PACKETS_OPTION: &str = "max_pps";
+pub const FILTER_OPTION: &str = "filter";
+pub const QUEUE_SIZE_OPTION: &str = "queue";
+pub const QUEUE_POLICY_OPTION: &str = "policy";
+/// Параметр ответа OK с ключом, которым подписываются udp датаграммы сессии
+pub const SESSION_KEY_OPTION: &str = "key";
 
 pub const QUOTE_GENERATOR_PERIOD: Duration = Duration::new(2, 0);
 pub const PING_WAIT_PERIOD: Duration = Duration::new(5, 0);
 pub const PING_SEND_PERIOD: Duration = Duration::new(1, 0);
 pub const UDP_SERVER_RECEIVE_PERIOD: Duration = Duration::new(0, 50_000_000);
+pub const UDP_CLIENT_RECEIVE_PERIOD: Duration = Duration::new(0, 50_000_000);
 pub const QUOTES_WAIT_PERIOD: Duration = Duration::new(6, 0);
 pub const TCP_CONNECTION_WAIT_PERIOD: Duration = Duration::new(0, 100_000_000);
 pub const UDP_CONNECTION_WAIT_PERIOD: Duration = Duration::new(5, 0);
+pub const TCP_WRITE_WAIT_PERIOD: Duration = Duration::new(5, 0);
+pub const UNIX_CONNECTION_WAIT_PERIOD: Duration = Duration::new(5, 0);
+pub const HANDSHAKE_WAIT_PERIOD: Duration = Duration::new(10, 0);
 
-pub const MAX_NUMBER_IGNORED_PING: u16 = 3;
\ No newline at end of file
+pub const MAX_NUMBER_IGNORED_PING: u16 = 3;
+pub const SUBSCRIBER_QUEUE_SIZE: usize = 16;
+/// Наибольший размер очереди, который клиент может запросить параметром queue
+pub const MAX_SUBSCRIBER_QUEUE_SIZE: usize = 1024;
+/// На сколько котировок может быть подписана одна сессия
+pub const MAX_SESSION_TICKERS: usize = 256;
+pub const MAX_PENDING_HANDSHAKES: usize = 64;
+pub const MAX_HANDSHAKES_PER_MINUTE: u32 = 120;
+pub const MAX_SESSIONS: usize = 10000;
+pub const MAX_SESSIONS_PER_IP: usize = 64;
+/// Максимальная длина строки рукопожатия. Защищает от клиента, который шлет данные без перевода строки
+pub const MAX_HANDSHAKE_LINE_SIZE: usize = 4096;
+/// Максимальный размер udp сообщения с котировками. Больше котировок разбиваются на несколько сообщений,
+/// что бы датаграмма не фрагментировалась
+pub const MAX_DATAGRAM_SIZE: usize = 1400;
\ No newline at end of file
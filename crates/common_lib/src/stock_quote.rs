@@ -39,7 +39,7 @@ impl FromStr for StockQuote {
         } else {
             Err(ErrType::NotSupported(format!(
                 "Не удалось прочитать котировку из строку {}",
-                s.to_string()
+                s
             )))
         }
     }
@@ -0,0 +1,205 @@
+//! Отправка и получение нескольких udp датаграмм за один системный вызов. На Linux используются
+//! sendmmsg и recvmmsg, на остальных платформах датаграммы отправляются и читаются по одной
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// Сколько датаграмм читается за один системный вызов
+pub const RECEIVE_BATCH_SIZE: usize = 32;
+
+///
+/// Отправить несколько датаграмм на один адрес
+///
+/// # Arguments
+///
+/// * `socket`: udp сокет. На Linux подходит любой сокет с дескриптором, например сокет mio
+/// * `messages`: датаграммы
+/// * `address`: адрес получателя
+///
+/// returns: io::Result<()>
+///
+#[cfg(target_os = "linux")]
+pub fn send_batch(
+    socket: &impl std::os::fd::AsRawFd,
+    messages: &[Vec<u8>],
+    address: SocketAddr,
+) -> io::Result<()> {
+    use socket2::SockAddr;
+
+    let address = SockAddr::from(address);
+    let mut iovecs = messages
+        .iter()
+        .map(|message| libc::iovec {
+            iov_base: message.as_ptr() as *mut libc::c_void,
+            iov_len: message.len(),
+        })
+        .collect::<Vec<libc::iovec>>();
+    let mut headers = iovecs
+        .iter_mut()
+        .map(|iovec| {
+            // SAFETY: заголовок из нулей корректен, нужные поля заполняются ниже
+            let mut header: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            header.msg_hdr.msg_name = address.as_ptr() as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = address.len();
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+            header
+        })
+        .collect::<Vec<libc::mmsghdr>>();
+
+    // sendmmsg может отправить не все сообщения, тогда досылаем оставшиеся
+    let mut sent = 0;
+    while sent < headers.len() {
+        // SAFETY: заголовки ссылаются на iovecs, messages и address, которые живы до конца функции
+        let result = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                headers[sent..].as_mut_ptr(),
+                (headers.len() - sent) as libc::c_uint,
+                0,
+            )
+        };
+        if result < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        sent += result as usize;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_batch(socket: &UdpSocket, messages: &[Vec<u8>], address: SocketAddr) -> io::Result<()> {
+    for message in messages {
+        socket.send_to(message, address)?;
+    }
+    Ok(())
+}
+
+/// Буферы для чтения пачки датаграмм. Датаграммы отдаются по одной, а новая пачка читается,
+/// когда прочитанные закончились
+pub struct ReceiveBatch {
+    buffers: Vec<Vec<u8>>,
+    received: Vec<(usize, SocketAddr)>,
+    next: usize,
+}
+
+impl ReceiveBatch {
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer_size`: размер буфера для одной датаграммы
+    ///
+    /// returns: ReceiveBatch
+    ///
+    pub fn new(buffer_size: usize) -> Self {
+        Self {
+            buffers: vec![vec![0u8; buffer_size]; RECEIVE_BATCH_SIZE],
+            received: Vec::with_capacity(RECEIVE_BATCH_SIZE),
+            next: 0,
+        }
+    }
+
+    ///
+    /// Получить следующую датаграмму. Ведет себя как UdpSocket::recv_from: если датаграмм нет,
+    /// то возвращается ошибка WouldBlock для неблокирующего сокета
+    ///
+    /// # Arguments
+    ///
+    /// * `socket`: udp сокет
+    /// * `buf`: буфер для сообщения
+    ///
+    /// returns: io::Result<(usize, SocketAddr)> - длина сообщения и адрес отправителя
+    ///
+    pub fn recv_from(&mut self, socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if self.next >= self.received.len() {
+            self.fill(socket)?;
+        }
+        let (n, from) = self.received[self.next];
+        let index = self.next;
+        self.next += 1;
+        let n = n.min(buf.len());
+        buf[..n].copy_from_slice(&self.buffers[index][..n]);
+        Ok((n, from))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn fill(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        use socket2::{SockAddr, SockAddrStorage};
+        use std::os::fd::AsRawFd;
+
+        self.received.clear();
+        self.next = 0;
+
+        let mut addresses = (0..self.buffers.len())
+            .map(|_| SockAddrStorage::zeroed())
+            .collect::<Vec<SockAddrStorage>>();
+        let mut iovecs = self
+            .buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            })
+            .collect::<Vec<libc::iovec>>();
+        let mut headers = iovecs
+            .iter_mut()
+            .zip(addresses.iter_mut())
+            .map(|(iovec, address)| {
+                // SAFETY: заголовок из нулей корректен, нужные поля заполняются ниже
+                let mut header: libc::mmsghdr = unsafe { std::mem::zeroed() };
+                header.msg_hdr.msg_name = address as *mut SockAddrStorage as *mut libc::c_void;
+                header.msg_hdr.msg_namelen = address.size_of();
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect::<Vec<libc::mmsghdr>>();
+
+        // Ждем только первую датаграмму, остальные забираем, если они уже пришли
+        let count = loop {
+            // SAFETY: заголовки ссылаются на буферы и адреса, которые живы до конца функции
+            let result = unsafe {
+                libc::recvmmsg(
+                    socket.as_raw_fd(),
+                    headers.as_mut_ptr(),
+                    headers.len() as libc::c_uint,
+                    libc::MSG_WAITFORONE,
+                    std::ptr::null_mut(),
+                )
+            };
+            if result >= 0 {
+                break result as usize;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        };
+
+        for (header, address) in headers.iter().zip(addresses).take(count) {
+            // SAFETY: адрес заполнен recvmmsg, длина взята из заголовка
+            let address = unsafe { SockAddr::new(address, header.msg_hdr.msg_namelen) };
+            let Some(from) = address.as_socket() else {
+                continue;
+            };
+            self.received.push((header.msg_len as usize, from));
+        }
+        if self.received.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn fill(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        self.received.clear();
+        self.next = 0;
+        let received = socket.recv_from(&mut self.buffers[0])?;
+        self.received.push(received);
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand};
+use common_lib::TCP_WRITE_WAIT_PERIOD;
+use common_lib::admin::AdminCommand;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "quotectl",
+    version,
+    about = "Управление работающим сервером котировок через порт управления"
+)]
+struct Cli {
+    /// Адрес порта управления сервера
+    #[arg(long, default_value = "127.0.0.1:1114")]
+    address: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Список активных сессий: номер, адрес, котировки, время работы, последний ping и отправленные пакеты
+    List,
+    /// Закрыть сессию. Номер сессии берется из вывода list
+    Kick { session: u64 },
+    /// Добавить котировки в генератор, например `add NVDA,AMD`
+    Add {
+        #[arg(value_delimiter = ',', required = true)]
+        tickers: Vec<String>,
+    },
+    /// Убрать котировки из генератора
+    Remove {
+        #[arg(value_delimiter = ',', required = true)]
+        tickers: Vec<String>,
+    },
+    /// Приостановить обновление котировок
+    Pause,
+    /// Продолжить обновление котировок
+    Resume,
+    /// Вывести состояние сервера в json
+    Dump,
+}
+
+impl From<Command> for AdminCommand {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::List => AdminCommand::List,
+            Command::Kick { session } => AdminCommand::Kick(session),
+            Command::Add { tickers } => AdminCommand::Add(tickers),
+            Command::Remove { tickers } => AdminCommand::Remove(tickers),
+            Command::Pause => AdminCommand::Pause,
+            Command::Resume => AdminCommand::Resume,
+            Command::Dump => AdminCommand::Dump,
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let command = AdminCommand::from(cli.command);
+
+    let mut stream = TcpStream::connect(&cli.address)
+        .with_context(|| format!("Не удалось подключиться к порту управления {}", cli.address))?;
+    stream.set_read_timeout(Some(TCP_WRITE_WAIT_PERIOD))?;
+    stream.write_all(format!("{command}\n").as_bytes())?;
+    // Больше команд не будет, сервер закроет соединение после ответа
+    stream.shutdown(Shutdown::Write)?;
+
+    // Ответ заканчивается строкой OK или ERROR, остальные строки выводятся как есть
+    for line in BufReader::new(stream).lines() {
+        let line = line.context("Не удалось прочитать ответ сервера")?;
+        if line == "OK" {
+            return Ok(());
+        }
+        if let Some(error) = line.strip_prefix("ERROR: ") {
+            bail!("{error}");
+        }
+        println!("{line}");
+    }
+    bail!("Сервер закрыл соединение не завершив ответ")
+}
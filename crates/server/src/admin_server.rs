@@ -0,0 +1,312 @@
+use crate::connection_limits::{ConnectionLimits, LimitOptions};
+use crate::stock_quotes_handler::{QuoteHandler, SessionId};
+use crate::tcp_server;
+use common_lib::admin::AdminCommand;
+use common_lib::errors::ErrType;
+use common_lib::errors::ErrType::{ConnectionError, NoAccess};
+use common_lib::stock_quote::StockQuote;
+use common_lib::{MAX_HANDSHAKE_LINE_SIZE, OK_REQUEST, TCP_CONNECTION_WAIT_PERIOD};
+use serde::Serialize;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{thread, thread::JoinHandle};
+
+/// Ограничения подключений к порту управления. Они отдельные от клиентских, что бы администратор мог
+/// подключиться и тогда, когда лимит сессий клиентов исчерпан
+const ADMIN_LIMITS: LimitOptions = LimitOptions {
+    max_pending_handshakes: 4,
+    max_handshakes_per_minute: 60,
+    max_sessions: 4,
+    max_sessions_per_ip: 4,
+};
+
+/// Состояние сервера для команды DUMP
+#[derive(Debug, Serialize)]
+struct Dump {
+    paused: bool,
+    tickers: Vec<String>,
+    dropped_batches: u64,
+    sessions: Vec<SessionDump>,
+    quotes: Vec<StockQuote>,
+}
+
+/// Сессия подписчика в DUMP
+#[derive(Debug, Serialize)]
+struct SessionDump {
+    session: SessionId,
+    address: String,
+    tickers: Vec<String>,
+    uptime_ms: u128,
+    /// Сколько миллисекунд назад пришел последний ping. Потоковые соединения ping не присылают
+    last_ping_ms: Option<u128>,
+    sent: u64,
+    dropped: u64,
+}
+
+/// Порт управления работающим сервером. Принимает текстовые команды по одной в строке, например `LIST`
+/// или `KICK 3`, и отвечает строками, последняя из которых `OK` или `ERROR: ...`.
+/// По умолчанию слушает только localhost, потому что проверки прав нет
+pub struct AdminServer {
+    stop: Arc<AtomicBool>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl AdminServer {
+    ///
+    /// Запустить поток, который принимает соединения порта управления
+    ///
+    /// # Arguments
+    ///
+    /// * `address`: адрес на котором слушать соединения
+    /// * `stocks`: хранитель котировок, через него читается и меняется состояние сервера
+    ///
+    /// returns: Result<AdminServer, ErrType>
+    ///
+    pub fn start(address: SocketAddr, stocks: Arc<QuoteHandler>) -> Result<Self, ErrType> {
+        let listener = tcp_server::bind(address).map_err(|e| {
+            ConnectionError(format!("Не удалось запустить порт управления на {address}. {e}"))
+        })?;
+        listener.set_nonblocking(true).map_err(|e| {
+            ConnectionError(format!("Не удалось настроить порт управления на {address}. {e}"))
+        })?;
+        log::info!("Порт управления начал работу и слушает {address}");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = stop.clone();
+        Ok(Self {
+            stop,
+            join_handle: Some(thread::spawn(move || {
+                Self::accept(listener, stop_clone, stocks, Arc::new(ConnectionLimits::new(ADMIN_LIMITS)))
+            })),
+        })
+    }
+
+    ///
+    /// Остановить порт управления
+    ///
+    pub fn stop(&mut self) -> Result<(), ErrType> {
+        self.stop.store(true, Ordering::Release);
+        if let Some(h) = self.join_handle.take()
+            && h.join().is_err()
+        {
+            log::error!("Ошибка остановки порта управления");
+            return Err(NoAccess(
+                "Не удалось завершить работу потока порта управления".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Цикл приема соединений. Каждое соединение обслуживается в своем потоке, количество потоков
+    /// ограничено лимитом сессий порта управления
+    fn accept(
+        listener: TcpListener,
+        stop: Arc<AtomicBool>,
+        stocks: Arc<QuoteHandler>,
+        limits: Arc<ConnectionLimits>,
+    ) {
+        let mut clients: Vec<JoinHandle<()>> = Vec::new();
+        for stream in listener.incoming() {
+            if stop.load(Ordering::Acquire) {
+                log::info!("Остановка работы порта управления");
+                break;
+            }
+            clients.retain(|c| !c.is_finished());
+
+            match stream {
+                Ok(mut stream) => {
+                    let ip = match stream.peer_addr() {
+                        Ok(peer) => peer.ip(),
+                        Err(e) => {
+                            log::warn!("Не удалось получить адрес подключения к порту управления. {e}");
+                            continue;
+                        }
+                    };
+                    let session = match limits.begin_handshake(ip) {
+                        Ok(permit) => permit.begin_session(ip),
+                        Err(rejection) => Err(NoAccess(format!(
+                            "Слишком много подключений к порту управления ({rejection:?})"
+                        ))),
+                    };
+                    let session = match session {
+                        Ok(session) => session,
+                        Err(e) => {
+                            log::warn!("Отказано в подключении к порту управления с {ip}. {e}");
+                            let _ = stream.write_all(format!("ERROR: {e}\n").as_bytes());
+                            continue;
+                        }
+                    };
+                    let stop = stop.clone();
+                    let stocks = stocks.clone();
+                    clients.push(thread::spawn(move || {
+                        // Место в лимите освобождается, когда соединение закрывается
+                        let _session = session;
+                        if let Err(e) = Self::serve(stream, &stop, &stocks) {
+                            log::warn!("Соединение порта управления закрыто с ошибкой. {e}");
+                        }
+                    }));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(TCP_CONNECTION_WAIT_PERIOD);
+                }
+                Err(e) => log::error!("Не удалось принять соединение порта управления. {e}"),
+            }
+        }
+        for client in clients {
+            let _ = client.join();
+        }
+    }
+
+    ///
+    /// Обслуживание одного соединения порта управления. Команды выполняются пока клиент не закроет соединение
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: tcp соединение
+    /// * `stop`: флаг остановки порта управления
+    /// * `stocks`: хранитель котировок
+    ///
+    /// returns: Result<(), ErrType>
+    ///
+    fn serve(stream: TcpStream, stop: &AtomicBool, stocks: &QuoteHandler) -> Result<(), ErrType> {
+        let error = |e: io::Error| ConnectionError(format!("Ошибка соединения порта управления. {e}"));
+        stream.set_nonblocking(false).map_err(error)?;
+        // Чтение с коротким ожиданием, что бы замечать остановку сервера
+        stream
+            .set_read_timeout(Some(TCP_CONNECTION_WAIT_PERIOD))
+            .map_err(error)?;
+        let peer = stream.peer_addr().map_err(error)?;
+        let mut writer = stream.try_clone().map_err(error)?;
+        let mut reader = BufReader::new(stream);
+        log::info!("Подключение к порту управления {peer}");
+
+        let mut line = String::new();
+        while !stop.load(Ordering::Acquire) {
+            // Читаем не дальше MAX_HANDSHAKE_LINE_SIZE, что бы строка без перевода строки не копилась в памяти.
+            // Прочитанная до истечения ожидания часть строки остается в line, дочитаем ее следующим вызовом
+            let limit = (MAX_HANDSHAKE_LINE_SIZE + 1).saturating_sub(line.len()) as u64;
+            match reader.by_ref().take(limit).read_line(&mut line) {
+                Ok(0) if line.is_empty() => break,
+                Ok(_) if line.len() > MAX_HANDSHAKE_LINE_SIZE => {
+                    let _ = writer.write_all("ERROR: Слишком длинная команда\n".as_bytes());
+                    break;
+                }
+                Ok(_) => {
+                    let input = line.trim().to_string();
+                    line.clear();
+                    if input.is_empty() {
+                        continue;
+                    }
+                    log::info!("Команда порта управления от {peer}: {input}");
+                    let answer = match input.parse::<AdminCommand>() {
+                        Ok(command) => Self::execute(command, stocks),
+                        Err(e) => Err(e),
+                    };
+                    let answer = match answer {
+                        Ok(lines) => lines + OK_REQUEST,
+                        Err(e) => format!("ERROR: {e}\n"),
+                    };
+                    writer.write_all(answer.as_bytes()).map_err(error)?;
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(error(e)),
+            }
+        }
+        log::info!("Соединение порта управления {peer} закрыто");
+        Ok(())
+    }
+
+    ///
+    /// Выполнить команду
+    ///
+    /// # Arguments
+    ///
+    /// * `command`: команда порта управления
+    /// * `stocks`: хранитель котировок
+    ///
+    /// returns: Result<String, ErrType> - строки ответа перед `OK`
+    ///
+    fn execute(command: AdminCommand, stocks: &QuoteHandler) -> Result<String, ErrType> {
+        match command {
+            AdminCommand::List => {
+                let mut lines = String::new();
+                for session in stocks.sessions() {
+                    let last_ping = match session.stats.last_ping() {
+                        Some(last_ping) => format!("{:.1}s", last_ping.as_secs_f64()),
+                        None => "-".to_string(),
+                    };
+                    lines += &format!(
+                        "{} {} tickers={} uptime={}s last_ping={last_ping} sent={} dropped={}\n",
+                        session.session,
+                        session.address,
+                        session.tickers.join(","),
+                        session.stats.uptime().as_secs(),
+                        session.stats.sent(),
+                        session.stats.dropped()
+                    );
+                }
+                Ok(lines)
+            }
+            AdminCommand::Kick(session) => {
+                if !stocks.kick(session) {
+                    return Err(ErrType::RequestError(format!("Сессия {session} не найдена")));
+                }
+                log::warn!("Сессия {session} закрыта через порт управления");
+                Ok(String::new())
+            }
+            AdminCommand::Add(tickers) => {
+                let added = stocks.add_tickers(&tickers)?;
+                log::info!("Через порт управления добавлены котировки {}", added.join(","));
+                Ok(format!("added={}\n", added.join(",")))
+            }
+            AdminCommand::Remove(tickers) => {
+                let removed = stocks.remove_tickers(&tickers)?;
+                log::info!("Через порт управления удалены котировки {}", removed.join(","));
+                Ok(format!("removed={}\n", removed.join(",")))
+            }
+            AdminCommand::Pause => {
+                stocks.set_paused(true);
+                log::warn!("Обновление котировок приостановлено через порт управления");
+                Ok(String::new())
+            }
+            AdminCommand::Resume => {
+                stocks.set_paused(false);
+                log::info!("Обновление котировок продолжено через порт управления");
+                Ok(String::new())
+            }
+            AdminCommand::Dump => {
+                let dump = Dump {
+                    paused: stocks.is_paused(),
+                    tickers: stocks.tickers(),
+                    dropped_batches: stocks.dropped_batches(),
+                    sessions: stocks
+                        .sessions()
+                        .into_iter()
+                        .map(|session| SessionDump {
+                            uptime_ms: session.stats.uptime().as_millis(),
+                            last_ping_ms: session.stats.last_ping().map(|t| t.as_millis()),
+                            session: session.session,
+                            sent: session.stats.sent(),
+                            dropped: session.stats.dropped(),
+                            address: session.address,
+                            tickers: session.tickers,
+                        })
+                        .collect(),
+                    quotes: stocks
+                        .snapshot()
+                        .map(|snapshot| snapshot.iter().map(|q| q.quote().clone()).collect())
+                        .unwrap_or_default(),
+                };
+                let json = serde_json::to_string_pretty(&dump).map_err(|e| {
+                    ErrType::ParseError(format!("Не удалось сериализовать состояние в json. {e}"))
+                })?;
+                Ok(json + "\n")
+            }
+        }
+    }
+}
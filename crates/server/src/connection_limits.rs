@@ -0,0 +1,256 @@
+use crate::metrics::HandshakeRejection;
+use common_lib::errors::ErrType;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Окно, за которое считаются подключения с одного адреса
+const HANDSHAKE_WINDOW: Duration = Duration::new(60, 0);
+/// Сколько адресов хранить. Когда место заканчивается, выкидываются адреса с истекшим окном,
+/// а если таких нет, то адрес с самым старым окном
+const MAX_TRACKED_ADDRESSES: usize = 1024;
+
+/// Ограничения подключений. Защищают сервер от скрипта, который открывает соединения без остановки
+#[derive(Debug, Clone, Copy)]
+pub struct LimitOptions {
+    /// Сколько подключений одновременно могут проходить рукопожатие
+    pub max_pending_handshakes: usize,
+    /// Сколько подключений с одного адреса принимается за минуту
+    pub max_handshakes_per_minute: u32,
+    /// Сколько всего сессий может обслуживать сервер
+    pub max_sessions: usize,
+    /// Сколько сессий может открыть один адрес
+    pub max_sessions_per_ip: usize,
+}
+
+#[derive(Default)]
+struct LimitState {
+    pending: usize,
+    /// Начало окна и количество подключений за окно по адресам
+    handshakes: HashMap<IpAddr, (Instant, u32)>,
+    sessions: usize,
+    sessions_per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Счетчики подключений и сессий. Разрешения освобождаются сами, когда рукопожатие или сессия заканчиваются
+pub struct ConnectionLimits {
+    options: LimitOptions,
+    state: Mutex<LimitState>,
+}
+
+/// Разрешение на рукопожатие. Пока оно живо, подключение считается незавершенным
+pub struct HandshakePermit {
+    limits: Arc<ConnectionLimits>,
+}
+
+/// Разрешение на сессию. Живет вместе с соединением клиента
+pub struct SessionPermit {
+    limits: Arc<ConnectionLimits>,
+    ip: IpAddr,
+}
+
+impl ConnectionLimits {
+    pub fn new(options: LimitOptions) -> Self {
+        Self {
+            options,
+            state: Mutex::new(LimitState::default()),
+        }
+    }
+
+    ///
+    /// Разрешить рукопожатие нового подключения
+    ///
+    /// # Arguments
+    ///
+    /// * `ip`: адрес клиента
+    ///
+    /// returns: Result<HandshakePermit, HandshakeRejection> - Busy если слишком много незавершенных подключений,
+    ///     RateLimited если адрес подключается слишком часто
+    ///
+    pub fn begin_handshake(self: &Arc<Self>, ip: IpAddr) -> Result<HandshakePermit, HandshakeRejection> {
+        let Ok(mut state) = self.state.lock() else {
+            return Err(HandshakeRejection::Internal);
+        };
+        // ipv4 клиент на ipv6 сокете приходит с адресом ::ffff:a.b.c.d, считаем его как ipv4
+        let ip = ip.to_canonical();
+        if state.pending >= self.options.max_pending_handshakes {
+            return Err(HandshakeRejection::Busy);
+        }
+
+        let now = Instant::now();
+        if state.handshakes.len() >= MAX_TRACKED_ADDRESSES && !state.handshakes.contains_key(&ip) {
+            state
+                .handshakes
+                .retain(|_, (started, _)| now.duration_since(*started) < HANDSHAKE_WINDOW);
+            // Все окна еще идут. Память важнее точного счета, поэтому забываем самый старый адрес
+            if state.handshakes.len() >= MAX_TRACKED_ADDRESSES
+                && let Some(oldest) = state
+                    .handshakes
+                    .iter()
+                    .min_by_key(|(_, (started, _))| *started)
+                    .map(|(ip, _)| *ip)
+            {
+                state.handshakes.remove(&oldest);
+            }
+        }
+        let (started, count) = state.handshakes.entry(ip).or_insert((now, 0));
+        if now.duration_since(*started) >= HANDSHAKE_WINDOW {
+            *started = now;
+            *count = 0;
+        }
+        if *count >= self.options.max_handshakes_per_minute {
+            return Err(HandshakeRejection::RateLimited);
+        }
+        *count += 1;
+
+        state.pending += 1;
+        Ok(HandshakePermit {
+            limits: self.clone(),
+        })
+    }
+
+    ///
+    /// Разрешить новую сессию
+    ///
+    /// # Arguments
+    ///
+    /// * `ip`: адрес клиента
+    ///
+    /// returns: Result<SessionPermit, ErrType> - ошибка с текстом для клиента, если лимит сессий исчерпан
+    ///
+    fn begin_session(self: &Arc<Self>, ip: IpAddr) -> Result<SessionPermit, ErrType> {
+        let Ok(mut state) = self.state.lock() else {
+            return Err(ErrType::NoAccess("Не удалось проверить количество сессий".to_string()));
+        };
+        let ip = ip.to_canonical();
+        if state.sessions >= self.options.max_sessions {
+            return Err(ErrType::NoAccess(format!(
+                "Сервер обслуживает максимальное количество сессий {}",
+                self.options.max_sessions
+            )));
+        }
+        let sessions = state.sessions_per_ip.entry(ip).or_insert(0);
+        if *sessions >= self.options.max_sessions_per_ip {
+            return Err(ErrType::NoAccess(format!(
+                "С адреса {ip} уже открыто максимальное количество сессий {}",
+                self.options.max_sessions_per_ip
+            )));
+        }
+        *sessions += 1;
+        state.sessions += 1;
+        Ok(SessionPermit {
+            limits: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl HandshakePermit {
+    ///
+    /// Разрешить сессию клиенту, который прошел рукопожатие
+    ///
+    /// # Arguments
+    ///
+    /// * `ip`: адрес клиента
+    ///
+    /// returns: Result<SessionPermit, ErrType> - ошибка с текстом для клиента, если лимит сессий исчерпан
+    ///
+    pub fn begin_session(&self, ip: IpAddr) -> Result<SessionPermit, ErrType> {
+        self.limits.begin_session(ip)
+    }
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.limits.state.lock() {
+            state.pending -= 1;
+        }
+    }
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.limits.state.lock() {
+            state.sessions -= 1;
+            if let Some(sessions) = state.sessions_per_ip.get_mut(&self.ip) {
+                *sessions -= 1;
+                if *sessions == 0 {
+                    state.sessions_per_ip.remove(&self.ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    fn limits(max_pending_handshakes: usize, max_handshakes_per_minute: u32) -> Arc<ConnectionLimits> {
+        Arc::new(ConnectionLimits::new(LimitOptions {
+            max_pending_handshakes,
+            max_handshakes_per_minute,
+            max_sessions: 2,
+            max_sessions_per_ip: 1,
+        }))
+    }
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n))
+    }
+
+    #[test]
+    fn handshake_permit_released_on_drop() {
+        let limits = limits(1, 10);
+        let permit = limits.begin_handshake(ip(1)).unwrap();
+        assert!(matches!(limits.begin_handshake(ip(2)), Err(HandshakeRejection::Busy)));
+        drop(permit);
+        assert!(limits.begin_handshake(ip(2)).is_ok());
+    }
+
+    #[test]
+    fn session_permit_released_on_drop() {
+        let limits = limits(10, 10);
+        let handshake = limits.begin_handshake(ip(1)).unwrap();
+        let first = handshake.begin_session(ip(1)).unwrap();
+        assert!(handshake.begin_session(ip(1)).is_err());
+        let second = handshake.begin_session(ip(2)).unwrap();
+        assert!(handshake.begin_session(ip(3)).is_err());
+
+        drop(first);
+        let first = handshake.begin_session(ip(1)).unwrap();
+        drop((first, second));
+        let state = limits.state.lock().unwrap();
+        assert_eq!(state.sessions, 0);
+        assert!(state.sessions_per_ip.is_empty());
+    }
+
+    #[test]
+    fn ipv4_mapped_counts_as_ipv4() {
+        let limits = limits(10, 1);
+        let _permit = limits.begin_handshake(ip(1)).unwrap();
+        let IpAddr::V4(v4) = ip(1) else { unreachable!() };
+        let mapped = IpAddr::V6(v4.to_ipv6_mapped());
+        assert!(matches!(limits.begin_handshake(mapped), Err(HandshakeRejection::RateLimited)));
+    }
+
+    #[test]
+    fn oldest_address_evicted() {
+        let limits = limits(10, 1);
+        assert!(limits.begin_handshake(ip(0)).is_ok());
+        // Окно первого адреса должно быть точно старше остальных
+        thread::sleep(Duration::from_millis(1));
+        for n in 1..MAX_TRACKED_ADDRESSES as u32 {
+            assert!(limits.begin_handshake(ip(n)).is_ok());
+        }
+        assert!(matches!(limits.begin_handshake(ip(0)), Err(HandshakeRejection::RateLimited)));
+
+        // Новый адрес при заполненной таблице вытесняет адрес с самым старым окном
+        assert!(limits.begin_handshake(ip(MAX_TRACKED_ADDRESSES as u32)).is_ok());
+        assert_eq!(limits.state.lock().unwrap().handshakes.len(), MAX_TRACKED_ADDRESSES);
+        assert!(limits.begin_handshake(ip(0)).is_ok());
+    }
+}
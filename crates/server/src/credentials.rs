@@ -0,0 +1,131 @@
+use common_lib::errors::ErrType;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Токены клиентов. В файле хранятся только sha256 хеши токенов, поэтому утечка файла не раскрывает токены.
+/// Каждая строка файла это имя клиента и hex хеш его токена через пробел, например
+/// `alice 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08`. Пустые строки и строки с `#` пропускаются
+#[derive(Debug, Default)]
+pub struct Credentials {
+    /// Имена клиентов по хешу токена
+    by_hash: HashMap<String, String>,
+}
+
+impl Credentials {
+    ///
+    /// Прочитать файл с хешами токенов
+    ///
+    /// # Arguments
+    ///
+    /// * `filename`: путь до файла
+    ///
+    /// returns: Result<Credentials, ErrType>
+    ///
+    pub fn load(filename: &Path) -> Result<Self, ErrType> {
+        let name = filename.display();
+        let file = File::open(filename)
+            .map_err(|e| ErrType::ReadError(format!("Ошибка при открытии файла {name}. {e}")))?;
+        let mut by_hash = HashMap::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line
+                .map_err(|e| ErrType::ReadError(format!("Ошибка при чтении файла {name}. {e}")))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let (Some(user), Some(hash), None) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(ErrType::ParseError(format!(
+                    "Строка {} файла {name} должна содержать имя и хеш токена через пробел",
+                    number + 1
+                )));
+            };
+            let hash = hash.to_lowercase();
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(ErrType::ParseError(format!(
+                    "Строка {} файла {name}: хеш токена {user} должен быть sha256 в hex",
+                    number + 1
+                )));
+            }
+            by_hash.insert(hash, user.to_string());
+        }
+        Ok(Self { by_hash })
+    }
+
+    ///
+    /// Проверить токен клиента
+    ///
+    /// # Arguments
+    ///
+    /// * `token`: токен из команды AUTH
+    ///
+    /// returns: Option<&str> - имя клиента, None если токен неизвестен
+    ///
+    pub fn authenticate(&self, token: &str) -> Option<&str> {
+        let hash = Sha256::digest(token.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        self.by_hash.get(&hash).map(String::as_str)
+    }
+
+    /// Количество клиентов в файле
+    pub fn len(&self) -> usize {
+        self.by_hash.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process};
+
+    /// sha256 от токена `test`
+    const TEST_HASH: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn load(name: &str, content: &str) -> Result<Credentials, ErrType> {
+        let path = std::env::temp_dir().join(format!("credentials-{}-{name}.txt", process::id()));
+        fs::write(&path, content).unwrap();
+        let credentials = Credentials::load(&path);
+        fs::remove_file(&path).unwrap();
+        credentials
+    }
+
+    #[test]
+    fn valid_file() {
+        let content = format!("# клиенты\n\nalice {TEST_HASH}\n  bob {}  \n", TEST_HASH.to_uppercase().replace('9', "0"));
+        let credentials = load("valid", &content).unwrap();
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(credentials.authenticate("test"), Some("alice"));
+        assert_eq!(credentials.authenticate("TEST"), None);
+        assert_eq!(credentials.authenticate(""), None);
+    }
+
+    #[test]
+    fn bad_lines() {
+        let lines = [
+            "alice".to_string(),
+            format!("alice {TEST_HASH} extra"),
+            format!("alice {}", &TEST_HASH[..63]),
+            format!("alice {TEST_HASH}0"),
+            format!("alice {}g", &TEST_HASH[..63]),
+            format!("alice {}я", &TEST_HASH[..62]),
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            let content = format!("# клиенты\nbob {TEST_HASH}\n{line}\n");
+            let Err(ErrType::ParseError(error)) = load(&format!("bad{i}"), &content) else {
+                panic!("строка {line} должна быть ошибкой");
+            };
+            assert!(error.contains("Строка 3"), "{error}");
+        }
+    }
+
+    #[test]
+    fn missing_file() {
+        let path = std::env::temp_dir().join(format!("credentials-{}-missing.txt", process::id()));
+        assert!(matches!(Credentials::load(&path), Err(ErrType::ReadError(_))));
+    }
+}
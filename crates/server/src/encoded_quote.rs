@@ -0,0 +1,52 @@
+use common_lib::errors::ErrType;
+use common_lib::stock_quote::StockQuote;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Котировка вместе с ее bincode представлением. Котировка кодируется один раз за обновление,
+/// а DATA сообщения для всех клиентов собираются склеиванием готовых байт
+#[derive(Debug)]
+pub struct EncodedQuote {
+    quote: StockQuote,
+    bytes: Vec<u8>,
+}
+
+/// Котировка, которую разделяют все подписчики одного обновления
+pub type SharedQuote = Arc<EncodedQuote>;
+/// Значения котировок одного обновления
+pub type Quotes = Arc<Vec<SharedQuote>>;
+
+impl EncodedQuote {
+    ///
+    /// Закодировать котировку
+    ///
+    /// # Arguments
+    ///
+    /// * `quote`: котировка
+    ///
+    /// returns: Result<EncodedQuote, ErrType>
+    ///
+    pub fn new(quote: StockQuote) -> Result<Self, ErrType> {
+        let bytes = bincode::serialize(&quote).map_err(|e| {
+            ErrType::ParseError(format!("Не удалось сериализовать котировку {}. {e}", quote.ticker))
+        })?;
+        Ok(Self { quote, bytes })
+    }
+
+    pub fn quote(&self) -> &StockQuote {
+        &self.quote
+    }
+
+    /// Котировка в bincode. Список котировок в bincode это длина списка и склеенные котировки
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Deref for EncodedQuote {
+    type Target = StockQuote;
+
+    fn deref(&self) -> &Self::Target {
+        &self.quote
+    }
+}
@@ -0,0 +1,201 @@
+use common_lib::errors::ErrType;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Котировки, которые разрешено получать одному клиенту. Шаблон это название котировки,
+/// `*` для всех котировок или префикс со звездочкой в конце, например `SBER*`
+#[derive(Debug, Default)]
+pub struct Entitlement {
+    patterns: Vec<String>,
+}
+
+/// Права клиентов на котировки. Каждая строка файла это имя клиента из файла токенов и шаблоны через запятую,
+/// например `alice AAPL,MSFT,SBER*`. Пустые строки и строки с `#` пропускаются. Клиенту, которого нет в файле,
+/// котировки не разрешены
+#[derive(Debug, Default)]
+pub struct Entitlements {
+    by_user: HashMap<String, Arc<Entitlement>>,
+    /// Права клиентов, которых нет в файле
+    nothing: Arc<Entitlement>,
+}
+
+impl Entitlement {
+    ///
+    /// Разрешено ли получать котировку
+    ///
+    pub fn allows(&self, ticker: &str) -> bool {
+        self.patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => ticker.starts_with(prefix),
+            None => pattern == ticker,
+        })
+    }
+
+    ///
+    /// Котировки, которые клиенту получать нельзя
+    ///
+    /// # Arguments
+    ///
+    /// * `tickers`: запрошенные котировки
+    ///
+    /// returns: Vec<String> - пустой, если разрешены все котировки
+    ///
+    pub fn denied(&self, tickers: &[String]) -> Vec<String> {
+        tickers
+            .iter()
+            .filter(|ticker| !self.allows(ticker))
+            .cloned()
+            .collect()
+    }
+}
+
+impl FromStr for Entitlement {
+    type Err = ErrType;
+
+    /// Шаблоны через запятую, например `AAPL,MSFT,SBER*`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let patterns = s
+            .split(',')
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect::<Vec<String>>();
+        if let Some(pattern) = patterns.iter().find(|p| p.strip_suffix('*').unwrap_or(p).contains('*')) {
+            return Err(ErrType::ParseError(format!(
+                "Звездочка в шаблоне {pattern} может быть только в конце"
+            )));
+        }
+        Ok(Self { patterns })
+    }
+}
+
+impl Entitlements {
+    ///
+    /// Прочитать файл с правами клиентов
+    ///
+    /// # Arguments
+    ///
+    /// * `filename`: путь до файла
+    ///
+    /// returns: Result<Entitlements, ErrType>
+    ///
+    pub fn load(filename: &Path) -> Result<Self, ErrType> {
+        let name = filename.display();
+        let file = File::open(filename)
+            .map_err(|e| ErrType::ReadError(format!("Ошибка при открытии файла {name}. {e}")))?;
+        let mut by_user = HashMap::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line
+                .map_err(|e| ErrType::ReadError(format!("Ошибка при чтении файла {name}. {e}")))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let (Some(user), Some(patterns), None) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(ErrType::ParseError(format!(
+                    "Строка {} файла {name} должна содержать имя и шаблоны котировок через запятую",
+                    number + 1
+                )));
+            };
+            let entitlement = patterns
+                .parse::<Entitlement>()
+                .map_err(|e| ErrType::ParseError(format!("Строка {} файла {name}. {e}", number + 1)))?;
+            by_user.insert(user.to_string(), Arc::new(entitlement));
+        }
+        Ok(Self {
+            by_user,
+            nothing: Arc::new(Entitlement::default()),
+        })
+    }
+
+    ///
+    /// Права клиента
+    ///
+    /// # Arguments
+    ///
+    /// * `user`: имя клиента после авторизации. None для клиентов без авторизации
+    ///
+    /// returns: Arc<Entitlement> - без разрешенных котировок, если клиента нет в файле
+    ///
+    pub fn of(&self, user: Option<&str>) -> Arc<Entitlement> {
+        user.and_then(|user| self.by_user.get(user))
+            .unwrap_or(&self.nothing)
+            .clone()
+    }
+
+    /// Количество клиентов в файле
+    pub fn len(&self) -> usize {
+        self.by_user.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process};
+
+    fn tickers(list: &[&str]) -> Vec<String> {
+        list.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn exact_and_prefix() {
+        let entitlement = "AAPL,SBER*".parse::<Entitlement>().unwrap();
+        assert!(entitlement.allows("AAPL"));
+        assert!(!entitlement.allows("AAPLX"));
+        assert!(!entitlement.allows("AAP"));
+        assert!(entitlement.allows("SBER"));
+        assert!(entitlement.allows("SBERP"));
+        assert!(!entitlement.allows("SBE"));
+        assert_eq!(entitlement.denied(&tickers(&["AAPL", "MSFT", "SBERP", "TSLA"])), ["MSFT", "TSLA"]);
+    }
+
+    #[test]
+    fn everything_and_nothing() {
+        let everything = "*".parse::<Entitlement>().unwrap();
+        assert!(everything.allows("AAPL"));
+        assert!(everything.allows(""));
+        let nothing = Entitlement::default();
+        assert!(!nothing.allows("AAPL"));
+        assert_eq!(nothing.denied(&tickers(&["AAPL"])), ["AAPL"]);
+    }
+
+    #[test]
+    fn non_ascii() {
+        let entitlement = "ГАЗП*,Ё".parse::<Entitlement>().unwrap();
+        assert!(entitlement.allows("ГАЗПРОМ"));
+        assert!(entitlement.allows("Ё"));
+        assert!(!entitlement.allows("Г"));
+        assert!(!entitlement.allows("ЁЁ"));
+        assert!(!entitlement.allows("Ж"));
+        assert!("ГАЗ*П".parse::<Entitlement>().is_err());
+        assert!("A*B,C".parse::<Entitlement>().is_err());
+        assert!("**".parse::<Entitlement>().is_err());
+    }
+
+    #[test]
+    fn load_file() {
+        let path = std::env::temp_dir().join(format!("entitlements-{}.txt", process::id()));
+        fs::write(&path, "# права\n\nalice AAPL,ГАЗП*\nbob *\n").unwrap();
+        let entitlements = Entitlements::load(&path);
+        fs::write(&path, "alice AAPL\nbob MS*FT\n").unwrap();
+        let broken = Entitlements::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        let entitlements = entitlements.unwrap();
+        assert_eq!(entitlements.len(), 2);
+        assert!(entitlements.of(Some("alice")).allows("ГАЗПРОМ"));
+        assert!(!entitlements.of(Some("alice")).allows("MSFT"));
+        assert!(entitlements.of(Some("bob")).allows("MSFT"));
+        assert!(!entitlements.of(Some("carol")).allows("AAPL"));
+        assert!(!entitlements.of(None).allows("AAPL"));
+
+        let Err(ErrType::ParseError(error)) = broken else {
+            panic!("шаблон со звездочкой в середине должен быть ошибкой");
+        };
+        assert!(error.contains("Строка 2"), "{error}");
+    }
+}
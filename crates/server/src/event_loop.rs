@@ -0,0 +1,244 @@
+use crate::stock_quotes_handler::QuoteHandler;
+use crate::udp_server_writer::ServerWriter;
+use common_lib::errors::ErrType;
+use common_lib::errors::ErrType::{ConnectionError, NoAccess};
+use common_lib::{PING_SEND_PERIOD, UDP_SERVER_RECEIVE_PERIOD};
+use crossbeam_channel::{Receiver, Sender, unbounded};
+use mio::{Events, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
+use std::{thread, thread::JoinHandle};
+
+/// Токен, которым будят цикл событий. Соединения получают токены начиная с 1
+const WAKER_TOKEN: Token = Token(0);
+/// Сколько событий забирается из ОС за один раз
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Несколько потоков с циклами событий, которые обслуживают все соединения клиентов. Поток не ждет
+/// своего клиента, а обрабатывает события всех своих сокетов, поэтому тысячам клиентов хватает
+/// нескольких потоков
+pub struct EventLoops {
+    stop: Arc<AtomicBool>,
+    loops: Vec<EventLoop>,
+}
+
+/// Поток с циклом событий
+struct EventLoop {
+    /// Новые соединения для цикла
+    sender: Sender<ServerWriter>,
+    waker: Arc<Waker>,
+    /// Сколько соединений обслуживает цикл
+    connections: Arc<AtomicUsize>,
+    join_handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl EventLoops {
+    ///
+    /// Запустить потоки с циклами событий
+    ///
+    /// # Arguments
+    ///
+    /// * `count`: количество потоков
+    /// * `stocks`: хранитель котировок. Будит циклы после рассылки и удаляет каналы закрытых соединений
+    ///
+    /// returns: Result<EventLoops, ErrType>
+    ///
+    pub fn start(count: usize, stocks: Arc<QuoteHandler>) -> Result<Self, ErrType> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut loops = Vec::with_capacity(count);
+        for _ in 0..count {
+            let poll = Poll::new().map_err(|e| {
+                ConnectionError(format!("Не удалось создать цикл событий. {e}"))
+            })?;
+            let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN).map_err(|e| {
+                ConnectionError(format!("Не удалось создать цикл событий. {e}"))
+            })?);
+            stocks.add_waker(waker.clone());
+
+            let (sender, receiver) = unbounded::<ServerWriter>();
+            let connections = Arc::new(AtomicUsize::new(0));
+            let stop = stop.clone();
+            let stocks = stocks.clone();
+            let connections_clone = connections.clone();
+            loops.push(EventLoop {
+                sender,
+                waker,
+                connections,
+                join_handle: Mutex::new(Some(thread::spawn(move || {
+                    Self::run(poll, receiver, stop, stocks, connections_clone)
+                }))),
+            });
+        }
+        log::info!("Запущено циклов событий: {count}");
+        Ok(Self { stop, loops })
+    }
+
+    ///
+    /// Передать соединение циклу событий, у которого меньше всего соединений
+    ///
+    /// # Arguments
+    ///
+    /// * `writer`: соединение с клиентом
+    ///
+    /// returns: Result<(), ErrType>
+    ///
+    pub fn add(&self, writer: ServerWriter) -> Result<(), ErrType> {
+        let Some(event_loop) = self
+            .loops
+            .iter()
+            .min_by_key(|l| l.connections.load(Ordering::Relaxed))
+        else {
+            return Err(NoAccess("Нет запущенных циклов событий".to_string()));
+        };
+        event_loop.connections.fetch_add(1, Ordering::Relaxed);
+        if event_loop.sender.send(writer).is_err() {
+            event_loop.connections.fetch_sub(1, Ordering::Relaxed);
+            return Err(NoAccess("Цикл событий уже остановлен".to_string()));
+        }
+        event_loop
+            .waker
+            .wake()
+            .map_err(|e| ConnectionError(format!("Не удалось разбудить цикл событий. {e}")))
+    }
+
+    ///
+    /// Остановить циклы событий. Клиентам отправляется BYE
+    ///
+    pub fn stop(&self) -> Result<(), ErrType> {
+        self.stop.store(true, Ordering::Release);
+        for event_loop in &self.loops {
+            if let Err(e) = event_loop.waker.wake() {
+                log::warn!("Не удалось разбудить цикл событий. {e}");
+            }
+            let join_handle = match event_loop.join_handle.lock() {
+                Ok(mut join_handle) => join_handle.take(),
+                Err(_) => None,
+            };
+            if let Some(h) = join_handle
+                && h.join().is_err()
+            {
+                log::error!("Ошибка остановки цикла событий");
+                return Err(NoAccess(
+                    "Не удалось завершить работу потока цикла событий".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Цикл событий одного потока. Котировки из каналов соединений забираются, когда хранитель котировок
+    /// будит цикл после рассылки. Проверка ping и отложенные ограничениями частоты котировки обрабатываются
+    /// по таймеру
+    ///
+    /// # Arguments
+    ///
+    /// * `poll`: ожидание событий сокетов
+    /// * `receiver`: канал новых соединений
+    /// * `stop`: флаг остановки
+    /// * `stocks`: хранитель котировок
+    /// * `connections`: счетчик соединений цикла
+    ///
+    fn run(
+        mut poll: Poll,
+        receiver: Receiver<ServerWriter>,
+        stop: Arc<AtomicBool>,
+        stocks: Arc<QuoteHandler>,
+        connections: Arc<AtomicUsize>,
+    ) {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        let mut writers: HashMap<Token, ServerWriter> = HashMap::new();
+        let mut next_token = WAKER_TOKEN.0 + 1;
+        let mut next_tick = Instant::now();
+
+        loop {
+            let timeout = next_tick.saturating_duration_since(Instant::now());
+            if let Err(e) = poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                log::error!("Ошибка ожидания событий. {e}");
+                break;
+            }
+
+            if stop.load(Ordering::Acquire) {
+                for writer in writers.values_mut() {
+                    writer.close();
+                    stocks.remove_channel(writer.session);
+                }
+                break;
+            }
+
+            // Новые соединения сразу получают снимок, который уже лежит в их канале
+            let mut woken = false;
+            for mut writer in receiver.try_iter() {
+                let token = Token(next_token);
+                next_token += 1;
+                if let Err(e) = writer.register(poll.registry(), token) {
+                    log::error!(
+                        "Не удалось добавить соединение с {} в цикл событий. {e}",
+                        writer.remote_address
+                    );
+                    stocks.remove_channel(writer.session);
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                    continue;
+                }
+                writers.insert(token, writer);
+                woken = true;
+            }
+
+            let mut closed: Vec<Token> = Vec::new();
+            for event in events.iter() {
+                if event.token() == WAKER_TOKEN {
+                    woken = true;
+                    continue;
+                }
+                let Some(writer) = writers.get_mut(&event.token()) else {
+                    continue;
+                };
+                let alive = (!event.is_writable() || writer.on_writable())
+                    && (!event.is_readable() || writer.on_readable());
+                if !alive {
+                    closed.push(event.token());
+                }
+            }
+
+            if woken || Instant::now() >= next_tick {
+                let mut pending = false;
+                for (token, writer) in writers.iter_mut() {
+                    if closed.contains(token) {
+                        continue;
+                    }
+                    if !writer.on_tick() {
+                        closed.push(*token);
+                    } else if writer.has_pending() {
+                        pending = true;
+                    }
+                }
+                // Отложенные котировки проверяются часто, а ping достаточно проверять раз в период отправки
+                next_tick = Instant::now()
+                    + if pending {
+                        UDP_SERVER_RECEIVE_PERIOD
+                    } else {
+                        PING_SEND_PERIOD
+                    };
+            }
+
+            for token in closed {
+                let Some(writer) = writers.remove(&token) else {
+                    continue;
+                };
+                log::info!(
+                    "Соединение с {} закрыто. Выкинуто пакетов из очереди: {}",
+                    writer.remote_address,
+                    writer.stats.dropped()
+                );
+                stocks.remove_channel(writer.session);
+                connections.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
@@ -1,47 +1,257 @@
+mod admin_server;
+mod connection_limits;
+mod credentials;
+mod encoded_quote;
+mod entitlements;
+mod event_loop;
+mod metrics;
+mod metrics_server;
+mod multicast_publisher;
+mod quote_filter;
+mod rate_limiter;
+mod session_stats;
 mod stock_quotes_handler;
+mod subscription;
 mod tcp_server;
+mod transport;
 mod udp_server_writer;
+mod ws_gateway;
 
-use crate::stock_quotes_handler::QuoteHandler;
-use crate::udp_server_writer::ServerWriter;
-use common_lib::TCP_CONNECTION_WAIT_PERIOD;
+use crate::admin_server::AdminServer;
+use crate::connection_limits::{ConnectionLimits, HandshakePermit, LimitOptions};
+use crate::credentials::Credentials;
+use crate::entitlements::Entitlements;
+use crate::event_loop::EventLoops;
+use crate::metrics::HandshakeRejection;
+use crate::metrics_server::MetricsServer;
+use crate::multicast_publisher::{MulticastGroup, MulticastPublisher};
+use crate::stock_quotes_handler::{GeneratorOptions, QuoteHandler};
+use crate::tcp_server::HandshakeOptions;
+use crate::transport::PortRange;
+use crate::ws_gateway::{WsAccess, WsGateway};
+use clap::Parser;
+use common_lib::{
+    HANDSHAKE_WAIT_PERIOD, MAX_HANDSHAKES_PER_MINUTE, MAX_PENDING_HANDSHAKES, MAX_SESSIONS, MAX_SESSIONS_PER_IP,
+    TCP_CONNECTION_WAIT_PERIOD,
+};
+use common_lib::datagram_auth::SessionKey;
 use common_lib::errors::ErrType;
+use log::LevelFilter;
 use std::collections::HashSet;
 use std::fs::File;
-use std::io::{BufRead, BufReader, ErrorKind};
-use std::net::TcpListener;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::sync::atomic::Ordering;
-use std::{env, io, thread};
+use std::time::Duration;
+use std::{io, thread};
 
-fn read_tickers(filename: &str) -> Result<HashSet<String>, ErrType> {
+#[derive(Debug, Parser)]
+#[command(
+    name = "quote_server",
+    version,
+    about = "Сервер котировок"
+)]
+struct Cli {
+    /// Адрес на котором сервер принимает tcp соединения. По умолчанию только localhost,
+    /// `::` включает все адреса ipv6 и ipv4
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    bind: IpAddr,
+
+    /// Порт для tcp соединений
+    #[arg(long, default_value_t = 1111)]
+    port: u16,
+
+    /// Файл со списком котировок
+    #[arg(long, default_value = "tickers.txt")]
+    tickers_file: PathBuf,
+
+    /// Файл с multicast группами
+    #[arg(long)]
+    multicast_groups: Option<PathBuf>,
+
+    /// Адрес websocket шлюза
+    #[arg(long, default_value = "127.0.0.1:1113")]
+    ws_address: SocketAddr,
+
+    /// Адрес порта управления. Команды принимаются без проверки прав, поэтому по умолчанию только localhost
+    #[arg(long, default_value = "127.0.0.1:1114")]
+    admin_address: SocketAddr,
+
+    /// Адрес http сервера, который отдает метрики в формате Prometheus по пути /metrics
+    #[arg(long, default_value = "127.0.0.1:1115")]
+    metrics_address: SocketAddr,
+
+    /// Как часто обновляются котировки в миллисекундах
+    #[arg(long, default_value_t = 2000, value_parser = clap::value_parser!(u64).range(1..))]
+    generator_period_ms: u64,
+
+    /// На сколько процентов может измениться цена за одно обновление
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..100))]
+    volatility: u32,
+
+    /// Уровень логирования. Если не задан, то берется из RUST_LOG
+    #[arg(long)]
+    log_level: Option<LevelFilter>,
+
+    /// Порт или диапазон портов, например `40000-40100`, с которых сервер отправляет котировки по udp.
+    /// Если не задан, то порт выдает ОС
+    #[arg(long)]
+    udp_ports: Option<PortRange>,
+
+    /// Количество потоков с циклами событий, которые обслуживают соединения клиентов.
+    /// По умолчанию по числу ядер
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    event_loops: Option<u16>,
+
+    /// За сколько миллисекунд клиент должен прислать запрос STREAM после подключения
+    #[arg(long, default_value_t = HANDSHAKE_WAIT_PERIOD.as_millis() as u64, value_parser = clap::value_parser!(u64).range(1..))]
+    handshake_timeout_ms: u64,
+
+    /// Сколько подключений одновременно может ждать запроса STREAM. Остальные сразу получают ошибку
+    #[arg(long, default_value_t = MAX_PENDING_HANDSHAKES)]
+    max_pending_handshakes: usize,
+
+    /// Сколько подключений с одного адреса принимается за минуту. Остальные сразу получают ошибку
+    #[arg(long, default_value_t = MAX_HANDSHAKES_PER_MINUTE, value_parser = clap::value_parser!(u32).range(1..))]
+    max_handshakes_per_minute: u32,
+
+    /// Сколько всего сессий может обслуживать сервер
+    #[arg(long, default_value_t = MAX_SESSIONS)]
+    max_sessions: usize,
+
+    /// Сколько сессий может открыть один адрес
+    #[arg(long, default_value_t = MAX_SESSIONS_PER_IP)]
+    max_sessions_per_ip: usize,
+
+    /// Файл с sha256 хешами токенов клиентов. Если задан, то перед STREAM клиент должен прислать AUTH <токен>
+    #[arg(long)]
+    credentials_file: Option<PathBuf>,
+
+    /// Файл с правами клиентов на котировки. Клиент различается по имени из credentials-file
+    #[arg(long, requires = "credentials_file")]
+    entitlements_file: Option<PathBuf>,
+
+    /// Папка с unix сокетами клиентов. Если не задана, то запросы unix:// отклоняются.
+    /// Сокеты принимаются только от клиентов с этой же машины
+    #[arg(long)]
+    unix_socket_dir: Option<PathBuf>,
+}
+
+fn read_tickers(filename: &Path) -> Result<HashSet<String>, ErrType> {
+    let name = filename.display();
     let mut tickers = HashSet::new();
     let file = File::open(filename)
-        .map_err(|e| ErrType::ReadError(format!("Ошибка при открытии файла {filename}. {e}")))?;
+        .map_err(|e| ErrType::ReadError(format!("Ошибка при открытии файла {name}. {e}")))?;
     let reader = BufReader::new(file);
     for line in reader.lines() {
         let line = line
-            .map_err(|e| ErrType::ReadError(format!("Ошибка при чтении файла {filename}. {e}")))?;
+            .map_err(|e| ErrType::ReadError(format!("Ошибка при чтении файла {name}. {e}")))?;
         tickers.insert(line);
     }
     Ok(tickers)
 }
 
-fn main() -> io::Result<()> {
-    env_logger::init();
-
-    let listener = TcpListener::bind("127.0.0.1:1111")?;
-    listener.set_nonblocking(true)?;
-    log::info!("TCP сервер начал работу и слушает порт 1111");
+fn read_multicast_groups(filename: &Path) -> Result<Vec<MulticastGroup>, ErrType> {
+    let name = filename.display();
+    let mut groups = Vec::new();
+    let file = File::open(filename)
+        .map_err(|e| ErrType::ReadError(format!("Ошибка при открытии файла {name}. {e}")))?;
+    let reader = BufReader::new(file);
+    for line in reader.lines() {
+        let line = line
+            .map_err(|e| ErrType::ReadError(format!("Ошибка при чтении файла {name}. {e}")))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        groups.push(line.parse::<MulticastGroup>()?);
+    }
+    Ok(groups)
+}
 
-    let args: Vec<String> = env::args().collect();
+///
+/// Провести рукопожатие в отдельном потоке и передать соединение циклу событий
+///
+/// # Arguments
+///
+/// * `stream`: новое tcp соединение
+/// * `stocks`: хранитель котировок
+/// * `options`: настройки рукопожатия
+/// * `loops`: циклы событий, которые обслуживают соединения
+/// * `permit`: разрешение на рукопожатие, освобождается когда рукопожатие закончилось
+///
+fn spawn_handshake(
+    stream: TcpStream,
+    stocks: Arc<QuoteHandler>,
+    options: Arc<HandshakeOptions>,
+    loops: Arc<EventLoops>,
+    permit: HandshakePermit,
+) {
+    thread::spawn(move || {
+        match tcp_server::handle_client(stream, &stocks, &options, &permit) {
+            Ok(Some(sender)) => {
+                // Дальше соединение обслуживает цикл событий
+                let (session, address) = (sender.session, sender.remote_address.clone());
+                if let Err(e) = loops.add(sender) {
+                    log::error!("Не удалось передать соединение с {address} циклу событий. {e}");
+                    stocks.remove_channel(session);
+                }
+            }
+            Ok(None) => {
+                // Клиент получает котировки через multicast, отдельного соединения нет
+            }
+            Err(e) => {
+                log::error!("Не удалось установить соединение. Ошибка {e}");
+            }
+        }
+    });
+}
 
-    let filename = if args.len() < 2 {
-        "tickers.txt"
-    } else {
-        args[1].as_str()
+///
+/// Отказать в подключении, когда слишком много клиентов одновременно проходят рукопожатие
+/// или клиент подключается слишком часто
+///
+/// # Arguments
+///
+/// * `stream`: новое tcp соединение
+/// * `peer`: адрес клиента
+/// * `stocks`: хранитель котировок, в нем считаются отказы
+/// * `rejection`: причина отказа
+///
+fn reject(mut stream: TcpStream, peer: SocketAddr, stocks: &QuoteHandler, rejection: HandshakeRejection) {
+    stocks.metrics().handshake_rejected(rejection);
+    let message = match rejection {
+        HandshakeRejection::RateLimited => {
+            log::warn!("Клиент {peer} подключается слишком часто, отказано");
+            "ERROR: Слишком много подключений с вашего адреса, повторите подключение позже\n"
+        }
+        _ => {
+            log::warn!("Слишком много незавершенных подключений, отказано {peer}");
+            "ERROR: Сервер занят, повторите подключение позже\n"
+        }
     };
+    // Запись не должна задерживать прием соединений, поэтому без ожидания
+    let _ = stream.set_nonblocking(true);
+    let _ = stream.write_all(message.as_bytes());
+}
+
+fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = cli.log_level {
+        logger.filter_level(level);
+    }
+    logger.init();
 
-    let tickers = match read_tickers(filename) {
+    let address = SocketAddr::new(cli.bind, cli.port);
+    let listener = tcp_server::bind(address)?;
+    listener.set_nonblocking(true)?;
+    log::info!("TCP сервер начал работу и слушает {address}");
+
+    let filename = cli.tickers_file.display();
+    let tickers = match read_tickers(&cli.tickers_file) {
         Ok(tickers) => tickers,
         Err(e) => {
             log::error!("Не удалось прочитать список котировок из файла {filename}",);
@@ -49,37 +259,148 @@ fn main() -> io::Result<()> {
         }
     };
 
+    // Необязательный файл с multicast группами
+    let groups = match &cli.multicast_groups {
+        Some(groups_filename) => match read_multicast_groups(groups_filename) {
+            Ok(groups) => groups,
+            Err(e) => {
+                log::error!(
+                    "Не удалось прочитать multicast группы из файла {}",
+                    groups_filename.display()
+                );
+                return Err(e.into());
+            }
+        },
+        None => Vec::new(),
+    };
+
+    // Необязательный файл с токенами клиентов
+    let credentials = match &cli.credentials_file {
+        Some(credentials_filename) => match Credentials::load(credentials_filename) {
+            Ok(credentials) => {
+                log::info!("Авторизация включена, загружено токенов: {}", credentials.len());
+                Some(Arc::new(credentials))
+            }
+            Err(e) => {
+                log::error!(
+                    "Не удалось прочитать токены клиентов из файла {}",
+                    credentials_filename.display()
+                );
+                return Err(e.into());
+            }
+        },
+        None => None,
+    };
+
+    // Необязательный файл с правами клиентов на котировки
+    let entitlements = match &cli.entitlements_file {
+        Some(entitlements_filename) => match Entitlements::load(entitlements_filename) {
+            Ok(entitlements) => {
+                log::info!("Права на котировки ограничены для клиентов: {}", entitlements.len());
+                Some(Arc::new(entitlements))
+            }
+            Err(e) => {
+                log::error!(
+                    "Не удалось прочитать права клиентов из файла {}",
+                    entitlements_filename.display()
+                );
+                return Err(e.into());
+            }
+        },
+        None => None,
+    };
+
+    // Путь приводится к каноническому один раз, с ним сравниваются канонические пути сокетов из запросов
+    let unix_socket_dir = match &cli.unix_socket_dir {
+        Some(dir) => match dir.canonicalize() {
+            Ok(dir) => {
+                log::info!("Unix сокеты клиентов принимаются в папке {}", dir.display());
+                Some(dir)
+            }
+            Err(e) => {
+                log::error!("Не удалось открыть папку unix сокетов {}. {e}", dir.display());
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+
     let stoper = common_lib::ctrlc::ctrlc_handler()?;
 
-    let mut stocks = QuoteHandler::new(&tickers);
+    let generator = GeneratorOptions {
+        period: Duration::from_millis(cli.generator_period_ms),
+        volatility: cli.volatility,
+    };
+    let stocks = Arc::new(QuoteHandler::new(&tickers, generator));
+
+    // Все группы подписываются одним ключом, его клиенты получают в ответе на запрос multicast
+    let multicast_key = SessionKey::new(rand::random())?;
+    let mut publishers: Vec<MulticastPublisher> = Vec::new();
+    for group in &groups {
+        publishers.push(MulticastPublisher::start(group.clone(), &stocks, multicast_key.clone())?);
+    }
 
-    let mut senders: Vec<ServerWriter> = Vec::new();
+    let limits = Arc::new(ConnectionLimits::new(LimitOptions {
+        max_pending_handshakes: cli.max_pending_handshakes,
+        max_handshakes_per_minute: cli.max_handshakes_per_minute,
+        max_sessions: cli.max_sessions,
+        max_sessions_per_ip: cli.max_sessions_per_ip,
+    }));
+
+    // Websocket шлюз для браузеров работает с теми же каналами котировок, токенами и правами клиентов
+    let access = WsAccess {
+        credentials: credentials.clone(),
+        entitlements: entitlements.clone(),
+    };
+    let mut gateway = WsGateway::start(cli.ws_address, stocks.clone(), access, limits.clone())?;
+
+    // Порт управления для просмотра сессий и изменения списка котировок на ходу
+    let mut admin = AdminServer::start(cli.admin_address, stocks.clone())?;
+    let mut metrics = MetricsServer::start(cli.metrics_address, stocks.clone())?;
+
+    let event_loops = match cli.event_loops {
+        Some(count) => count as usize,
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let loops = Arc::new(EventLoops::start(event_loops, stocks.clone())?);
 
-    // Ловим новые tcp соединения и каждое соединение обрабатываем в методе handle_client
+    let handshake = Arc::new(HandshakeOptions {
+        groups,
+        multicast_key,
+        udp_ports: cli.udp_ports,
+        timeout: Duration::from_millis(cli.handshake_timeout_ms),
+        credentials,
+        entitlements,
+        unix_socket_dir,
+    });
+
+    // Ловим новые tcp соединения. Рукопожатие каждого соединения идет в своем потоке, поэтому
+    // молчащий клиент не мешает принимать остальных
     for stream in listener.incoming() {
         if stoper.load(Ordering::Acquire) {
             log::info!("Остановка работы tcp сервера");
             break;
         }
-        senders
-            .iter()
-            .filter(|s| s.stop.load(Ordering::Acquire))
-            .for_each(|s| stocks.remove_channel(&s.remote_address));
-        senders.retain(|s| !s.stop.load(Ordering::Acquire));
-
         match stream {
             Ok(stream) => {
-                // Поскольку обработка соединение не долгая все делается в одном потоке
-                match tcp_server::handle_client(stream, &mut stocks) {
-                    Ok(sender) => {
-                        // Сохраняем соединение, что бы при остановке сервера корректно их закрыть
-                        senders.push(sender);
-                    }
-                    Err(e) => {
-                        log::error!("Не удалось установить соединение. Ошибка {e}");
+                let Ok(peer) = stream.peer_addr() else {
+                    // Клиент уже отключился
+                    continue;
+                };
+                let permit = match limits.begin_handshake(peer.ip()) {
+                    Ok(permit) => permit,
+                    Err(rejection) => {
+                        reject(stream, peer, &stocks, rejection);
                         continue;
                     }
-                }
+                };
+                spawn_handshake(
+                    stream,
+                    stocks.clone(),
+                    handshake.clone(),
+                    loops.clone(),
+                    permit,
+                );
             }
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
                 // Если нет соединение тогда спать. Нужно, что бы отлавливать ctrlc команды.
@@ -89,12 +410,21 @@ fn main() -> io::Result<()> {
         }
     }
 
-    for sender in &mut senders {
-        match sender.stop() {
-            Ok(_) => (),
-            Err(e) => {
-                log::error!("{e}");
-            }
+    if let Err(e) = loops.stop() {
+        log::error!("{e}");
+    }
+    if let Err(e) = metrics.stop() {
+        log::error!("{e}");
+    }
+    if let Err(e) = admin.stop() {
+        log::error!("{e}");
+    }
+    if let Err(e) = gateway.stop() {
+        log::error!("{e}");
+    }
+    for publisher in &mut publishers {
+        if let Err(e) = publisher.stop() {
+            log::error!("{e}");
         }
     }
     stocks.stop()?;
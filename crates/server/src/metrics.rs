@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Границы корзин гистограммы длительности обновления котировок в секундах
+const TICK_BUCKETS: [f64; 9] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25];
+
+/// Почему клиенту отказано в подписке при рукопожатии
+#[derive(Debug, Clone, Copy)]
+pub enum HandshakeRejection {
+    /// Слишком много незавершенных подключений
+    Busy,
+    /// Адрес подключается чаще разрешенного
+    RateLimited,
+    /// Превышено количество сессий сервера или адреса
+    SessionLimit,
+    /// Клиент не прислал токен или токен неверный
+    Unauthorized,
+    /// Клиент не прислал запрос вовремя
+    Timeout,
+    /// Строка запроса слишком длинная или не в utf8
+    InvalidLine,
+    /// Неверный запрос STREAM или неизвестная команда
+    BadRequest,
+    /// Клиент закрыл соединение или прислал пустой запрос
+    Empty,
+    /// Не удалось создать соединение для котировок
+    Transport,
+    /// Ошибка сервера или соединения
+    Internal,
+}
+
+impl HandshakeRejection {
+    const ALL: [HandshakeRejection; 10] = [
+        HandshakeRejection::Busy,
+        HandshakeRejection::RateLimited,
+        HandshakeRejection::SessionLimit,
+        HandshakeRejection::Unauthorized,
+        HandshakeRejection::Timeout,
+        HandshakeRejection::InvalidLine,
+        HandshakeRejection::BadRequest,
+        HandshakeRejection::Empty,
+        HandshakeRejection::Transport,
+        HandshakeRejection::Internal,
+    ];
+
+    /// Значение метки reason
+    fn label(self) -> &'static str {
+        match self {
+            HandshakeRejection::Busy => "busy",
+            HandshakeRejection::RateLimited => "rate_limited",
+            HandshakeRejection::SessionLimit => "session_limit",
+            HandshakeRejection::Unauthorized => "unauthorized",
+            HandshakeRejection::Timeout => "timeout",
+            HandshakeRejection::InvalidLine => "invalid_line",
+            HandshakeRejection::BadRequest => "bad_request",
+            HandshakeRejection::Empty => "empty",
+            HandshakeRejection::Transport => "transport",
+            HandshakeRejection::Internal => "internal",
+        }
+    }
+}
+
+/// Счетчики работы сервера для мониторинга. Отдаются в формате Prometheus на адресе metrics-address
+#[derive(Debug, Default)]
+pub struct Metrics {
+    handshakes_accepted: AtomicU64,
+    handshakes_rejected: [AtomicU64; HandshakeRejection::ALL.len()],
+    /// Отправленные сообщения с котировками во всех udp, tcp и unix соединениях и multicast группах
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    /// Пакеты, выкинутые из переполненных очередей подписчиков
+    dropped_batches: AtomicU64,
+    /// Пакеты, которые не удалось положить в очередь подписчика
+    channel_send_failures: AtomicU64,
+    ping_timeouts: AtomicU64,
+    /// Датаграммы клиентов с неверной подписью или с чужого адреса
+    rejected_datagrams: AtomicU64,
+    tick_buckets: [AtomicU64; TICK_BUCKETS.len()],
+    tick_count: AtomicU64,
+    tick_sum_micros: AtomicU64,
+    ticker_updates: Mutex<HashMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn handshake_accepted(&self) {
+        self.handshakes_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_rejected(&self, reason: HandshakeRejection) {
+        self.handshakes_rejected[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///
+    /// Учесть отправленные сообщения с котировками
+    ///
+    /// # Arguments
+    ///
+    /// * `messages`: отправленные сообщения
+    ///
+    pub fn add_sent(&self, messages: &[Vec<u8>]) {
+        let bytes = messages.iter().map(|m| m.len() as u64).sum::<u64>();
+        self.messages_sent
+            .fetch_add(messages.len() as u64, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    ///
+    /// Учесть одно отправленное сообщение с котировками, например websocket сообщение
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes`: размер сообщения
+    ///
+    pub fn add_sent_message(&self, bytes: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_dropped_batch(&self) {
+        self.dropped_batches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped_batches(&self) -> u64 {
+        self.dropped_batches.load(Ordering::Relaxed)
+    }
+
+    pub fn channel_send_failed(&self) {
+        self.channel_send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ping_timeout(&self) {
+        self.ping_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn datagram_rejected(&self) {
+        self.rejected_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///
+    /// Учесть одно обновление котировок
+    ///
+    /// # Arguments
+    ///
+    /// * `duration`: сколько заняли обновление, кодирование и рассылка
+    /// * `tickers`: обновленные котировки
+    ///
+    pub fn observe_tick<'a>(&self, duration: Duration, tickers: impl Iterator<Item = &'a str>) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = TICK_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.tick_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.tick_count.fetch_add(1, Ordering::Relaxed);
+        self.tick_sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+
+        if let Ok(mut updates) = self.ticker_updates.lock() {
+            for ticker in tickers {
+                match updates.get_mut(ticker) {
+                    Some(count) => *count += 1,
+                    None => {
+                        updates.insert(ticker.to_string(), 1);
+                    }
+                }
+            }
+        }
+    }
+
+    ///
+    /// Все метрики в текстовом формате Prometheus
+    ///
+    /// # Arguments
+    ///
+    /// * `subscribers`: количество активных подписчиков
+    /// * `tickers`: количество котировок генератора
+    ///
+    /// returns: String
+    ///
+    pub fn render(&self, subscribers: usize, tickers: usize) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        Self::header(&mut out, "quotes_active_subscribers", "Количество активных подписчиков", "gauge");
+        let _ = writeln!(out, "quotes_active_subscribers {subscribers}");
+        Self::header(&mut out, "quotes_tickers", "Количество котировок генератора", "gauge");
+        let _ = writeln!(out, "quotes_tickers {tickers}");
+
+        Self::header(&mut out, "quotes_handshakes_accepted_total", "Принятые запросы STREAM", "counter");
+        let _ = writeln!(out, "quotes_handshakes_accepted_total {}", load(&self.handshakes_accepted));
+        Self::header(
+            &mut out,
+            "quotes_handshakes_rejected_total",
+            "Отклоненные запросы STREAM по причине",
+            "counter",
+        );
+        for reason in HandshakeRejection::ALL {
+            let _ = writeln!(
+                out,
+                "quotes_handshakes_rejected_total{{reason=\"{}\"}} {}",
+                reason.label(),
+                load(&self.handshakes_rejected[reason as usize])
+            );
+        }
+
+        Self::header(&mut out, "quotes_messages_sent_total", "Отправленные сообщения с котировками", "counter");
+        let _ = writeln!(out, "quotes_messages_sent_total {}", load(&self.messages_sent));
+        Self::header(&mut out, "quotes_sent_bytes_total", "Отправленные байты сообщений с котировками", "counter");
+        let _ = writeln!(out, "quotes_sent_bytes_total {}", load(&self.bytes_sent));
+        Self::header(
+            &mut out,
+            "quotes_dropped_batches_total",
+            "Пакеты, выкинутые из переполненных очередей подписчиков",
+            "counter",
+        );
+        let _ = writeln!(out, "quotes_dropped_batches_total {}", load(&self.dropped_batches));
+        Self::header(
+            &mut out,
+            "quotes_channel_send_failures_total",
+            "Пакеты, которые не удалось положить в очередь подписчика",
+            "counter",
+        );
+        let _ = writeln!(out, "quotes_channel_send_failures_total {}", load(&self.channel_send_failures));
+        Self::header(&mut out, "quotes_ping_timeouts_total", "Соединения, закрытые из-за отсутствия PING", "counter");
+        let _ = writeln!(out, "quotes_ping_timeouts_total {}", load(&self.ping_timeouts));
+        Self::header(
+            &mut out,
+            "quotes_rejected_datagrams_total",
+            "Датаграммы клиентов с неверной подписью, повторным номером или с чужого адреса",
+            "counter",
+        );
+        let _ = writeln!(out, "quotes_rejected_datagrams_total {}", load(&self.rejected_datagrams));
+
+        Self::header(
+            &mut out,
+            "quotes_generator_tick_seconds",
+            "Длительность обновления и рассылки котировок",
+            "histogram",
+        );
+        let mut cumulative = 0;
+        for (bound, bucket) in TICK_BUCKETS.iter().zip(&self.tick_buckets) {
+            cumulative += load(bucket);
+            let _ = writeln!(out, "quotes_generator_tick_seconds_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let count = load(&self.tick_count);
+        let _ = writeln!(out, "quotes_generator_tick_seconds_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(
+            out,
+            "quotes_generator_tick_seconds_sum {}",
+            load(&self.tick_sum_micros) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "quotes_generator_tick_seconds_count {count}");
+
+        Self::header(&mut out, "quotes_ticker_updates_total", "Обновления значений котировки", "counter");
+        if let Ok(updates) = self.ticker_updates.lock() {
+            let mut updates = updates.iter().collect::<Vec<(&String, &u64)>>();
+            updates.sort();
+            for (ticker, count) in updates {
+                // Котировки добавляются через порт управления, поэтому экранируем значение метки
+                let ticker = ticker.replace('\\', "\\\\").replace('"', "\\\"");
+                let _ = writeln!(out, "quotes_ticker_updates_total{{ticker=\"{ticker}\"}} {count}");
+            }
+        }
+        out
+    }
+
+    /// Строки HELP и TYPE метрики
+    fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+    }
+}
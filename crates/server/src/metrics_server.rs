@@ -0,0 +1,144 @@
+use crate::stock_quotes_handler::QuoteHandler;
+use crate::tcp_server;
+use common_lib::errors::ErrType;
+use common_lib::errors::ErrType::{ConnectionError, NoAccess};
+use common_lib::{MAX_HANDSHAKE_LINE_SIZE, TCP_CONNECTION_WAIT_PERIOD, TCP_WRITE_WAIT_PERIOD};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{thread, thread::JoinHandle};
+
+/// Путь, по которому отдаются метрики
+const METRICS_PATH: &str = "/metrics";
+/// Тип содержимого текстового формата Prometheus
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Http сервер, который отдает метрики сервера котировок в формате Prometheus по адресу `/metrics`.
+/// Запросы редкие и короткие, поэтому они обрабатываются по одному в потоке приема соединений
+pub struct MetricsServer {
+    stop: Arc<AtomicBool>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    ///
+    /// Запустить поток, который отвечает на запросы метрик
+    ///
+    /// # Arguments
+    ///
+    /// * `address`: адрес на котором слушать соединения
+    /// * `stocks`: хранитель котировок, в нем лежат счетчики
+    ///
+    /// returns: Result<MetricsServer, ErrType>
+    ///
+    pub fn start(address: SocketAddr, stocks: Arc<QuoteHandler>) -> Result<Self, ErrType> {
+        let listener = tcp_server::bind(address).map_err(|e| {
+            ConnectionError(format!("Не удалось запустить сервер метрик на {address}. {e}"))
+        })?;
+        listener.set_nonblocking(true).map_err(|e| {
+            ConnectionError(format!("Не удалось настроить сервер метрик на {address}. {e}"))
+        })?;
+        log::info!("Сервер метрик начал работу и слушает http://{address}{METRICS_PATH}");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = stop.clone();
+        Ok(Self {
+            stop,
+            join_handle: Some(thread::spawn(move || {
+                Self::accept(listener, stop_clone, stocks)
+            })),
+        })
+    }
+
+    ///
+    /// Остановить сервер метрик
+    ///
+    pub fn stop(&mut self) -> Result<(), ErrType> {
+        self.stop.store(true, Ordering::Release);
+        if let Some(h) = self.join_handle.take()
+            && h.join().is_err()
+        {
+            log::error!("Ошибка остановки сервера метрик");
+            return Err(NoAccess(
+                "Не удалось завершить работу потока сервера метрик".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Цикл приема соединений
+    fn accept(listener: TcpListener, stop: Arc<AtomicBool>, stocks: Arc<QuoteHandler>) {
+        for stream in listener.incoming() {
+            if stop.load(Ordering::Acquire) {
+                log::info!("Остановка работы сервера метрик");
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = Self::serve(stream, &stocks) {
+                        log::warn!("Запрос метрик завершился с ошибкой. {e}");
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(TCP_CONNECTION_WAIT_PERIOD);
+                }
+                Err(e) => log::error!("Не удалось принять соединение сервера метрик. {e}"),
+            }
+        }
+    }
+
+    ///
+    /// Ответить на один http запрос. Поддерживается только `GET /metrics`, соединение закрывается после ответа
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: tcp соединение
+    /// * `stocks`: хранитель котировок
+    ///
+    /// returns: Result<(), ErrType>
+    ///
+    fn serve(stream: TcpStream, stocks: &QuoteHandler) -> Result<(), ErrType> {
+        let error = |e: io::Error| ConnectionError(format!("Ошибка соединения сервера метрик. {e}"));
+        stream.set_nonblocking(false).map_err(error)?;
+        stream
+            .set_read_timeout(Some(TCP_WRITE_WAIT_PERIOD))
+            .map_err(error)?;
+        stream
+            .set_write_timeout(Some(TCP_WRITE_WAIT_PERIOD))
+            .map_err(error)?;
+        let mut writer = stream.try_clone().map_err(error)?;
+        let mut reader = BufReader::new(stream).take(MAX_HANDSHAKE_LINE_SIZE as u64);
+
+        // Нужна только первая строка запроса, заголовки дочитываются, что бы клиент не получил сброс соединения
+        let mut request = String::new();
+        reader.read_line(&mut request).map_err(error)?;
+        let mut header = String::new();
+        while reader.read_line(&mut header).map_err(error)? > 0 && !header.trim().is_empty() {
+            header.clear();
+        }
+
+        let mut parts = request.split_whitespace();
+        let (status, content_type, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some(METRICS_PATH)) => (
+                "200 OK",
+                METRICS_CONTENT_TYPE,
+                stocks
+                    .metrics()
+                    .render(stocks.subscriber_count(), stocks.tickers().len()),
+            ),
+            (Some("GET"), _) => ("404 Not Found", "text/plain; charset=utf-8", "Not Found\n".to_string()),
+            _ => (
+                "405 Method Not Allowed",
+                "text/plain; charset=utf-8",
+                "Method Not Allowed\n".to_string(),
+            ),
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        writer.write_all(response.as_bytes()).map_err(error)
+    }
+}
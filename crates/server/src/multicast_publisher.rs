@@ -0,0 +1,241 @@
+use crate::stock_quotes_handler::{QuoteHandler, QuotesReceiver};
+use crate::subscription::{SlowConsumerPolicy, SubscriptionOptions};
+use common_lib::bye::ByeReason;
+use common_lib::datagram_auth::{Direction, SessionKey};
+use common_lib::errors::ErrType;
+use common_lib::errors::ErrType::NoAccess;
+use crate::encoded_quote::EncodedQuote;
+use crate::metrics::Metrics;
+use crate::session_stats::SessionStats;
+use crate::udp_server_writer::encode_quotes;
+use common_lib::UDP_SERVER_RECEIVE_PERIOD;
+use common_lib::udp_batch::send_batch;
+use crossbeam_channel::RecvTimeoutError;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{thread, thread::JoinHandle};
+
+const TTL_OPTION: &str = "ttl";
+const INTERFACE_OPTION: &str = "iface";
+const DEFAULT_TTL: u32 = 1;
+
+/// Multicast группа и котировки, которые в нее рассылаются
+#[derive(Debug, Clone)]
+pub struct MulticastGroup {
+    pub address: SocketAddrV4,
+    pub tickers: Vec<String>,
+    /// Сколько маршрутизаторов может пройти пакет
+    pub ttl: u32,
+    /// Интерфейс через который идет рассылка
+    pub interface: Ipv4Addr,
+}
+
+impl FromStr for MulticastGroup {
+    type Err = ErrType;
+
+    /// Строка вида `239.1.1.1:5000 AAPL,MSFT ttl=1 iface=0.0.0.0`. ttl и iface необязательные
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let (Some(address), Some(tickers)) = (parts.next(), parts.next()) else {
+            return Err(ErrType::ParseError(format!(
+                "В строке {s} должны быть адрес группы и список котировок"
+            )));
+        };
+        let address = SocketAddrV4::from_str(address)
+            .map_err(|e| ErrType::ParseError(format!("Неверный адрес группы {address}. {e}")))?;
+        if !address.ip().is_multicast() {
+            return Err(ErrType::ParseError(format!(
+                "Адрес {address} не является multicast адресом"
+            )));
+        }
+
+        let mut group = Self {
+            address,
+            tickers: tickers.split(',').map(|x| x.to_string()).collect(),
+            ttl: DEFAULT_TTL,
+            interface: Ipv4Addr::UNSPECIFIED,
+        };
+        for part in parts {
+            match part.split_once('=') {
+                Some((TTL_OPTION, value)) => group.ttl = value.parse()?,
+                Some((INTERFACE_OPTION, value)) => {
+                    group.interface = Ipv4Addr::from_str(value).map_err(|e| {
+                        ErrType::ParseError(format!("Неверный адрес интерфейса {value}. {e}"))
+                    })?
+                }
+                _ => {
+                    return Err(ErrType::NotSupported(format!(
+                        "Неизвестный параметр {part} группы {address}"
+                    )));
+                }
+            }
+        }
+        Ok(group)
+    }
+}
+
+/// Поток, который рассылает котировки группы сразу всем клиентам через multicast
+pub struct MulticastPublisher {
+    stop: Arc<AtomicBool>,
+    group: MulticastGroup,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl MulticastPublisher {
+    ///
+    /// Создать сокет и запустить поток рассылки котировок группы
+    ///
+    /// # Arguments
+    ///
+    /// * `group`: настройки группы
+    /// * `stocks`: хранитель котировок, из него берется канал с новыми значениями
+    /// * `key`: ключ подписи датаграмм, общий для всех групп сервера
+    ///
+    /// returns: Result<MulticastPublisher, ErrType>
+    ///
+    pub fn start(group: MulticastGroup, stocks: &QuoteHandler, key: SessionKey) -> Result<Self, ErrType> {
+        let socket = Self::create_socket(&group)?;
+
+        // Рассылке нужны только последние значения, поэтому в очереди держим один снимок
+        let options = SubscriptionOptions {
+            queue_policy: SlowConsumerPolicy::Conflate,
+            ..SubscriptionOptions::default()
+        };
+        let Some(channel) = stocks.create_channel(&Self::channel_name(&group), &group.tickers, &options)
+        else {
+            return Err(NoAccess(format!(
+                "Не удалось создать канал для рассылки в группу {}",
+                group.address
+            )));
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = stop.clone();
+        let group_clone = group.clone();
+        let metrics = stocks.metrics().clone();
+        log::info!(
+            "Запущена рассылка котировок {} в группу {}",
+            group.tickers.join(","),
+            group.address
+        );
+
+        Ok(Self {
+            stop,
+            group,
+            join_handle: Some(thread::spawn(move || {
+                Self::publish(stop_clone, socket, group_clone, channel.receiver, channel.stats, metrics, key)
+            })),
+        })
+    }
+
+    /// Имя канала группы в хранителе котировок
+    pub fn channel_name(group: &MulticastGroup) -> String {
+        format!("multicast://{}", group.address)
+    }
+
+    ///
+    /// Остановить рассылку. Клиентам группы отправляется BYE
+    ///
+    pub fn stop(&mut self) -> Result<(), ErrType> {
+        self.stop.store(true, Ordering::Release);
+        if let Some(h) = self.join_handle.take()
+            && h.join().is_err()
+        {
+            log::error!("Ошибка остановки рассылки в группу {}", self.group.address);
+            return Err(NoAccess(format!(
+                "Не удалось завершить работу потока рассылки в группу {}",
+                self.group.address
+            )));
+        }
+        Ok(())
+    }
+
+    /// Настроить сокет для рассылки: интерфейс, ttl и получение своих пакетов на этой же машине
+    fn create_socket(group: &MulticastGroup) -> Result<UdpSocket, ErrType> {
+        let error = |e: std::io::Error| {
+            ErrType::ConnectionError(format!(
+                "Не удалось создать сокет для рассылки в группу {}. {e}",
+                group.address
+            ))
+        };
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).map_err(error)?;
+        socket
+            .set_multicast_if_v4(&group.interface)
+            .map_err(error)?;
+        socket.set_multicast_ttl_v4(group.ttl).map_err(error)?;
+        socket.set_multicast_loop_v4(true).map_err(error)?;
+        socket
+            .bind(&SocketAddr::from((group.interface, 0)).into())
+            .map_err(error)?;
+        Ok(socket.into())
+    }
+
+    ///
+    /// Метод для потока рассылки
+    ///
+    /// # Arguments
+    ///
+    /// * `stop`: флаг для завершения работы потока
+    /// * `socket`: сокет для рассылки
+    /// * `group`: настройки группы
+    /// * `receiver`: канал с новыми значениями котировок
+    /// * `stats`: счетчики рассылки для порта управления
+    /// * `metrics`: общие счетчики отправленных сообщений и байт
+    /// * `key`: ключ подписи датаграмм
+    ///
+    fn publish(
+        stop: Arc<AtomicBool>,
+        socket: UdpSocket,
+        group: MulticastGroup,
+        receiver: QuotesReceiver,
+        stats: Arc<SessionStats>,
+        metrics: Arc<Metrics>,
+        key: SessionKey,
+    ) {
+        loop {
+            if stop.load(Ordering::Acquire) {
+                log::info!("Остановка рассылки в группу {}", group.address);
+                let bye = key.sign(Direction::ToClient, &ByeReason::ServerShutdown.to_message());
+                let _ = socket.send_to(&bye, group.address);
+                break;
+            }
+
+            match receiver.recv_timeout(UDP_SERVER_RECEIVE_PERIOD) {
+                Ok(all_stocks) => {
+                    // В канал приходят только котировки группы
+                    let quotes = all_stocks
+                        .iter()
+                        .map(|x| x.as_ref())
+                        .collect::<Vec<&EncodedQuote>>();
+                    let messages = encode_quotes(&quotes)
+                        .iter()
+                        .map(|message| key.sign(Direction::ToClient, message))
+                        .collect::<Vec<Vec<u8>>>();
+                    match send_batch(&socket, &messages, SocketAddr::V4(group.address)) {
+                        Ok(_) => {
+                            stats.add_sent(messages.len() as u64);
+                            metrics.add_sent(&messages);
+                        }
+                        Err(e) => {
+                            log::warn!("Не удалось отправить котировки в группу {}. {e}", group.address)
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) if stats.is_kicked() => {
+                    log::warn!("Рассылка в группу {} остановлена администратором", group.address);
+                    let bye = key.sign(Direction::ToClient, &ByeReason::Kicked.to_message());
+                    let _ = socket.send_to(&bye, group.address);
+                    break;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    log::error!("Закрылся канал котировок группы {}", group.address);
+                    break;
+                }
+            }
+        }
+    }
+}
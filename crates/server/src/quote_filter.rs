@@ -0,0 +1,290 @@
+use common_lib::errors::ErrType;
+use common_lib::stock_quote::StockQuote;
+
+/// Наибольшая вложенность скобок в фильтре. Разбор рекурсивный, без ограничения
+/// глубоко вложенное выражение переполнит стек потока
+const MAX_FILTER_DEPTH: usize = 32;
+
+/// Поле котировки, по которому проверяется условие
+#[derive(Debug, Clone, Copy)]
+pub enum Field {
+    /// Цена в валюте, т.е. 12345 интерпретируется как 123.45
+    Price,
+    Volume,
+    /// Изменение цены в процентах по модулю относительно последнего отправленного значения
+    Move,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+///
+/// Условие на значения котировки. Разбирается из выражения вида
+///
+/// ```text
+/// expr  := and ('|' and)*
+/// and   := atom ('&' atom)*
+/// atom  := '(' expr ')' | 'cross(' number ')' | field op number
+/// field := price | volume | move
+/// op    := > | >= | < | <=
+/// ```
+///
+/// Например `move>0.5|volume>=3000` или `cross(150)&volume>100`. `move` - изменение цены в процентах с
+/// последнего отправленного клиенту значения, `cross(x)` - цена пересекла уровень x с последней отправки.
+/// Пока клиенту ничего не отправляли, и `move`, и `cross` считаются выполненными
+///
+#[derive(Debug, Clone)]
+pub enum QuoteFilter {
+    Compare(Field, Op, f64),
+    Cross(f64),
+    And(Box<QuoteFilter>, Box<QuoteFilter>),
+    Or(Box<QuoteFilter>, Box<QuoteFilter>),
+}
+
+impl QuoteFilter {
+    ///
+    /// Разобрать выражение фильтра
+    ///
+    /// # Arguments
+    ///
+    /// * `expr`: текст выражения без пробелов
+    ///
+    /// returns: Result<QuoteFilter, ErrType>
+    ///
+    pub fn parse(expr: &str) -> Result<Self, ErrType> {
+        let mut parser = Parser { input: expr, pos: 0, depth: 0 };
+        let filter = parser.parse_or()?;
+        if parser.pos != expr.len() {
+            return Err(parser.error("лишние символы"));
+        }
+        Ok(filter)
+    }
+
+    ///
+    /// Проверить подходит ли котировка под условие
+    ///
+    /// # Arguments
+    ///
+    /// * `quote`: новое значение котировки
+    /// * `last_sent`: последнее отправленное клиенту значение. None если еще ничего не отправляли
+    ///
+    /// returns: bool
+    ///
+    pub fn matches(&self, quote: &StockQuote, last_sent: Option<&StockQuote>) -> bool {
+        match self {
+            QuoteFilter::Compare(field, op, value) => {
+                let actual = match field {
+                    Field::Price => quote.price as f64 / 100.0,
+                    Field::Volume => quote.volume as f64,
+                    Field::Move => match last_sent {
+                        // Без предыдущего значения изменение неизвестно, поэтому котировку отправляем
+                        None => return true,
+                        Some(last) if last.price == 0 => return true,
+                        Some(last) => {
+                            (quote.price as f64 - last.price as f64).abs() * 100.0
+                                / last.price as f64
+                        }
+                    },
+                };
+                match op {
+                    Op::Greater => actual > *value,
+                    Op::GreaterOrEqual => actual >= *value,
+                    Op::Less => actual < *value,
+                    Op::LessOrEqual => actual <= *value,
+                }
+            }
+            QuoteFilter::Cross(level) => {
+                // Как и для move, без предыдущего значения котировку отправляем
+                let Some(last) = last_sent else {
+                    return true;
+                };
+                let before = last.price as f64 / 100.0;
+                let now = quote.price as f64 / 100.0;
+                (before < *level && now >= *level) || (before > *level && now <= *level)
+            }
+            QuoteFilter::And(left, right) => {
+                left.matches(quote, last_sent) && right.matches(quote, last_sent)
+            }
+            QuoteFilter::Or(left, right) => {
+                left.matches(quote, last_sent) || right.matches(quote, last_sent)
+            }
+        }
+    }
+}
+
+/// Разбор выражения рекурсивным спуском
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    /// Текущая вложенность скобок
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.input[self.pos..]
+    }
+
+    fn error(&self, text: &str) -> ErrType {
+        ErrType::ParseError(format!(
+            "Ошибка в фильтре {} на позиции {}: {text}",
+            self.input, self.pos
+        ))
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<QuoteFilter, ErrType> {
+        let mut left = self.parse_and()?;
+        while self.eat("|") {
+            let right = self.parse_and()?;
+            left = QuoteFilter::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<QuoteFilter, ErrType> {
+        let mut left = self.parse_atom()?;
+        while self.eat("&") {
+            let right = self.parse_atom()?;
+            left = QuoteFilter::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_atom(&mut self) -> Result<QuoteFilter, ErrType> {
+        if self.eat("(") {
+            if self.depth >= MAX_FILTER_DEPTH {
+                return Err(self.error(&format!("вложенность скобок больше {MAX_FILTER_DEPTH}")));
+            }
+            self.depth += 1;
+            let inner = self.parse_or()?;
+            self.depth -= 1;
+            if !self.eat(")") {
+                return Err(self.error("ожидалась )"));
+            }
+            return Ok(inner);
+        }
+        if self.eat("cross(") {
+            let level = self.parse_number()?;
+            if !self.eat(")") {
+                return Err(self.error("ожидалась )"));
+            }
+            return Ok(QuoteFilter::Cross(level));
+        }
+
+        let field = if self.eat("price") {
+            Field::Price
+        } else if self.eat("volume") {
+            Field::Volume
+        } else if self.eat("move") {
+            Field::Move
+        } else {
+            return Err(self.error("ожидалось price, volume, move или cross"));
+        };
+        // Сначала двухсимвольные операторы, что бы >= не разобрался как >
+        let op = if self.eat(">=") {
+            Op::GreaterOrEqual
+        } else if self.eat("<=") {
+            Op::LessOrEqual
+        } else if self.eat(">") {
+            Op::Greater
+        } else if self.eat("<") {
+            Op::Less
+        } else {
+            return Err(self.error("ожидался оператор сравнения"));
+        };
+        Ok(QuoteFilter::Compare(field, op, self.parse_number()?))
+    }
+
+    fn parse_number(&mut self) -> Result<f64, ErrType> {
+        let len = self
+            .rest()
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            return Err(self.error("ожидалось число"));
+        }
+        let number = self.rest()[..len].parse::<f64>()?;
+        self.pos += len;
+        Ok(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote(price: u32, volume: u32) -> StockQuote {
+        StockQuote {
+            ticker: "AAPL".to_string(),
+            price,
+            volume,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn price_and_volume() {
+        let filter = QuoteFilter::parse("price>=150&volume<3000").unwrap();
+        assert!(filter.matches(&quote(15000, 2999), None));
+        assert!(!filter.matches(&quote(14999, 100), None));
+        assert!(!filter.matches(&quote(15000, 3000), None));
+    }
+
+    #[test]
+    fn move_percent() {
+        let filter = QuoteFilter::parse("move>0.5").unwrap();
+        let last = quote(10000, 0);
+        assert!(filter.matches(&quote(10051, 0), Some(&last)));
+        assert!(filter.matches(&quote(9949, 0), Some(&last)));
+        assert!(!filter.matches(&quote(10050, 0), Some(&last)));
+        assert!(filter.matches(&quote(10000, 0), None));
+    }
+
+    #[test]
+    fn cross_level() {
+        let filter = QuoteFilter::parse("cross(150)").unwrap();
+        assert!(filter.matches(&quote(15000, 0), Some(&quote(14990, 0))));
+        assert!(filter.matches(&quote(14900, 0), Some(&quote(15100, 0))));
+        assert!(!filter.matches(&quote(15200, 0), Some(&quote(15100, 0))));
+        assert!(filter.matches(&quote(14000, 0), None));
+    }
+
+    #[test]
+    fn or_and_parentheses() {
+        let filter = QuoteFilter::parse("(price<100|volume>5000)&move>=1").unwrap();
+        let last = quote(9000, 0);
+        assert!(filter.matches(&quote(9090, 0), Some(&last)));
+        assert!(filter.matches(&quote(20000, 6000), Some(&quote(19800, 0))));
+        assert!(!filter.matches(&quote(20000, 100), Some(&quote(19800, 0))));
+        assert!(!filter.matches(&quote(9010, 0), Some(&last)));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for expr in ["", "price", "price=1", "move>", "cross(1", "(price>1", "price>1|", "price>1x", "spread>1"] {
+            assert!(QuoteFilter::parse(expr).is_err(), "{expr}");
+        }
+    }
+
+    #[test]
+    fn nesting_depth() {
+        let nested = |depth: usize| format!("{}price>1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(QuoteFilter::parse(&nested(MAX_FILTER_DEPTH)).is_ok());
+        assert!(QuoteFilter::parse(&nested(MAX_FILTER_DEPTH + 1)).is_err());
+        assert!(QuoteFilter::parse(&nested(100_000)).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::subscription::SubscriptionOptions;
+use crate::encoded_quote::SharedQuote;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::new(1, 0);
+
+/// Ограничивает частоту отправки котировок клиенту. Промежуточные значения не копятся,
+/// для каждой котировки хранится только последнее значение, которое и уйдет клиенту когда наступит его время
+pub struct RateLimiter {
+    ticker_interval: Option<Duration>,
+    max_packets_per_sec: Option<u32>,
+    pending: HashMap<String, SharedQuote>,
+    last_sent: HashMap<String, Instant>,
+    window_start: Instant,
+    packets_in_window: u32,
+}
+
+impl RateLimiter {
+    pub fn new(options: &SubscriptionOptions) -> Self {
+        Self {
+            ticker_interval: options.ticker_interval,
+            max_packets_per_sec: options.max_packets_per_sec,
+            pending: HashMap::new(),
+            last_sent: HashMap::new(),
+            window_start: Instant::now(),
+            packets_in_window: 0,
+        }
+    }
+
+    ///
+    /// Запомнить новые значения котировок. Значение, которое еще не успели отправить, заменяется новым
+    ///
+    /// # Arguments
+    ///
+    /// * `quotes`: новые значения котировок клиента
+    ///
+    pub fn push<'a>(&mut self, quotes: impl Iterator<Item = &'a SharedQuote>) {
+        for quote in quotes {
+            self.pending.insert(quote.ticker.clone(), quote.clone());
+        }
+    }
+
+    ///
+    /// Забрать котировки, которые уже можно отправить. Вызов считается отправкой одного пакета
+    /// если вернулся не пустой список
+    ///
+    /// returns: Vec<SharedQuote> - котировки для отправки, пустой если отправлять пока нечего
+    ///
+    pub fn take_ready(&mut self) -> Vec<SharedQuote> {
+        let now = Instant::now();
+        if self.pending.is_empty() {
+            return Vec::new();
+        }
+
+        if now.duration_since(self.window_start) >= RATE_WINDOW {
+            self.window_start = now;
+            self.packets_in_window = 0;
+        }
+        if let Some(max) = self.max_packets_per_sec
+            && self.packets_in_window >= max
+        {
+            return Vec::new();
+        }
+
+        let ready = self
+            .pending
+            .keys()
+            .filter(|ticker| match (self.ticker_interval, self.last_sent.get(*ticker)) {
+                (Some(interval), Some(sent)) => now.duration_since(*sent) >= interval,
+                _ => true,
+            })
+            .cloned()
+            .collect::<Vec<String>>();
+        if ready.is_empty() {
+            return Vec::new();
+        }
+
+        self.packets_in_window += 1;
+        ready
+            .into_iter()
+            .filter_map(|ticker| {
+                self.last_sent.insert(ticker.clone(), now);
+                self.pending.remove(&ticker)
+            })
+            .collect()
+    }
+
+    ///
+    /// Забыть отложенные значения котировок, от которых клиент отписался
+    ///
+    /// # Arguments
+    ///
+    /// * `tickers`: котировки, на которые клиент подписан
+    ///
+    pub fn retain(&mut self, tickers: &[String]) {
+        self.pending.retain(|ticker, _| tickers.contains(ticker));
+    }
+
+    ///
+    /// Есть ли котировки, которые ждут своего времени отправки
+    ///
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoded_quote::EncodedQuote;
+    use common_lib::stock_quote::StockQuote;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn quote(ticker: &str, price: u32) -> SharedQuote {
+        Arc::new(
+            EncodedQuote::new(StockQuote {
+                ticker: ticker.to_string(),
+                price,
+                volume: 0,
+                timestamp: 0,
+            })
+            .unwrap(),
+        )
+    }
+
+    fn limiter(ticker_interval: Option<Duration>, max_packets_per_sec: Option<u32>) -> RateLimiter {
+        RateLimiter::new(&SubscriptionOptions {
+            ticker_interval,
+            max_packets_per_sec,
+            ..SubscriptionOptions::default()
+        })
+    }
+
+    #[test]
+    fn keeps_last_value() {
+        let mut limiter = limiter(None, None);
+        limiter.push([quote("AAPL", 1), quote("AAPL", 2)].iter());
+        let ready = limiter.take_ready();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].price, 2);
+        assert!(!limiter.has_pending());
+    }
+
+    #[test]
+    fn packet_burst_and_refill() {
+        let mut limiter = limiter(None, Some(2));
+        for price in 1..=2 {
+            limiter.push([quote("AAPL", price)].iter());
+            assert_eq!(limiter.take_ready().len(), 1);
+        }
+        limiter.push([quote("AAPL", 3)].iter());
+        assert!(limiter.take_ready().is_empty());
+        assert!(limiter.has_pending());
+
+        thread::sleep(RATE_WINDOW);
+        let ready = limiter.take_ready();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].price, 3);
+    }
+
+    #[test]
+    fn ticker_interval() {
+        let interval = Duration::from_millis(50);
+        let mut limiter = limiter(Some(interval), None);
+        limiter.push([quote("AAPL", 1)].iter());
+        assert_eq!(limiter.take_ready().len(), 1);
+
+        limiter.push([quote("AAPL", 2), quote("MSFT", 1)].iter());
+        let ready = limiter.take_ready();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].ticker, "MSFT");
+        assert!(limiter.take_ready().is_empty());
+
+        thread::sleep(interval);
+        let ready = limiter.take_ready();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].price, 2);
+    }
+
+    #[test]
+    fn retain_drops_unsubscribed() {
+        let mut limiter = limiter(None, None);
+        limiter.push([quote("AAPL", 1), quote("MSFT", 1)].iter());
+        limiter.retain(&["MSFT".to_string()]);
+        let ready = limiter.take_ready();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].ticker, "MSFT");
+    }
+}
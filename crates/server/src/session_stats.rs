@@ -0,0 +1,75 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Счетчики сессии подписчика. Их обновляет поток, который обслуживает соединение, а читает порт управления
+#[derive(Debug)]
+pub struct SessionStats {
+    started: Instant,
+    last_ping: Mutex<Option<Instant>>,
+    /// Количество отправленных пакетов с котировками
+    sent: AtomicU64,
+    /// Количество пакетов, выкинутых из переполненной очереди подписчика
+    dropped: AtomicU64,
+    /// Сессию закрыл администратор
+    kicked: AtomicBool,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            last_ping: Mutex::new(None),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            kicked: AtomicBool::new(false),
+        }
+    }
+}
+
+impl SessionStats {
+    /// Сколько времени работает сессия
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Сколько времени назад пришел последний ping. None если ping не приходил
+    pub fn last_ping(&self) -> Option<Duration> {
+        match self.last_ping.lock() {
+            Ok(last_ping) => last_ping.map(|t| t.elapsed()),
+            Err(_) => None,
+        }
+    }
+
+    /// Запомнить время ping сообщения
+    pub fn ping(&self) {
+        if let Ok(mut last_ping) = self.last_ping.lock() {
+            *last_ping = Some(Instant::now());
+        }
+    }
+
+    pub fn add_sent(&self, packets: u64) {
+        self.sent.fetch_add(packets, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn add_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Отметить, что сессию закрыл администратор. Клиент получит BYE с этой причиной
+    pub fn kick(&self) {
+        self.kicked.store(true, Ordering::Release);
+    }
+
+    pub fn is_kicked(&self) -> bool {
+        self.kicked.load(Ordering::Acquire)
+    }
+}
@@ -1,21 +1,158 @@
 use common_lib::errors::ErrType;
 use common_lib::errors::ErrType::NoAccess;
+use crate::encoded_quote::{EncodedQuote, Quotes, SharedQuote};
+use crate::metrics::Metrics;
+use crate::session_stats::SessionStats;
+use crate::subscription::{SlowConsumerPolicy, SubscriptionOptions};
+use common_lib::QUOTE_GENERATOR_PERIOD;
 use common_lib::stock_quote::StockQuote;
-use crossbeam_channel::{Receiver, Sender, unbounded};
+use crossbeam_channel::{Receiver, Sender, TrySendError, bounded};
+use mio::Waker;
 use rand::Rng;
 use std::collections::{HashMap, HashSet};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{thread, thread::JoinHandle};
 
 const POPULAR_QUOTES: [&str; 3] = ["AAPL", "MSFT", "TSLA"];
-type SubsType = Arc<RwLock<HashMap<String, Sender<Arc<Vec<StockQuote>>>>>>;
+type SubsType = Arc<RwLock<Arc<SubscriptionIndex>>>;
+type LatestType = Arc<RwLock<Quotes>>;
+type UniverseType = Arc<Mutex<Vec<StockQuote>>>;
+pub type QuotesReceiver = Receiver<Quotes>;
+type WakersType = Arc<RwLock<Vec<Arc<Waker>>>>;
+
+/// Параметры генератора котировок
+#[derive(Debug, Clone, Copy)]
+pub struct GeneratorOptions {
+    /// Как часто обновляются значения котировок
+    pub period: Duration,
+    /// На сколько процентов может измениться цена за одно обновление
+    pub volatility: u32,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        Self {
+            period: QUOTE_GENERATOR_PERIOD,
+            volatility: 20,
+        }
+    }
+}
+
+/// Котировки генератора. Их меняет поток обновления и порт управления
+#[derive(Clone)]
+struct GeneratorState {
+    /// Котировки, которые обновляет генератор
+    universe: UniverseType,
+    /// Последние значения котировок. Из них собирается снимок для новых подписчиков
+    latest: LatestType,
+    /// Обновление котировок приостановлено
+    paused: Arc<AtomicBool>,
+}
+
+/// Номер сессии подписчика. Выдается сервером и не повторяется, в отличие от адреса,
+/// который передает клиент
+pub type SessionId = u64;
+
+/// Канал новой сессии подписчика
+pub struct SubscriberChannel {
+    /// Номер сессии, по нему меняется подписка и закрывается канал
+    pub session: SessionId,
+    pub receiver: QuotesReceiver,
+    /// Счетчики сессии подписчика
+    pub stats: Arc<SessionStats>,
+}
+
+/// Очередь подписчика
+struct Subscriber {
+    session: SessionId,
+    /// Адрес клиента для логов и порта управления
+    address: String,
+    sender: Sender<Quotes>,
+    /// Копия читающей стороны очереди, через нее выкидываются самые старые пакеты
+    receiver: QuotesReceiver,
+    policy: SlowConsumerPolicy,
+    /// Счетчики сессии подписчика
+    stats: Arc<SessionStats>,
+}
+
+/// Сессия подписчика для порта управления
+pub struct SessionInfo {
+    pub session: SessionId,
+    pub address: String,
+    pub tickers: Vec<String>,
+    pub stats: Arc<SessionStats>,
+}
+
+/// Подписчики по котировкам. Индекс не меняется на месте: при изменении подписок собирается копия
+/// и подменяет старую. Рассылка берет текущий индекс под короткой блокировкой чтения и дальше
+/// работает без блокировок, а котировки, на которые никто не подписан, никому не рассылаются
+#[derive(Default, Clone)]
+struct SubscriptionIndex {
+    by_ticker: HashMap<String, Vec<Arc<Subscriber>>>,
+    by_session: HashMap<SessionId, (Arc<Subscriber>, HashSet<String>)>,
+}
+
+impl SubscriptionIndex {
+    ///
+    /// Добавить подписчика или заменить подписчика той же сессии
+    ///
+    fn insert(&mut self, subscriber: Arc<Subscriber>, tickers: HashSet<String>) {
+        self.remove(subscriber.session);
+        for ticker in &tickers {
+            self.by_ticker
+                .entry(ticker.clone())
+                .or_default()
+                .push(subscriber.clone());
+        }
+        self.by_session.insert(subscriber.session, (subscriber, tickers));
+    }
+
+    ///
+    /// Удалить подписчика
+    ///
+    fn remove(&mut self, session: SessionId) {
+        let Some((_, tickers)) = self.by_session.remove(&session) else {
+            return;
+        };
+        for ticker in tickers {
+            if let Some(subscribers) = self.by_ticker.get_mut(&ticker) {
+                subscribers.retain(|s| s.session != session);
+                if subscribers.is_empty() {
+                    self.by_ticker.remove(&ticker);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Заменить список котировок подписчика
+    ///
+    /// returns: bool - false если подписчика нет
+    ///
+    fn set_tickers(&mut self, session: SessionId, tickers: HashSet<String>) -> bool {
+        let Some((subscriber, _)) = self.by_session.get(&session) else {
+            return false;
+        };
+        let subscriber = subscriber.clone();
+        self.insert(subscriber, tickers);
+        true
+    }
+}
 
 pub struct QuoteHandler {
     stopper: Arc<AtomicBool>,
-    join_handle: Option<JoinHandle<()>>,
+    join_handle: Mutex<Option<JoinHandle<()>>>,
     subscribers: SubsType,
+    state: GeneratorState,
+    volatility: u32,
+    /// Счетчики работы сервера для мониторинга
+    metrics: Arc<Metrics>,
+    /// Циклы событий, которые нужно разбудить после рассылки котировок
+    wakers: WakersType,
+    /// Номер последней выданной сессии
+    last_session: AtomicU64,
 }
 
 impl QuoteHandler {
@@ -24,22 +161,43 @@ impl QuoteHandler {
     /// # Arguments
     ///
     /// * `tickers`: Список имен котировок для которых необходимо обновлять значения
+    /// * `generator`: Как часто и насколько сильно меняются котировки
     ///
     /// returns: (QuoteHandler, JoinHandle<()>) - Объект и поток для корректной завершении работы
     ///
-    pub fn new(tickers: &HashSet<String>) -> QuoteHandler {
+    pub fn new(tickers: &HashSet<String>, generator: GeneratorOptions) -> QuoteHandler {
         let stopper = Arc::new(AtomicBool::new(false));
         let stopper_clone = stopper.clone();
-        let subscribers = Arc::new(RwLock::new(HashMap::new()));
+        let subscribers = Arc::new(RwLock::new(Arc::new(SubscriptionIndex::default())));
+        let state = GeneratorState {
+            universe: Arc::new(Mutex::new(
+                tickers
+                    .iter()
+                    .map(|ticker| Self::generate_quote(ticker, None, generator.volatility))
+                    .collect::<Vec<StockQuote>>(),
+            )),
+            latest: Arc::new(RwLock::new(Arc::new(Vec::new()))),
+            paused: Arc::new(AtomicBool::new(false)),
+        };
+        let metrics = Arc::new(Metrics::default());
+        let wakers = Arc::new(RwLock::new(Vec::new()));
 
         Self {
             stopper,
-            join_handle: Some(Self::start_update_quotes(
+            join_handle: Mutex::new(Some(Self::start_update_quotes(
                 stopper_clone,
-                tickers,
+                state.clone(),
+                generator,
                 subscribers.clone(),
-            )),
+                metrics.clone(),
+                wakers.clone(),
+            ))),
             subscribers,
+            state,
+            volatility: generator.volatility,
+            metrics,
+            wakers,
+            last_session: AtomicU64::new(0),
         }
     }
 
@@ -47,18 +205,24 @@ impl QuoteHandler {
     ///
     /// # Arguments
     ///
-    /// * `inner`: Ссылка на общие данные где и будут храниться данные котировок
+    /// * `state`: Котировки генератора, их последние значения и флаг паузы
+    /// * `generator`: Параметры генератора котировок
+    /// * `metrics`: Счетчики работы сервера. В них учитываются выкинутые пакеты и длительность обновлений
+    /// * `wakers`: Циклы событий, которые забирают котировки из очередей подписчиков
     ///
     /// returns: JoinHandle<()> - держатель потока с помощью которого можно будет дождаться корректного завершения потока
     ///
     fn start_update_quotes(
         stopper: Arc<AtomicBool>,
-        tickers: &HashSet<String>,
+        state: GeneratorState,
+        generator: GeneratorOptions,
         subscribers: SubsType,
+        metrics: Arc<Metrics>,
+        wakers: WakersType,
     ) -> JoinHandle<()> {
-        let mut stocks: Vec<StockQuote> = Vec::new();
-        for ticker in tickers {
-            stocks.push(Self::generate_quote(ticker, None));
+        // Снимок есть сразу, что бы первые подписчики не ждали первого обновления
+        if let Ok(stocks) = state.universe.lock() {
+            Self::store_latest(&state.latest, &stocks);
         }
 
         thread::spawn(move || {
@@ -70,39 +234,230 @@ impl QuoteHandler {
                     break;
                 }
 
-                for quote in &mut stocks {
-                    let new = Self::generate_quote(&quote.ticker, Some(quote.price));
-                    quote.price = new.price;
-                    quote.volume = new.volume;
-                    quote.timestamp = new.timestamp;
+                // На паузе подписчики не получают обновлений, но соединения продолжают жить
+                if state.paused.load(Ordering::Acquire) {
+                    thread::sleep(generator.period);
+                    continue;
                 }
 
-                let data = Arc::new(stocks.clone());
-                match subscribers.read() {
-                    Ok(subscribers) => {
-                        subscribers
-                            .iter()
-                            .for_each(|(_, s)| match s.try_send(data.clone()) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    log::warn!("Не удалось отправить сообщение по каналу. {:?}", e)
-                                }
-                            })
+                let started = Instant::now();
+                let data = match state.universe.lock() {
+                    Ok(mut stocks) => {
+                        for quote in stocks.iter_mut() {
+                            let new = Self::generate_quote(
+                                &quote.ticker,
+                                Some(quote.price),
+                                generator.volatility,
+                            );
+                            quote.price = new.price;
+                            quote.volume = new.volume;
+                            quote.timestamp = new.timestamp;
+                        }
+                        Self::store_latest(&state.latest, &stocks)
                     }
                     Err(_) => {
-                        log::debug!("Не удалось отправить данные котировок в канал");
+                        log::error!("Не удалось обновить значения котировок");
+                        break;
                     }
                 };
-                thread::sleep(common_lib::QUOTE_GENERATOR_PERIOD);
+                Self::broadcast(&subscribers, data.clone(), &metrics);
+                Self::wake(&wakers);
+                metrics.observe_tick(started.elapsed(), data.iter().map(|quote| quote.ticker.as_str()));
+                thread::sleep(generator.period);
             }
         })
     }
 
+    ///
+    /// Разослать новые значения котировок подписчикам. Каждый подписчик получает только свои котировки.
+    /// Если очередь подписчика заполнена, то поступаем согласно его политике
+    ///
+    /// # Arguments
+    ///
+    /// * `subscribers`: подписчики
+    /// * `data`: новые значения котировок
+    /// * `metrics`: счетчики выкинутых и неотправленных пакетов
+    ///
+    fn broadcast(
+        subscribers: &SubsType,
+        data: Quotes,
+        metrics: &Metrics,
+    ) {
+        let index = match subscribers.read() {
+            Ok(index) => index.clone(),
+            Err(_) => {
+                log::debug!("Не удалось отправить данные котировок в канал");
+                return;
+            }
+        };
+
+        // Раскладываем котировки по подписчикам. Подписчик различается по адресу его очереди в памяти
+        let mut batches: HashMap<*const Subscriber, (&Arc<Subscriber>, Vec<SharedQuote>)> =
+            HashMap::new();
+        for quote in data.iter() {
+            let Some(watchers) = index.by_ticker.get(&quote.ticker) else {
+                continue;
+            };
+            for subscriber in watchers {
+                batches
+                    .entry(Arc::as_ptr(subscriber))
+                    .or_insert_with(|| (subscriber, Vec::new()))
+                    .1
+                    .push(quote.clone());
+            }
+        }
+
+        let mut disconnect = Vec::new();
+        for (subscriber, quotes) in batches.into_values() {
+            let address = &subscriber.address;
+            match subscriber.sender.try_send(Arc::new(quotes)) {
+                Ok(_) => {}
+                Err(TrySendError::Full(data)) => {
+                    subscriber.stats.add_dropped();
+                    metrics.add_dropped_batch();
+                    if subscriber.policy == SlowConsumerPolicy::Disconnect {
+                        log::warn!("Клиент {address} не успевает забирать котировки и будет отключен");
+                        disconnect.push(subscriber.session);
+                        continue;
+                    }
+                    // Освобождаем место выкидывая самый старый пакет
+                    let _ = subscriber.receiver.try_recv();
+                    if let Err(e) = subscriber.sender.try_send(data) {
+                        metrics.channel_send_failed();
+                        log::warn!("Не удалось отправить сообщение по каналу. {:?}", e)
+                    }
+                }
+                Err(e) => {
+                    metrics.channel_send_failed();
+                    log::warn!("Не удалось отправить сообщение по каналу. {:?}", e)
+                }
+            }
+        }
+
+        // Удаление очереди закрывает канал и соединение клиента закрывается
+        if !disconnect.is_empty() {
+            Self::update_index(subscribers, |index| {
+                for session in &disconnect {
+                    index.remove(*session);
+                }
+            });
+        }
+    }
+
+    ///
+    /// Изменить индекс подписчиков. Изменяется копия индекса, которая затем подменяет текущий,
+    /// поэтому рассылка не ждет изменения подписок
+    ///
+    /// # Arguments
+    ///
+    /// * `subscribers`: подписчики
+    /// * `change`: изменение копии индекса
+    ///
+    /// returns: Option<R> - None если индекс недоступен
+    ///
+    fn update_index<R>(
+        subscribers: &SubsType,
+        change: impl FnOnce(&mut SubscriptionIndex) -> R,
+    ) -> Option<R> {
+        match subscribers.write() {
+            Ok(mut current) => {
+                let mut index = current.as_ref().clone();
+                let result = change(&mut index);
+                *current = Arc::new(index);
+                Some(result)
+            }
+            Err(_) => {
+                log::error!("Не удалось изменить подписки");
+                None
+            }
+        }
+    }
+
+    ///
+    /// Закодировать значения котировок и сохранить их как последние. Котировки кодируются один раз,
+    /// дальше все подписчики получают готовые байты
+    ///
+    /// # Arguments
+    ///
+    /// * `latest`: последние значения котировок
+    /// * `stocks`: новые значения котировок
+    ///
+    /// returns: Quotes - закодированные котировки для рассылки
+    ///
+    fn store_latest(latest: &LatestType, stocks: &[StockQuote]) -> Quotes {
+        let data = Self::encode(stocks);
+        match latest.write() {
+            Ok(mut latest) => *latest = data.clone(),
+            Err(_) => {
+                log::error!("Не удалось сохранить последние значения котировок");
+            }
+        }
+        data
+    }
+
+    ///
+    /// Закодировать значения котировок для рассылки
+    ///
+    /// # Arguments
+    ///
+    /// * `stocks`: новые значения котировок
+    ///
+    /// returns: Quotes - котировки, которые не удалось закодировать, пропускаются
+    ///
+    fn encode(stocks: &[StockQuote]) -> Quotes {
+        let encoded = stocks
+            .iter()
+            .filter_map(|quote| match EncodedQuote::new(quote.clone()) {
+                Ok(encoded) => Some(Arc::new(encoded)),
+                Err(e) => {
+                    log::error!("{e}");
+                    None
+                }
+            })
+            .collect();
+        Arc::new(encoded)
+    }
+
+    ///
+    /// Разбудить циклы событий, что бы они забрали новые котировки из очередей подписчиков
+    ///
+    fn wake(wakers: &WakersType) {
+        match wakers.read() {
+            Ok(wakers) => {
+                for waker in wakers.iter() {
+                    if let Err(e) = waker.wake() {
+                        log::warn!("Не удалось разбудить цикл событий. {e}");
+                    }
+                }
+            }
+            Err(_) => {
+                log::error!("Не удалось разбудить циклы событий");
+            }
+        }
+    }
+
+    /// Добавить цикл событий, который будится после каждой рассылки котировок
+    pub fn add_waker(&self, waker: Arc<Waker>) {
+        match self.wakers.write() {
+            Ok(mut wakers) => wakers.push(waker),
+            Err(_) => {
+                log::error!("Не удалось добавить цикл событий");
+            }
+        }
+    }
+
     /// Остановит работу потока обновляющего значения котировок
-    pub fn stop(&mut self) -> Result<(), ErrType> {
+    pub fn stop(&self) -> Result<(), ErrType> {
         self.stopper.store(true, Ordering::Release);
-        log::info!("Поток обновления значений котировок будет остановлен");
-        if let Some(join_handle) = self.join_handle.take() {
+        log::info!(
+            "Поток обновления значений котировок будет остановлен. Всего выкинуто пакетов из переполненных очередей: {}",
+            self.dropped_batches()
+        );
+        let join_handle = match self.join_handle.lock() {
+            Ok(mut join_handle) => join_handle.take(),
+            Err(_) => None,
+        };
+        if let Some(join_handle) = join_handle {
             match join_handle.join() {
                 Ok(()) => (),
                 Err(_) => {
@@ -117,44 +472,245 @@ impl QuoteHandler {
         Ok(())
     }
 
-    /// Создаем новое канал по которому будем отправлять котировки
-    pub fn create_channel(&self, address: &String) -> Option<Receiver<Arc<Vec<StockQuote>>>> {
-        match self.subscribers.write() {
-            Ok(mut subscribers) => {
-                let (sender, receiver) = unbounded::<Arc<Vec<StockQuote>>>();
-                subscribers.insert(address.to_string(), sender);
-                Some(receiver)
-            }
-            Err(_) => {
+    /// Создаем новое канал по которому будем отправлять котировки. Первым сообщением в канал сразу
+    /// кладется снимок последних значений, что бы клиент не ждал следующего обновления.
+    /// Каждый канал получает новый номер сессии, поэтому два клиента с одним адресом не заменяют
+    /// каналы друг друга
+    pub fn create_channel(
+        &self,
+        address: &str,
+        tickers: &[String],
+        options: &SubscriptionOptions,
+    ) -> Option<SubscriberChannel> {
+        // При склеивании в очереди нужен только последний снимок
+        let capacity = match options.queue_policy {
+            SlowConsumerPolicy::Conflate => 1,
+            _ => options.queue_size,
+        };
+        let (sender, receiver) = bounded::<Quotes>(capacity);
+        if let Some(snapshot) = self.snapshot_of(tickers) {
+            let _ = sender.try_send(snapshot);
+        }
+        let stats = Arc::new(SessionStats::default());
+        let session = self.last_session.fetch_add(1, Ordering::Relaxed) + 1;
+        let subscriber = Arc::new(Subscriber {
+            session,
+            address: address.to_string(),
+            sender,
+            receiver: receiver.clone(),
+            policy: options.queue_policy,
+            stats: stats.clone(),
+        });
+        let tickers = tickers.iter().cloned().collect::<HashSet<String>>();
+        match Self::update_index(&self.subscribers, |index| index.insert(subscriber, tickers)) {
+            Some(_) => Some(SubscriberChannel {
+                session,
+                receiver,
+                stats,
+            }),
+            None => {
                 log::error!("Не удалось создать канал для передачи котировок");
                 None
             }
         }
     }
 
-    pub fn remove_channel(&self, address: &String) {
-        match self.subscribers.write() {
-            Ok(mut subscribers) => {
-                subscribers.remove(address);
+    ///
+    /// Заменить список котировок подписчика не пересоздавая его канал
+    ///
+    /// # Arguments
+    ///
+    /// * `session`: номер сессии подписчика
+    /// * `tickers`: новый список котировок
+    ///
+    /// returns: bool - false если подписчика нет
+    ///
+    pub fn set_tickers(&self, session: SessionId, tickers: &[String]) -> bool {
+        let tickers = tickers.iter().cloned().collect::<HashSet<String>>();
+        Self::update_index(&self.subscribers, |index| index.set_tickers(session, tickers))
+            .unwrap_or(false)
+    }
+
+    /// Последние значения указанных котировок
+    pub fn snapshot_of(&self, tickers: &[String]) -> Option<Quotes> {
+        let snapshot = self.snapshot()?;
+        Some(Arc::new(
+            snapshot
+                .iter()
+                .filter(|quote| tickers.contains(&quote.ticker))
+                .cloned()
+                .collect(),
+        ))
+    }
+
+    /// Последние значения всех котировок
+    pub fn snapshot(&self) -> Option<Quotes> {
+        match self.state.latest.read() {
+            Ok(latest) => Some(latest.clone()),
+            Err(_) => {
+                log::error!("Не удалось прочитать последние значения котировок");
+                None
             }
+        }
+    }
+
+    /// Сколько всего пакетов было выкинуто из переполненных очередей подписчиков
+    pub fn dropped_batches(&self) -> u64 {
+        self.metrics.dropped_batches()
+    }
+
+    /// Счетчики работы сервера для мониторинга
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Количество активных подписчиков
+    pub fn subscriber_count(&self) -> usize {
+        match self.subscribers.read() {
+            Ok(index) => index.by_session.len(),
+            Err(_) => 0,
+        }
+    }
+
+    pub fn remove_channel(&self, session: SessionId) {
+        if Self::update_index(&self.subscribers, |index| index.remove(session)).is_none() {
+            log::error!("Не удалось удалить канал для передачи котировок");
+        }
+    }
+
+    /// Активные сессии подписчиков в порядке подключения
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let index = match self.subscribers.read() {
+            Ok(index) => index.clone(),
             Err(_) => {
-                log::error!("Не удалось удалить канал для передачи котировок");
+                log::error!("Не удалось прочитать подписки");
+                return Vec::new();
             }
+        };
+        let mut sessions = index
+            .by_session
+            .iter()
+            .map(|(session, (subscriber, tickers))| {
+                let mut tickers = tickers.iter().cloned().collect::<Vec<String>>();
+                tickers.sort();
+                SessionInfo {
+                    session: *session,
+                    address: subscriber.address.clone(),
+                    tickers,
+                    stats: subscriber.stats.clone(),
+                }
+            })
+            .collect::<Vec<SessionInfo>>();
+        sessions.sort_by_key(|session| session.session);
+        sessions
+    }
+
+    ///
+    /// Закрыть сессию подписчика. Канал подписчика удаляется, а соединение, заметив закрытый канал,
+    /// отправляет клиенту BYE
+    ///
+    /// # Arguments
+    ///
+    /// * `session`: номер сессии подписчика
+    ///
+    /// returns: bool - false если подписчика нет
+    ///
+    pub fn kick(&self, session: SessionId) -> bool {
+        let kicked = Self::update_index(&self.subscribers, |index| {
+            let Some((subscriber, _)) = index.by_session.get(&session) else {
+                return false;
+            };
+            subscriber.stats.kick();
+            index.remove(session);
+            true
+        })
+        .unwrap_or(false);
+        if kicked {
+            Self::wake(&self.wakers);
         }
+        kicked
+    }
+
+    ///
+    /// Добавить котировки в генератор. Их значения сразу попадают в снимок
+    ///
+    /// # Arguments
+    ///
+    /// * `tickers`: новые котировки
+    ///
+    /// returns: Result<Vec<String>, ErrType> - котировки, которых раньше не было
+    ///
+    pub fn add_tickers(&self, tickers: &[String]) -> Result<Vec<String>, ErrType> {
+        let Ok(mut stocks) = self.state.universe.lock() else {
+            return Err(NoAccess("Не удалось изменить список котировок".to_string()));
+        };
+        let mut added = Vec::new();
+        for ticker in tickers {
+            if stocks.iter().any(|quote| &quote.ticker == ticker) || added.contains(ticker) {
+                continue;
+            }
+            stocks.push(Self::generate_quote(ticker, None, self.volatility));
+            added.push(ticker.clone());
+        }
+        Self::store_latest(&self.state.latest, &stocks);
+        Ok(added)
+    }
+
+    ///
+    /// Убрать котировки из генератора. Подписчики этих котировок перестают получать их значения
+    ///
+    /// # Arguments
+    ///
+    /// * `tickers`: котировки для удаления
+    ///
+    /// returns: Result<Vec<String>, ErrType> - котировки, которые были удалены
+    ///
+    pub fn remove_tickers(&self, tickers: &[String]) -> Result<Vec<String>, ErrType> {
+        let Ok(mut stocks) = self.state.universe.lock() else {
+            return Err(NoAccess("Не удалось изменить список котировок".to_string()));
+        };
+        let removed = stocks
+            .iter()
+            .filter(|quote| tickers.contains(&quote.ticker))
+            .map(|quote| quote.ticker.clone())
+            .collect::<Vec<String>>();
+        stocks.retain(|quote| !tickers.contains(&quote.ticker));
+        Self::store_latest(&self.state.latest, &stocks);
+        Ok(removed)
+    }
+
+    /// Котировки, которые обновляет генератор, по алфавиту
+    pub fn tickers(&self) -> Vec<String> {
+        let mut tickers = match self.state.universe.lock() {
+            Ok(stocks) => stocks.iter().map(|quote| quote.ticker.clone()).collect(),
+            Err(_) => Vec::new(),
+        };
+        tickers.sort();
+        tickers
+    }
+
+    /// Приостановить или продолжить обновление котировок
+    pub fn set_paused(&self, paused: bool) {
+        self.state.paused.store(paused, Ordering::Release);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::Acquire)
     }
 
     /// Генерирует новое значение для котировки. Изначально берется рандомная цена, а в последующих вызовах
-    /// цена генерируется в промежутке 80% от предыдущей цены до 120% от предыдущей цены. В результате изменения
-    /// цены будут реалистичнее
+    /// цена генерируется в промежутке от предыдущей цены минус volatility процентов до предыдущей цены плюс
+    /// volatility процентов. В результате изменения цены будут реалистичнее
     ///
     /// # Arguments
     ///
     /// * `ticker`: название котировки
     /// * `last_price`: предыдущая цена которая будет None при первом вызове
+    /// * `volatility`: на сколько процентов может измениться цена. Меньше 100, что бы цена не упала до нуля
     ///
     /// returns: Option<StockQuote> - новая котировка
     ///
-    fn generate_quote(ticker: &str, last_price: Option<u32>) -> StockQuote {
+    fn generate_quote(ticker: &str, last_price: Option<u32>, volatility: u32) -> StockQuote {
         let mut generator = rand::rng();
         let price: u32;
         if let Some(lp) = last_price {
@@ -164,7 +720,7 @@ impl QuoteHandler {
                 start = lp;
                 end = lp * 2;
             } else {
-                let percents = (lp / 100) * 20;
+                let percents = (lp / 100) * volatility;
                 start = lp - percents;
                 end = lp + percents;
             }
@@ -192,3 +748,34 @@ impl QuoteHandler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_with_same_address() {
+        let tickers = ["AAPL", "MSFT"].iter().map(|t| t.to_string()).collect::<HashSet<String>>();
+        let stocks = QuoteHandler::new(&tickers, GeneratorOptions::default());
+        let address = "udp://127.0.0.1:5000".to_string();
+        let options = SubscriptionOptions::default();
+        let first = stocks.create_channel(&address, &["AAPL".to_string()], &options).unwrap();
+        let second = stocks.create_channel(&address, &["MSFT".to_string()], &options).unwrap();
+        assert_ne!(first.session, second.session);
+        assert_eq!(stocks.subscriber_count(), 2);
+
+        assert!(stocks.set_tickers(second.session, &tickers.iter().cloned().collect::<Vec<String>>()));
+        stocks.remove_channel(first.session);
+        let sessions = stocks.sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session, second.session);
+        assert_eq!(sessions[0].address, address);
+        assert_eq!(sessions[0].tickers, ["AAPL", "MSFT"]);
+
+        assert!(stocks.kick(second.session));
+        assert!(second.stats.is_kicked());
+        assert!(!stocks.kick(second.session));
+        assert_eq!(stocks.subscriber_count(), 0);
+        stocks.stop().unwrap();
+    }
+}
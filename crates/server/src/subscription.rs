@@ -0,0 +1,299 @@
+use crate::entitlements::Entitlement;
+use crate::quote_filter::QuoteFilter;
+use common_lib::errors::ErrType;
+use common_lib::{
+    FILTER_OPTION, MAX_PACKETS_OPTION, MAX_SESSION_TICKERS, MAX_SUBSCRIBER_QUEUE_SIZE, NOT_ENTITLED_ERROR,
+    QUEUE_POLICY_OPTION, QUEUE_SIZE_OPTION, SUBSCRIBER_QUEUE_SIZE, TICKER_INTERVAL_OPTION,
+};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Что делать с подписчиком, который не успевает забирать котировки из своей очереди
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// Выкинуть самый старый пакет и положить новый
+    #[default]
+    DropOldest,
+    /// В очереди держится только последний снимок котировок
+    Conflate,
+    /// Отключить подписчика
+    Disconnect,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = ErrType;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_oldest" => Ok(SlowConsumerPolicy::DropOldest),
+            "conflate" => Ok(SlowConsumerPolicy::Conflate),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            _ => Err(ErrType::NotSupported(format!(
+                "Неизвестная политика очереди {s}. Доступны drop_oldest, conflate, disconnect"
+            ))),
+        }
+    }
+}
+
+/// Дополнительные параметры подписки, которые клиент передает в запросе STREAM после списка котировок
+/// в виде `ключ=значение`
+#[derive(Debug, Clone)]
+pub struct SubscriptionOptions {
+    /// Не чаще одного обновления каждой котировки за этот период
+    pub ticker_interval: Option<Duration>,
+    /// Не больше этого количества пакетов в секунду
+    pub max_packets_per_sec: Option<u32>,
+    /// Условия отправки для отдельных котировок. Передаются как `filter=AAPL:move>0.5`
+    pub filters: HashMap<String, QuoteFilter>,
+    /// Размер очереди пакетов котировок подписчика
+    pub queue_size: usize,
+    /// Что делать когда очередь заполнена
+    pub queue_policy: SlowConsumerPolicy,
+}
+
+impl Default for SubscriptionOptions {
+    fn default() -> Self {
+        Self {
+            ticker_interval: None,
+            max_packets_per_sec: None,
+            filters: HashMap::new(),
+            queue_size: SUBSCRIBER_QUEUE_SIZE,
+            queue_policy: SlowConsumerPolicy::default(),
+        }
+    }
+}
+
+impl SubscriptionOptions {
+    ///
+    /// Разобрать параметры подписки
+    ///
+    /// # Arguments
+    ///
+    /// * `parts`: части запроса, которые идут после списка котировок
+    ///
+    /// returns: Result<SubscriptionOptions, ErrType>
+    ///
+    pub fn parse<'a>(parts: impl Iterator<Item = &'a str>) -> Result<Self, ErrType> {
+        let mut options = Self::default();
+        for part in parts {
+            let Some((key, value)) = part.split_once('=') else {
+                return Err(ErrType::RequestError(format!(
+                    "Параметр {part} должен быть в виде ключ=значение"
+                )));
+            };
+            match key {
+                TICKER_INTERVAL_OPTION => {
+                    let ms = value.parse::<u64>()?;
+                    options.ticker_interval = Some(Duration::from_millis(ms));
+                }
+                MAX_PACKETS_OPTION => {
+                    let packets = value.parse::<u32>()?;
+                    if packets == 0 {
+                        return Err(ErrType::RequestError(format!(
+                            "Параметр {MAX_PACKETS_OPTION} должен быть больше 0"
+                        )));
+                    }
+                    options.max_packets_per_sec = Some(packets);
+                }
+                FILTER_OPTION => {
+                    let Some((ticker, expr)) = value.split_once(':') else {
+                        return Err(ErrType::RequestError(format!(
+                            "Фильтр {value} должен быть в виде котировка:условие"
+                        )));
+                    };
+                    options
+                        .filters
+                        .insert(ticker.to_string(), QuoteFilter::parse(expr)?);
+                }
+                QUEUE_SIZE_OPTION => {
+                    let size = value.parse::<usize>()?;
+                    if size == 0 || size > MAX_SUBSCRIBER_QUEUE_SIZE {
+                        return Err(ErrType::RequestError(format!(
+                            "Параметр {QUEUE_SIZE_OPTION} должен быть от 1 до {MAX_SUBSCRIBER_QUEUE_SIZE}"
+                        )));
+                    }
+                    options.queue_size = size;
+                }
+                QUEUE_POLICY_OPTION => {
+                    options.queue_policy = value.parse()?;
+                }
+                _ => {
+                    return Err(ErrType::NotSupported(format!("Неизвестный параметр {key}")));
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Котировки запроса подписки после проверки. Запросы STREAM, SUB и подписка websocket
+/// проверяются одинаково
+#[derive(Debug, Default)]
+pub struct TickerCheck {
+    /// Котировки, которые можно добавить в подписку
+    pub allowed: Vec<String>,
+    /// Котировок нет в генераторе
+    pub unknown: Vec<String>,
+    /// На котировки у клиента нет прав
+    pub not_entitled: Vec<String>,
+    /// Котировки сверх MAX_SESSION_TICKERS
+    pub over_limit: Vec<String>,
+}
+
+impl TickerCheck {
+    ///
+    /// Проверить запрошенные котировки. Котировки, которые уже есть в подписке, и повторы пропускаются
+    ///
+    /// # Arguments
+    ///
+    /// * `requested`: запрошенные котировки
+    /// * `current`: котировки, на которые клиент уже подписан
+    /// * `known`: котировки генератора по алфавиту
+    /// * `entitlement`: права клиента. None если права не ограничены
+    ///
+    /// returns: TickerCheck
+    ///
+    pub fn check(
+        requested: impl IntoIterator<Item = String>,
+        current: &[String],
+        known: &[String],
+        entitlement: Option<&Entitlement>,
+    ) -> Self {
+        let mut check = Self::default();
+        for ticker in requested {
+            if current.contains(&ticker) || check.contains(&ticker) {
+                continue;
+            }
+            // Котировки генератора отсортированы, поэтому ищем неизвестные двоичным поиском
+            if known.binary_search(&ticker).is_err() {
+                check.unknown.push(ticker);
+            } else if !entitlement.is_none_or(|entitlement| entitlement.allows(&ticker)) {
+                check.not_entitled.push(ticker);
+            } else if current.len() + check.allowed.len() >= MAX_SESSION_TICKERS {
+                check.over_limit.push(ticker);
+            } else {
+                check.allowed.push(ticker);
+            }
+        }
+        check
+    }
+
+    fn contains(&self, ticker: &String) -> bool {
+        self.allowed.contains(ticker)
+            || self.unknown.contains(ticker)
+            || self.not_entitled.contains(ticker)
+            || self.over_limit.contains(ticker)
+    }
+
+    /// Все отклоненные котировки
+    pub fn denied(&self) -> Vec<String> {
+        [&self.unknown, &self.not_entitled, &self.over_limit]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    ///
+    /// Текст ошибки для запроса, который принимается только целиком
+    ///
+    /// returns: Option<String> - None если отклоненных котировок нет
+    ///
+    pub fn error(&self) -> Option<String> {
+        if !self.not_entitled.is_empty() {
+            Some(format!(
+                "{NOT_ENTITLED_ERROR} Нет доступа к котировкам {}",
+                self.not_entitled.join(",")
+            ))
+        } else if !self.unknown.is_empty() {
+            Some(format!("Неизвестные котировки {}", self.unknown.join(",")))
+        } else if !self.over_limit.is_empty() {
+            Some(format!("Можно запросить не больше {MAX_SESSION_TICKERS} котировок"))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(request: &str) -> Result<SubscriptionOptions, ErrType> {
+        SubscriptionOptions::parse(request.split_whitespace())
+    }
+
+    #[test]
+    fn defaults() {
+        let options = parse("").unwrap();
+        assert!(options.ticker_interval.is_none());
+        assert!(options.max_packets_per_sec.is_none());
+        assert!(options.filters.is_empty());
+        assert_eq!(options.queue_size, SUBSCRIBER_QUEUE_SIZE);
+        assert_eq!(options.queue_policy, SlowConsumerPolicy::DropOldest);
+    }
+
+    #[test]
+    fn all_options() {
+        let options =
+            parse("interval_ms=250 max_pps=10 filter=AAPL:price>100 queue=64 policy=conflate").unwrap();
+        assert_eq!(options.ticker_interval, Some(Duration::from_millis(250)));
+        assert_eq!(options.max_packets_per_sec, Some(10));
+        assert!(options.filters.contains_key("AAPL"));
+        assert_eq!(options.queue_size, 64);
+        assert_eq!(options.queue_policy, SlowConsumerPolicy::Conflate);
+        assert_eq!(parse("queue=1024").unwrap().queue_size, MAX_SUBSCRIBER_QUEUE_SIZE);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for request in [
+            "queue",
+            "queue=0",
+            "queue=1025",
+            "queue=-1",
+            "max_pps=0",
+            "max_pps=many",
+            "interval_ms=1.5",
+            "filter=price>1",
+            "filter=AAPL:price=1",
+            "policy=block",
+            "speed=1",
+        ] {
+            assert!(parse(request).is_err(), "{request}");
+        }
+    }
+
+    fn tickers(list: &[&str]) -> Vec<String> {
+        list.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn ticker_check() {
+        let known = tickers(&["AAPL", "MSFT", "TSLA"]);
+        let checked = TickerCheck::check(tickers(&["MSFT", "NVDA", "MSFT", "AAPL"]), &tickers(&["AAPL"]), &known, None);
+        assert_eq!(checked.allowed, ["MSFT"]);
+        assert_eq!(checked.unknown, ["NVDA"]);
+        assert_eq!(checked.denied(), ["NVDA"]);
+        assert!(checked.error().unwrap().contains("NVDA"));
+
+        let nothing = Entitlement::default();
+        let checked = TickerCheck::check(tickers(&["TSLA"]), &[], &known, Some(&nothing));
+        assert_eq!(checked.not_entitled, ["TSLA"]);
+        assert!(checked.error().unwrap().starts_with(NOT_ENTITLED_ERROR));
+
+        let checked = TickerCheck::check(known.clone(), &[], &known, None);
+        assert_eq!(checked.allowed, known);
+        assert!(checked.error().is_none());
+    }
+
+    #[test]
+    fn ticker_limit() {
+        let known = (0..MAX_SESSION_TICKERS + 2).map(|i| format!("T{i:04}")).collect::<Vec<String>>();
+        let current = known[..MAX_SESSION_TICKERS - 1].to_vec();
+        let checked = TickerCheck::check(known.clone(), &current, &known, None);
+        assert_eq!(checked.allowed, [known[MAX_SESSION_TICKERS - 1].clone()]);
+        assert_eq!(checked.over_limit, known[MAX_SESSION_TICKERS..]);
+        assert!(checked.error().is_some());
+    }
+}
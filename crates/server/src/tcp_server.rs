@@ -1,10 +1,82 @@
+use crate::connection_limits::HandshakePermit;
+use crate::credentials::Credentials;
+use crate::entitlements::{Entitlement, Entitlements};
+use crate::metrics::{HandshakeRejection, Metrics};
+use crate::multicast_publisher::MulticastGroup;
 use crate::stock_quotes_handler::QuoteHandler;
+use crate::subscription::{SubscriptionOptions, TickerCheck};
+#[cfg(unix)]
+use crate::transport::UnixTransport;
+use crate::transport::{PortRange, TcpTransport, Transport, UdpTransport};
 use crate::udp_server_writer::ServerWriter;
-use common_lib::STREAM_REQUEST;
+use common_lib::datagram_auth::SessionKey;
 use common_lib::errors::ErrType;
 use common_lib::errors::ErrType::ConnectionError;
+use common_lib::{
+    AUTH_FAILED_ERROR, AUTH_REQUEST, AUTH_REQUIRED_ERROR, MAX_HANDSHAKE_LINE_SIZE, MULTICAST_STREAM,
+    NAT_STREAM, NOT_ENTITLED_ERROR, OK_REQUEST, SESSION_KEY_OPTION, STREAM_REQUEST, TCP_STREAM, UDP_STREAM_PREFIX, UNIX_STREAM_PREFIX,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
 use std::io::{BufRead, BufReader, Write};
-use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Сколько соединений ОС держит в очереди, пока сервер их не принял
+const LISTEN_BACKLOG: i32 = 128;
+
+/// Настройки рукопожатия, общие для всех подключений
+pub struct HandshakeOptions {
+    /// multicast группы. Клиенту, который запросил multicast, отправляются адреса групп
+    pub groups: Vec<MulticastGroup>,
+    /// Ключ подписи multicast датаграмм. Передается клиенту вместе с адресами групп
+    pub multicast_key: SessionKey,
+    /// Порты, с которых сервер отправляет котировки по udp
+    pub udp_ports: Option<PortRange>,
+    /// За сколько клиент должен прислать корректный запрос STREAM
+    pub timeout: Duration,
+    /// Токены клиентов. Если не заданы, то запрос STREAM принимается без AUTH
+    pub credentials: Option<Arc<Credentials>>,
+    /// Права клиентов на котировки. Если не заданы, то клиент может получать любые котировки
+    pub entitlements: Option<Arc<Entitlements>>,
+    /// Папка, в которой сервер подключается к unix сокетам клиентов. Если не задана, то unix сокеты выключены
+    pub unix_socket_dir: Option<PathBuf>,
+}
+
+/// Куда отправлять котировки клиента
+enum Destination {
+    Udp(SocketAddr),
+    /// Котировки идут по тому же tcp соединению
+    Tcp,
+    /// Unix сокет клиента на этой же машине
+    Unix(PathBuf),
+    /// Клиент за NAT, его адрес станет известен после регистрации
+    Nat,
+}
+
+///
+/// Создать сокет, который принимает tcp соединения. Если задан ipv6 адрес, то сокет
+/// принимает и ipv4 соединения, поэтому на `[::]` сервер доступен по обоим протоколам
+///
+/// # Arguments
+///
+/// * `address`: адрес и порт
+///
+/// returns: io::Result<TcpListener>
+///
+pub fn bind(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    // После перезапуска сервера порт должен сразу освобождаться
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
 
 ///
 /// Метод в котором обрабатываем tcp соединение, проверяем данные запросов и создаем upd соединение если все успешно
@@ -13,22 +85,58 @@ use std::net::TcpStream;
 ///
 /// * `stream`: tcp соединение
 /// * `stocks`: Хранитель котировок. Нужен для создания канала
+/// * `options`: настройки рукопожатия
+/// * `permit`: разрешение на рукопожатие, через него занимается место в лимите сессий
 ///
-/// returns: Result<ServerWriter, ErrType>
+/// returns: Result<Option<ServerWriter>, ErrType> - None если клиент получает котировки через multicast
 ///
 pub fn handle_client(
     stream: TcpStream,
-    stocks: &mut QuoteHandler,
-) -> Result<ServerWriter, ErrType> {
+    stocks: &Arc<QuoteHandler>,
+    options: &HandshakeOptions,
+    permit: &HandshakePermit,
+) -> Result<Option<ServerWriter>, ErrType> {
+    let HandshakeOptions {
+        groups,
+        multicast_key,
+        udp_ports,
+        timeout,
+        credentials,
+        entitlements,
+        unix_socket_dir,
+    } = options;
+    let (udp_ports, timeout) = (*udp_ports, *timeout);
+    // Рукопожатие ограничено по времени целиком, а не каждое чтение, иначе клиент может тянуть его бесконечно
+    let deadline = Instant::now() + timeout;
+    let metrics = stocks.metrics();
+    if let Err(e) = stream.set_write_timeout(Some(timeout)) {
+        metrics.handshake_rejected(HandshakeRejection::Internal);
+        return Err(ConnectionError(format!(
+            "Не удалось настроить новое tcp соединение. {e}"
+        )));
+    }
+
     let mut writer = match stream.try_clone() {
         Ok(stream) => stream,
         Err(_) => {
+            metrics.handshake_rejected(HandshakeRejection::Internal);
             return Err(ConnectionError(
                 "Ошибка записи в поток нового tcp соединения".to_string(),
             ));
         }
     };
 
+    // udp сокет клиента создается на адресе, к которому он подключился. Лимиты сессий считаются по адресу клиента
+    let (local_ip, peer_ip) = match (stream.local_addr(), stream.peer_addr()) {
+        (Ok(local), Ok(peer)) => (local.ip(), peer.ip()),
+        (Err(e), _) | (_, Err(e)) => {
+            metrics.handshake_rejected(HandshakeRejection::Internal);
+            return Err(ConnectionError(format!(
+                "Не удалось получить адрес нового tcp соединения. {e}"
+            )));
+        }
+    };
+
     let mut write = |text: &str| -> Result<(), ErrType> {
         let Ok(_) =writer.write_all(text.as_bytes()) else {
             log::error!("Не удалось отправить запрос {text}");
@@ -44,43 +152,161 @@ pub fn handle_client(
     let mut reader = BufReader::new(stream);
     let sender: ServerWriter;
     let mut line = String::new();
+    // Имя клиента после успешной команды AUTH
+    let mut user: Option<String> = None;
 
     write("Вы подключились к бирже!\n")?; // Приветственное сообщение, для работы не нужно
 
     loop {
+        // Запрос прошлой итерации получил ERROR, клиент может прислать исправленный
+        if !line.is_empty() {
+            metrics.handshake_rejected(HandshakeRejection::BadRequest);
+        }
         line.clear();
-        match reader.read_line(&mut line) {
+        match read_line(&mut reader, &mut line, deadline) {
             Ok(0) => {
+                metrics.handshake_rejected(HandshakeRejection::Empty);
                 return Err(ErrType::RequestError("Пришел пустой запрос".to_string()));
             }
             Ok(_) => {
                 let input = line.trim();
                 if input.is_empty() {
+                    metrics.handshake_rejected(HandshakeRejection::Empty);
                     return Err(ErrType::RequestError("Пришел пустой запрос".to_string()));
                 }
 
                 // Разбиваем запрос по пробелам и проверяем каждую часть отдельно
                 let mut parts = input.split_whitespace();
                 match parts.next() {
+                    Some(AUTH_REQUEST) => {
+                        let Some(credentials) = credentials else {
+                            // Авторизация выключена, любой токен подходит
+                            write(OK_REQUEST)?;
+                            line.clear();
+                            continue;
+                        };
+                        let authenticated = match (parts.next(), parts.next()) {
+                            (Some(token), None) => credentials.authenticate(token),
+                            _ => None,
+                        };
+                        let Some(name) = authenticated else {
+                            log::warn!("Клиент {peer_ip} прислал неверный токен");
+                            metrics.handshake_rejected(HandshakeRejection::Unauthorized);
+                            write(&format!("ERROR: {AUTH_FAILED_ERROR} Неверный токен\n"))?;
+                            return Err(ErrType::NoAccess("Клиент прислал неверный токен".to_string()));
+                        };
+                        log::info!("Клиент {peer_ip} авторизован как {name}");
+                        user = Some(name.to_string());
+                        write(OK_REQUEST)?;
+                        // Успешная авторизация не считается отказом в начале следующей итерации
+                        line.clear();
+                        continue;
+                    }
                     Some(STREAM_REQUEST) => {
+                        if credentials.is_some() && user.is_none() {
+                            log::warn!("Клиент {peer_ip} запросил котировки без авторизации");
+                            metrics.handshake_rejected(HandshakeRejection::Unauthorized);
+                            write(&format!(
+                                "ERROR: {AUTH_REQUIRED_ERROR} Сначала нужно отправить AUTH <токен>\n"
+                            ))?;
+                            return Err(ErrType::NoAccess(
+                                "Клиент запросил котировки без авторизации".to_string(),
+                            ));
+                        }
+                        let entitlement = entitlements.as_ref().map(|e| e.of(user.as_deref()));
                         let Some(address) = parts.next() else {
                             log::warn!("В принятом запросе {input} отсутствует upd адрес");
                             write("ERROR: Не передан адрес для udp соединения\n")?;
                             continue;
                         };
 
-                        let Some(host_port) = address.strip_prefix("udp://") else {
-                            log::warn!(
+                        if address == MULTICAST_STREAM {
+                            let Some(tickers) = parts.next() else {
+                                log::warn!("В принятом запросе {input} отсутствует список котировок");
+                                write("ERROR: Не передан список котировок\n")?;
+                                continue;
+                            };
+                            let requested = tickers.split(',').map(String::from).collect::<Vec<String>>();
+                            if let Some(reply) = check_entitlement(entitlement.as_deref(), &requested) {
+                                log::warn!("Клиенту {peer_ip} отказано в котировках запроса {input}");
+                                write(&reply)?;
+                                continue;
+                            }
+                            match select_groups(tickers, groups) {
+                                Ok(selected) => {
+                                    if let Some(reply) = check_group_entitlement(entitlement.as_deref(), &selected) {
+                                        log::warn!("Клиенту {peer_ip} отказано в multicast группах запроса {input}");
+                                        write(&reply)?;
+                                        continue;
+                                    }
+                                    let addresses = selected
+                                        .iter()
+                                        .map(|group| group.address.to_string())
+                                        .collect::<Vec<String>>()
+                                        .join(",");
+                                    // Отдельного соединения нет, клиент сам подключается к группам
+                                    log::debug!("Клиенту отправлены multicast группы {addresses}");
+                                    write(&format!(
+                                        "{} {addresses} {SESSION_KEY_OPTION}={multicast_key}\n",
+                                        OK_REQUEST.trim_end()
+                                    ))?;
+                                    metrics.handshake_accepted();
+                                    return Ok(None);
+                                }
+                                Err(e) => {
+                                    log::warn!("Не удалось подобрать multicast группы для {input}. {e}");
+                                    write(&format!("ERROR: {e}\n"))?;
+                                    continue;
+                                }
+                            }
+                        }
+
+                        // Для tcp котировки идут по этому же соединению, для udp нужен адрес клиента,
+                        // а для unix путь до сокета клиента
+                        let destination = if address == TCP_STREAM {
+                            Destination::Tcp
+                        } else if address == NAT_STREAM {
+                            Destination::Nat
+                        } else if let Some(path) = address.strip_prefix(UNIX_STREAM_PREFIX) {
+                            if path.is_empty() {
+                                log::warn!("В принятом запросе {input} отсутствует путь до unix сокета");
+                                write("ERROR: Не передан путь до unix сокета\n")?;
+                                continue;
+                            }
+                            // Сервер сам подключается к сокету, поэтому удаленный клиент не должен выбирать
+                            // произвольный сокет на машине сервера
+                            let Some(dir) = unix_socket_dir else {
+                                log::warn!("Клиент {peer_ip} запросил unix сокет {path}, но unix сокеты выключены");
+                                write("ERROR: Unix сокеты на сервере выключены\n")?;
+                                continue;
+                            };
+                            if !peer_ip.to_canonical().is_loopback() {
+                                log::warn!("Клиент {peer_ip} не с этой машины запросил unix сокет {path}");
+                                write("ERROR: Unix сокет можно запросить только с машины сервера\n")?;
+                                continue;
+                            }
+                            let Some(path) = socket_in_dir(path, dir) else {
+                                log::warn!("Unix сокет {path} из запроса {input} не находится в папке {}", dir.display());
+                                write("ERROR: Unix сокет должен находиться в папке, разрешенной на сервере\n")?;
+                                continue;
+                            };
+                            Destination::Unix(path)
+                        } else {
+                            let Some(host_port) = address.strip_prefix(UDP_STREAM_PREFIX) else {
+                                log::warn!(
                                     "В принятом запросе {input} отсутствует тип соединения udp"
                                 );
-                            write("ERROR: Не передан тип соединения udp\n")?;
-                            continue;
-                        };
+                                write("ERROR: Не передан тип соединения udp\n")?;
+                                continue;
+                            };
 
-                        let Some((host, port)) = host_port.split_once(':') else {
-                            log::warn!("В принятом запросе {input} отсутствует адрес и порт");
-                            write("ERROR: Не передан адрес и порт\n")?;
-                            continue;
+                            // ipv6 адрес передается в квадратных скобках, например [::1]:1112
+                            let Ok(udp_address) = host_port.parse::<SocketAddr>() else {
+                                log::warn!("В принятом запросе {input} неверный адрес и порт");
+                                write("ERROR: Не передан адрес и порт\n")?;
+                                continue;
+                            };
+                            Destination::Udp(udp_address)
                         };
 
                         let Some(tickers) = parts.next() else {
@@ -103,12 +329,68 @@ pub fn handle_client(
                             write("ERROR: Не передан список котировок\n")?;
                             continue;
                         }
-                        // Отвечаем что все ок что бы клиент запуска udp. И сами тоже создаем udp сокет
+                        // Неизвестные, запрещенные и сверх лимита котировки отклоняют весь запрос
+                        let checked = TickerCheck::check(tickers_vec, &[], &stocks.tickers(), entitlement.as_deref());
+                        if let Some(error) = checked.error() {
+                            log::warn!("Клиенту {peer_ip} отказано в котировках запроса {input}. {error}");
+                            write(&format!("ERROR: {error}\n"))?;
+                            continue;
+                        }
+                        let tickers_vec = checked.allowed;
+
+                        // Необязательные параметры подписки
+                        let options = match SubscriptionOptions::parse(parts) {
+                            Ok(options) => options,
+                            Err(e) => {
+                                log::warn!("В принятом запросе {input} неверные параметры. {e}");
+                                write(&format!("ERROR: Неверные параметры подписки. {e}\n"))?;
+                                continue;
+                            }
+                        };
+                        // Место в лимите занимается до создания соединения, что бы не открывать лишние сокеты
+                        let session = match permit.begin_session(peer_ip) {
+                            Ok(permit) => permit,
+                            Err(e) => {
+                                log::warn!("Отказано в сессии для запроса {input}. {e}");
+                                metrics.handshake_rejected(HandshakeRejection::SessionLimit);
+                                write(&format!("ERROR: {e}\n"))?;
+                                return Err(e);
+                            }
+                        };
+                        let created = match destination {
+                            Destination::Udp(address) => udp_transport(address, local_ip, udp_ports, metrics),
+                            Destination::Tcp => {
+                                // Байты, которые клиент успел прислать вслед за запросом, уже лежат в буфере
+                                let pending = reader.buffer().to_vec();
+                                tcp_transport(reader.into_inner(), pending)
+                            }
+                            Destination::Unix(path) => unix_transport(path),
+                            Destination::Nat => nat_transport(&reader, local_ip, udp_ports, metrics),
+                        };
+                        let (transport, address) = match created {
+                            Ok(created) => created,
+                            Err(e) => {
+                                // Клиент ждет котировки, поэтому сообщаем, что их не будет
+                                log::warn!("Не удалось создать соединение для запроса {input}. {e}");
+                                metrics.handshake_rejected(HandshakeRejection::Transport);
+                                write(&format!("ERROR: {e}\n"))?;
+                                return Err(e);
+                            }
+                        };
+
+                        // Отвечаем что все ок что бы клиент запуска udp. Сокет или соединение для клиента уже готово.
+                        // Для udp в ответе передается адрес, с которого пойдут котировки
                         log::debug!("Пришел корректный запрос {input}");
-                        write(common_lib::OK_REQUEST)?;
-                        let address = format!("{host}:{port}");
+                        match transport.endpoint() {
+                            Some(endpoint) => {
+                                write(&format!("{} {endpoint}\n", OK_REQUEST.trim_end()))?
+                            }
+                            None => write(OK_REQUEST)?,
+                        }
 
-                        let Some(receiver) = stocks.create_channel(&address) else {
+                        let Some(channel) = stocks.create_channel(&address, &tickers_vec, &options)
+                        else {
+                            metrics.handshake_rejected(HandshakeRejection::Internal);
                             write(
                                 "ERROR: Произошла ошибка сервера при создании канала свзи",
                             )?;
@@ -117,7 +399,17 @@ pub fn handle_client(
                                     .to_string(),
                             ));
                         };
-                        sender = ServerWriter::start(address, tickers_vec, receiver)?;
+                        sender = ServerWriter::new(
+                            transport,
+                            address,
+                            tickers_vec,
+                            channel,
+                            stocks.clone(),
+                            options,
+                        )
+                        .with_permit(session)
+                        .with_entitlement(entitlement);
+                        metrics.handshake_accepted();
                         break;
                     }
                     _ => {
@@ -126,13 +418,313 @@ pub fn handle_client(
                     }
                 };
             }
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                log::warn!("Клиент не прислал запрос за {} сек", timeout.as_secs());
+                metrics.handshake_rejected(HandshakeRejection::Timeout);
+                let _ = write("ERROR: Истекло время ожидания запроса\n");
+                return Err(ConnectionError(
+                    "Истекло время ожидания запроса клиента".to_string(),
+                ));
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                log::warn!("Клиент прислал неверный запрос. {e}");
+                metrics.handshake_rejected(HandshakeRejection::InvalidLine);
+                let _ = write(&format!("ERROR: {e}\n"));
+                return Err(ErrType::RequestError(e.to_string()));
+            }
             Err(e) => {
                 log::error!("Произошла ошибка в соединение {:?}", e);
+                metrics.handshake_rejected(HandshakeRejection::Internal);
                 return Err(ConnectionError(format!(
                     "Произошла ошибка в соединении {e:?}",
                 )));
             }
         }
     }
-    Ok(sender)
+    Ok(Some(sender))
+}
+
+///
+/// Прочитать строку рукопожатия не дольше срока и не длиннее MAX_HANDSHAKE_LINE_SIZE. Ожидание каждого
+/// чтения ограничено оставшимся временем, поэтому клиент не может растянуть строку, присылая ее по байту
+///
+/// # Arguments
+///
+/// * `reader`: tcp соединение клиента
+/// * `line`: строка, в которую дописывается прочитанное
+/// * `deadline`: до какого момента клиент должен прислать строку
+///
+/// returns: io::Result<usize> - количество прочитанных байт. Ошибка TimedOut если срок прошел,
+///     InvalidData если строка слишком длинная или не в utf8
+///
+fn read_line(
+    reader: &mut BufReader<TcpStream>,
+    line: &mut String,
+    deadline: Instant,
+) -> io::Result<usize> {
+    let mut bytes = Vec::new();
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        reader.get_ref().set_read_timeout(Some(remaining))?;
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        // Соединение закрыто, отдаем то, что успели прочитать
+        if buf.is_empty() {
+            break;
+        }
+        let (used, done) = match buf.iter().position(|&b| b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (buf.len(), false),
+        };
+        bytes.extend_from_slice(&buf[..used]);
+        reader.consume(used);
+        if bytes.len() > MAX_HANDSHAKE_LINE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Строка запроса длиннее {MAX_HANDSHAKE_LINE_SIZE} байт"),
+            ));
+        }
+        if done {
+            break;
+        }
+    }
+    let text = String::from_utf8(bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    line.push_str(&text);
+    Ok(text.len())
+}
+
+///
+/// Создать udp сокет для отправки котировок клиенту
+///
+/// # Arguments
+///
+/// * `address`: адрес клиента
+/// * `local_ip`: адрес сервера, к которому подключился клиент
+/// * `udp_ports`: порты, с которых сервер отправляет котировки
+/// * `metrics`: счетчики сервера, в них считаются отброшенные датаграммы
+///
+/// returns: Result<(Box<dyn Transport>, String), ErrType> - соединение и адрес клиента для хранителя котировок
+///
+fn udp_transport(
+    address: SocketAddr,
+    local_ip: IpAddr,
+    udp_ports: Option<PortRange>,
+    metrics: &Arc<Metrics>,
+) -> Result<(Box<dyn Transport>, String), ErrType> {
+    let transport = UdpTransport::bind(address, local_ip, udp_ports, metrics.clone()).map_err(|e| {
+        ConnectionError(format!("Не удалось создать udp сокет для {address}. {e}"))
+    })?;
+    Ok((Box::new(transport), address.to_string()))
+}
+
+///
+/// Создать udp сокет для клиента за NAT
+///
+/// # Arguments
+///
+/// * `reader`: tcp соединение клиента. По нему клиент различается, пока не прислал регистрацию
+/// * `local_ip`: адрес сервера, к которому подключился клиент
+/// * `udp_ports`: порты, с которых сервер отправляет котировки
+/// * `metrics`: счетчики сервера, в них считаются отброшенные датаграммы
+///
+/// returns: Result<(Box<dyn Transport>, String), ErrType> - соединение и адрес клиента для хранителя котировок
+///
+fn nat_transport(
+    reader: &BufReader<TcpStream>,
+    local_ip: IpAddr,
+    udp_ports: Option<PortRange>,
+    metrics: &Arc<Metrics>,
+) -> Result<(Box<dyn Transport>, String), ErrType> {
+    let address = match reader.get_ref().peer_addr() {
+        Ok(peer) => format!(
+            "{NAT_STREAM}://{}",
+            SocketAddr::new(peer.ip().to_canonical(), peer.port())
+        ),
+        Err(e) => {
+            return Err(ConnectionError(format!(
+                "Не удалось получить адрес tcp клиента. {e}"
+            )));
+        }
+    };
+    let transport = UdpTransport::bind_nat(local_ip, udp_ports, metrics.clone()).map_err(|e| {
+        ConnectionError(format!("Не удалось создать udp сокет для {address}. {e}"))
+    })?;
+    Ok((Box::new(transport), address))
+}
+
+///
+/// Отправлять котировки кадрами через tcp соединение запроса STREAM
+///
+/// # Arguments
+///
+/// * `stream`: tcp соединение клиента
+/// * `pending`: байты, которые прочитали из соединения вместе с запросом
+///
+/// returns: Result<(Box<dyn Transport>, String), ErrType> - соединение и адрес клиента для хранителя котировок
+///
+fn tcp_transport(
+    stream: TcpStream,
+    pending: Vec<u8>,
+) -> Result<(Box<dyn Transport>, String), ErrType> {
+    let address = match stream.peer_addr() {
+        Ok(peer) => format!("tcp://{peer}"),
+        Err(e) => {
+            return Err(ConnectionError(format!(
+                "Не удалось получить адрес tcp клиента. {e}"
+            )));
+        }
+    };
+    let transport = TcpTransport::new(stream, pending).map_err(|e| {
+        ConnectionError(format!("Не удалось настроить tcp соединение с {address}. {e}"))
+    })?;
+    Ok((Box::new(transport), address))
+}
+
+///
+/// Проверить, что unix сокет клиента находится в разрешенной папке. Путь приводится к каноническому,
+/// поэтому `..` и символические ссылки не выводят за пределы папки
+///
+/// # Arguments
+///
+/// * `path`: путь до сокета из запроса
+/// * `dir`: разрешенная папка в каноническом виде
+///
+/// returns: Option<PathBuf> - канонический путь до сокета, None если сокета нет или он вне папки
+///
+fn socket_in_dir(path: &str, dir: &Path) -> Option<PathBuf> {
+    let path = Path::new(path).canonicalize().ok()?;
+    path.starts_with(dir).then_some(path)
+}
+
+///
+/// Подключиться к unix сокету клиента
+///
+/// # Arguments
+///
+/// * `path`: путь до файла сокета
+///
+/// returns: Result<(Box<dyn Transport>, String), ErrType> - соединение и адрес клиента для хранителя котировок
+///
+#[cfg(unix)]
+fn unix_transport(path: PathBuf) -> Result<(Box<dyn Transport>, String), ErrType> {
+    let address = format!("{UNIX_STREAM_PREFIX}{}", path.display());
+    let transport = UnixTransport::connect(&path).map_err(|e| {
+        ConnectionError(format!("Не удалось подключиться к unix сокету {address}. {e}"))
+    })?;
+    Ok((Box::new(transport), address))
+}
+
+#[cfg(not(unix))]
+fn unix_transport(path: PathBuf) -> Result<(Box<dyn Transport>, String), ErrType> {
+    Err(ErrType::NotSupported(format!(
+        "Unix сокет {} не поддерживается на этой платформе",
+        path.display()
+    )))
+}
+
+///
+/// Проверить права клиента на котировки запроса
+///
+/// # Arguments
+///
+/// * `entitlement`: права клиента. None если права не ограничены
+/// * `tickers`: запрошенные котировки
+///
+/// returns: Option<String> - ответ клиенту со списком запрещенных котировок, None если разрешены все
+///
+fn check_entitlement(entitlement: Option<&Entitlement>, tickers: &[String]) -> Option<String> {
+    let denied = entitlement?.denied(tickers);
+    if denied.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "ERROR: {NOT_ENTITLED_ERROR} Нет доступа к котировкам {}\n",
+        denied.join(",")
+    ))
+}
+
+///
+/// Проверить права клиента на multicast группы. В группу рассылаются все ее котировки, поэтому права
+/// нужны на каждую из них, а не только на запрошенные
+///
+/// # Arguments
+///
+/// * `entitlement`: права клиента. None если права не ограничены
+/// * `groups`: группы, которые клиент будет слушать
+///
+/// returns: Option<String> - ответ клиенту с котировками групп, на которые нет прав. None если права есть
+///
+fn check_group_entitlement(entitlement: Option<&Entitlement>, groups: &[&MulticastGroup]) -> Option<String> {
+    let delivered = groups
+        .iter()
+        .flat_map(|group| group.tickers.iter().cloned())
+        .collect::<Vec<String>>();
+    check_entitlement(entitlement, &delivered)
+}
+
+///
+/// Подобрать multicast группы в которые рассылаются запрошенные котировки
+///
+/// # Arguments
+///
+/// * `tickers`: котировки клиента через запятую
+/// * `groups`: все multicast группы сервера
+///
+/// returns: Result<Vec<&MulticastGroup>, ErrType> - группы без повторов
+///
+fn select_groups<'a>(tickers: &str, groups: &'a [MulticastGroup]) -> Result<Vec<&'a MulticastGroup>, ErrType> {
+    let mut selected: Vec<&MulticastGroup> = Vec::new();
+    for ticker in tickers.split(',') {
+        let Some(group) = groups.iter().find(|g| g.tickers.iter().any(|t| t == ticker)) else {
+            return Err(ErrType::NotSupported(format!(
+                "Котировка {ticker} не рассылается через multicast"
+            )));
+        };
+        if !selected.iter().any(|g| g.address == group.address) {
+            selected.push(group);
+        }
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn group(port: u16, tickers: &[&str]) -> MulticastGroup {
+        MulticastGroup {
+            address: SocketAddrV4::new(Ipv4Addr::new(239, 1, 1, 1), port),
+            tickers: tickers.iter().map(|t| t.to_string()).collect(),
+            ttl: 1,
+            interface: Ipv4Addr::UNSPECIFIED,
+        }
+    }
+
+    #[test]
+    fn multicast_group_requires_every_ticker() {
+        let groups = [group(5000, &["AAPL", "MSFT"]), group(5001, &["TSLA"])];
+        let entitlement = "AAPL,TSLA".parse::<Entitlement>().unwrap();
+
+        let selected = select_groups("AAPL", &groups).unwrap();
+        assert_eq!(selected.len(), 1);
+        let reply = check_group_entitlement(Some(&entitlement), &selected).unwrap();
+        assert!(reply.contains(NOT_ENTITLED_ERROR) && reply.contains("MSFT") && !reply.contains("AAPL"), "{reply}");
+
+        let selected = select_groups("TSLA", &groups).unwrap();
+        assert!(check_group_entitlement(Some(&entitlement), &selected).is_none());
+        let selected = select_groups("AAPL,TSLA,MSFT", &groups).unwrap();
+        assert_eq!(selected.len(), 2);
+        assert!(check_group_entitlement(None, &selected).is_none());
+        assert!(select_groups("NVDA", &groups).is_err());
+    }
 }
@@ -0,0 +1,523 @@
+use crate::metrics::Metrics;
+use common_lib::datagram_auth::{Direction, SessionKey};
+use common_lib::errors::ErrType;
+use common_lib::framing::{FrameReader, encode_frame};
+#[cfg(target_os = "linux")]
+use common_lib::udp_batch::send_batch;
+use common_lib::{REGISTER_REQUEST, SESSION_KEY_OPTION, TCP_WRITE_WAIT_PERIOD, UDP_STREAM_PREFIX};
+use mio::event::Source;
+#[cfg(unix)]
+use mio::net::UnixStream;
+use mio::net::{TcpStream, UdpSocket};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Instant;
+
+/// Канал до клиента, через который ServerWriter отправляет котировки и получает запросы клиента.
+/// Сокеты не блокирующиеся, их события ждет цикл событий сервера
+pub trait Transport: Send {
+    ///
+    /// Отправить сообщение клиенту
+    ///
+    fn send(&mut self, message: &[u8]) -> io::Result<()>;
+
+    ///
+    /// Отправить клиенту несколько сообщений подряд
+    ///
+    /// returns: io::Result<usize> - сколько сообщений передано сокету. Только они учитываются в метриках
+    ///
+    fn send_batch(&mut self, messages: &[Vec<u8>]) -> io::Result<usize> {
+        for message in messages {
+            self.send(message)?;
+        }
+        Ok(messages.len())
+    }
+
+    ///
+    /// Получить сообщение клиента не дожидаясь его
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: буфер для сообщения
+    ///
+    /// returns: io::Result<Option<usize>> - длина сообщения или None если все пришедшее уже прочитано
+    ///
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>>;
+
+    ///
+    /// Нужно ли проверять, что клиент жив, через ping. Для потоковых соединений это делает сама ОС
+    ///
+    fn needs_ping(&self) -> bool;
+
+    ///
+    /// Адрес, с которого сервер отправляет котировки. Передается клиенту в ответе OK, что бы он сразу
+    /// знал, куда отправлять ping, и принимал сообщения только с этого адреса. Клиенту за NAT вместе
+    /// с адресом передается токен регистрации, а для udp еще и ключ подписи датаграмм
+    ///
+    fn endpoint(&self) -> Option<String> {
+        None
+    }
+
+    ///
+    /// Сокет соединения, который регистрируется в цикле событий
+    ///
+    fn source(&mut self) -> &mut dyn Source;
+
+    ///
+    /// Дописать в сокет сообщения, которые не поместились в буфер ОС. Вызывается, когда сокет снова готов к записи
+    ///
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Диапазон портов, с которых сервер отправляет котировки по udp. Нужен, когда firewall пропускает только известные порты
+#[derive(Debug, Clone, Copy)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = ErrType;
+
+    ///
+    /// Разобрать диапазон в виде `40000-40100` или один порт `40000`
+    ///
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start = start.trim().parse::<u16>()?;
+        let end = end.trim().parse::<u16>()?;
+        if start == 0 || start > end {
+            return Err(ErrType::ParseError(format!("Неверный диапазон портов {s}")));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+/// Отправка котировок по udp на адрес, который клиент передал в запросе STREAM. Если клиент за NAT,
+/// то адрес становится известен, когда клиент пришлет сообщение регистрации с токеном из ответа OK.
+/// Все датаграммы в обе стороны подписываются ключом сессии, датаграммы без верной подписи отбрасываются
+pub struct UdpTransport {
+    socket: UdpSocket,
+    addr: Option<SocketAddr>,
+    /// Токен регистрации клиента за NAT
+    token: Option<String>,
+    key: SessionKey,
+    /// В метриках считаются отброшенные датаграммы
+    metrics: Arc<Metrics>,
+}
+
+impl UdpTransport {
+    ///
+    /// Создать сокет для клиента
+    ///
+    /// # Arguments
+    ///
+    /// * `addr`: адрес клиента
+    /// * `local_ip`: адрес сервера, к которому подключился клиент
+    /// * `ports`: порты сервера для udp. Если не заданы, то порт выдает ОС
+    /// * `metrics`: счетчики сервера
+    ///
+    /// returns: io::Result<UdpTransport>
+    ///
+    pub fn bind(
+        addr: SocketAddr,
+        local_ip: IpAddr,
+        ports: Option<PortRange>,
+        metrics: Arc<Metrics>,
+    ) -> io::Result<Self> {
+        // Так как для udp у сервера должен быть отдельный сокет, то для каждого клиента берется свободный порт.
+        // Сокет создается на том же адресе, к которому подключился клиент, тогда котировки приходят ровно
+        // с адреса из ответа OK. Если клиент ждет котировки по другому протоколу, то берется адрес,
+        // с которого ОС отправляет пакеты клиенту
+        let local_ip = local_ip.to_canonical();
+        let ip = if local_ip.is_ipv4() == addr.is_ipv4() {
+            local_ip
+        } else {
+            Self::route_ip(addr)?
+        };
+        Ok(Self {
+            socket: Self::bind_socket(ip, ports)?,
+            addr: Some(addr),
+            token: None,
+            key: Self::session_key()?,
+            metrics,
+        })
+    }
+
+    ///
+    /// Создать сокет для клиента за NAT. Адрес клиента неизвестен, пока он не пришлет регистрацию с токеном
+    ///
+    /// # Arguments
+    ///
+    /// * `local_ip`: адрес сервера, к которому подключился клиент
+    /// * `ports`: порты сервера для udp. Если не заданы, то порт выдает ОС
+    /// * `metrics`: счетчики сервера
+    ///
+    /// returns: io::Result<UdpTransport>
+    ///
+    pub fn bind_nat(local_ip: IpAddr, ports: Option<PortRange>, metrics: Arc<Metrics>) -> io::Result<Self> {
+        // Токен знают только сервер и клиент, поэтому чужой пакет не перехватит котировки
+        let token = format!("{:016x}", rand::random::<u64>());
+        Ok(Self {
+            socket: Self::bind_socket(local_ip.to_canonical(), ports)?,
+            addr: None,
+            token: Some(token),
+            key: Self::session_key()?,
+            metrics,
+        })
+    }
+
+    ///
+    /// Создать случайный ключ подписи датаграмм для новой сессии
+    ///
+    fn session_key() -> io::Result<SessionKey> {
+        SessionKey::new(rand::random()).map_err(|e| io::Error::other(e.to_string()))
+    }
+
+    fn bind_socket(ip: IpAddr, ports: Option<PortRange>) -> io::Result<UdpSocket> {
+        let socket = match ports {
+            Some(ports) => Self::bind_in_range(ip, ports)?,
+            None => std::net::UdpSocket::bind((ip, 0))?,
+        };
+        socket.set_nonblocking(true)?;
+        Ok(UdpSocket::from_std(socket))
+    }
+
+    ///
+    /// Проверить сообщение регистрации клиента за NAT. Сообщение имеет вид `REG <токен>`
+    ///
+    fn is_registration(&self, message: &[u8]) -> bool {
+        let Some(token) = &self.token else {
+            return false;
+        };
+        let Some(rest) = message.strip_prefix(REGISTER_REQUEST.as_slice()) else {
+            return false;
+        };
+        rest.trim_ascii() == token.as_bytes()
+    }
+
+    ///
+    /// Узнать адрес сервера, с которого ОС отправляет пакеты клиенту. Подключение udp сокета
+    /// ничего не отправляет, только выбирает маршрут
+    ///
+    fn route_ip(addr: SocketAddr) -> io::Result<IpAddr> {
+        let unspecified = match addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let probe = std::net::UdpSocket::bind((unspecified, 0))?;
+        probe.connect(addr)?;
+        Ok(probe.local_addr()?.ip())
+    }
+
+    ///
+    /// Занять первый свободный порт из диапазона
+    ///
+    fn bind_in_range(ip: IpAddr, ports: PortRange) -> io::Result<std::net::UdpSocket> {
+        for port in ports.start..=ports.end {
+            match std::net::UdpSocket::bind((ip, port)) {
+                Ok(socket) => return Ok(socket),
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("Все udp порты {}-{} заняты", ports.start, ports.end),
+        ))
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        // Пока клиент за NAT не зарегистрировался, отправлять некуда. Снимок он получит после регистрации
+        let Some(addr) = self.addr else {
+            return Ok(());
+        };
+        // udp не гарантирует доставку, поэтому ошибку отправки только логируем
+        let frame = self.key.sign(Direction::ToClient, message);
+        if let Err(e) = self.socket.send_to(&frame, addr) {
+            log::warn!("Не удалось отправить сообщение клиенту {addr}. {e}");
+        }
+        Ok(())
+    }
+
+    fn send_batch(&mut self, messages: &[Vec<u8>]) -> io::Result<usize> {
+        // Пока клиент за NAT не зарегистрировался, ничего не отправлено
+        let Some(addr) = self.addr else {
+            return Ok(0);
+        };
+        let frames = messages
+            .iter()
+            .map(|message| self.key.sign(Direction::ToClient, message))
+            .collect::<Vec<Vec<u8>>>();
+        // На Linux все сообщения уходят одним системным вызовом sendmmsg, на остальных платформах по одному
+        #[cfg(target_os = "linux")]
+        let result = send_batch(&self.socket, &frames, addr);
+        #[cfg(not(target_os = "linux"))]
+        let result = frames
+            .iter()
+            .try_for_each(|frame| self.socket.send_to(frame, addr).map(|_| ()));
+        // udp не гарантирует доставку, поэтому ошибку только логируем, но сообщения не считаем отправленными
+        if let Err(e) = result {
+            log::warn!("Не удалось отправить сообщения клиенту {addr}. {e}");
+            return Ok(0);
+        }
+        Ok(frames.len())
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        loop {
+            match self.socket.recv_from(buf) {
+                Ok((n, from)) => {
+                    // Подпись проверяется первой, поэтому без ключа нельзя ни зарегистрироваться, ни прислать ping
+                    let Some(message) = self.key.verify(Direction::ToServer, &buf[..n]) else {
+                        log::warn!("Получена датаграмма с неверной подписью или повторным номером от {from}");
+                        self.metrics.datagram_rejected();
+                        continue;
+                    };
+                    let n = message.len();
+                    if self.addr.is_none() && self.is_registration(&buf[..n]) {
+                        // Котировки идут на адрес, который видно после NAT
+                        log::info!("Клиент за NAT зарегистрировался с адреса {from}");
+                        self.addr = Some(from);
+                        return Ok(Some(n));
+                    }
+                    if self.addr != Some(from) {
+                        // Запросы не от клиента игнорируем и читаем дальше
+                        log::warn!(
+                            "Получен запрос от неизвестного адреса {from}: {}",
+                            String::from_utf8_lossy(&buf[..n])
+                        );
+                        self.metrics.datagram_rejected();
+                        continue;
+                    }
+                    return Ok(Some(n));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn needs_ping(&self) -> bool {
+        true
+    }
+
+    fn endpoint(&self) -> Option<String> {
+        let address = self.socket.local_addr().ok()?;
+        let key = &self.key;
+        match &self.token {
+            Some(token) => Some(format!("{UDP_STREAM_PREFIX}{address} {token} {SESSION_KEY_OPTION}={key}")),
+            None => Some(format!("{UDP_STREAM_PREFIX}{address} {SESSION_KEY_OPTION}={key}")),
+        }
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        &mut self.socket
+    }
+}
+
+/// Отправка котировок кадрами через tcp соединение, по которому клиент прислал запрос STREAM
+pub struct TcpTransport {
+    stream: TcpStream,
+    reader: FrameReader,
+    output: OutputBuffer,
+}
+
+impl TcpTransport {
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: tcp соединение клиента
+    /// * `pending`: байты, которые прочитали из соединения вместе с запросом STREAM
+    ///
+    /// returns: io::Result<TcpTransport>
+    ///
+    pub fn new(stream: std::net::TcpStream, pending: Vec<u8>) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream: TcpStream::from_std(stream),
+            reader: FrameReader::new(pending),
+            output: OutputBuffer::default(),
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.output.push(&self.stream, message)
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        receive_frame(&mut self.reader, &self.stream, buf)
+    }
+
+    fn needs_ping(&self) -> bool {
+        false
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        &mut self.stream
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush(&self.stream)
+    }
+}
+
+/// Отправка котировок кадрами через unix сокет клиента, который работает на той же машине.
+/// Сервер сам подключается к сокету, поэтому доступ к котировкам ограничивают права на файл сокета
+#[cfg(unix)]
+pub struct UnixTransport {
+    stream: UnixStream,
+    reader: FrameReader,
+    output: OutputBuffer,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    ///
+    /// Подключиться к unix сокету клиента
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: путь до файла сокета, который клиент передал в запросе STREAM
+    ///
+    /// returns: io::Result<UnixTransport>
+    ///
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream: UnixStream::from_std(stream),
+            reader: FrameReader::default(),
+            output: OutputBuffer::default(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.output.push(&self.stream, message)
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        receive_frame(&mut self.reader, &self.stream, buf)
+    }
+
+    fn needs_ping(&self) -> bool {
+        false
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        &mut self.stream
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush(&self.stream)
+    }
+}
+
+/// Кадры потокового соединения, которые еще не ушли в сокет. Если клиент не забирает данные,
+/// то кадры копятся здесь, а когда клиент не читает дольше TCP_WRITE_WAIT_PERIOD, то соединение закрывается
+#[derive(Default)]
+struct OutputBuffer {
+    data: Vec<u8>,
+    /// Когда сокет перестал принимать данные
+    blocked_since: Option<Instant>,
+}
+
+impl OutputBuffer {
+    ///
+    /// Добавить кадр с сообщением и отправить сколько получится
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: соединение
+    /// * `message`: сообщение
+    ///
+    /// returns: io::Result<()>
+    ///
+    fn push(&mut self, stream: impl Write, message: &[u8]) -> io::Result<()> {
+        self.data.extend_from_slice(&encode_frame(message)?);
+        self.flush(stream)
+    }
+
+    ///
+    /// Записать в соединение накопленные кадры, пока ОС их принимает
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: соединение
+    ///
+    /// returns: io::Result<()> - ошибка TimedOut если клиент слишком долго не забирает данные
+    ///
+    fn flush(&mut self, mut stream: impl Write) -> io::Result<()> {
+        while !self.data.is_empty() {
+            match stream.write(&self.data) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.data.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.data.is_empty() {
+            self.blocked_since = None;
+            return Ok(());
+        }
+        let blocked_since = *self.blocked_since.get_or_insert_with(Instant::now);
+        if blocked_since.elapsed() > TCP_WRITE_WAIT_PERIOD {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "Клиент не забирает данные дольше {} сек",
+                    TCP_WRITE_WAIT_PERIOD.as_secs()
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+///
+/// Прочитать кадр из потокового соединения в буфер
+///
+/// # Arguments
+///
+/// * `reader`: разбор кадров соединения
+/// * `stream`: соединение
+/// * `buf`: буфер для сообщения
+///
+/// returns: io::Result<Option<usize>> - длина сообщения или None если целых кадров больше нет
+///
+fn receive_frame(
+    reader: &mut FrameReader,
+    stream: impl Read,
+    buf: &mut [u8],
+) -> io::Result<Option<usize>> {
+    let Some(frame) = reader.read_frame(stream)? else {
+        return Ok(None);
+    };
+    if frame.len() > buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Клиент прислал сообщение длиной {}", frame.len()),
+        ));
+    }
+    buf[..frame.len()].copy_from_slice(&frame);
+    Ok(Some(frame.len()))
+}
@@ -1,177 +1,510 @@
+use common_lib::bye::ByeReason;
+use common_lib::datagram_auth::FRAME_OVERHEAD;
 use common_lib::errors::ErrType;
-use common_lib::errors::ErrType::NoAccess;
-use common_lib::stock_quote::StockQuote;
+use crate::connection_limits::SessionPermit;
+use crate::encoded_quote::{EncodedQuote, SharedQuote};
+use crate::entitlements::Entitlement;
+use crate::metrics::Metrics;
+use crate::rate_limiter::RateLimiter;
+use crate::session_stats::SessionStats;
+use crate::stock_quotes_handler::{QuoteHandler, QuotesReceiver, SessionId, SubscriberChannel};
+use crate::subscription::{SubscriptionOptions, TickerCheck};
+use crate::transport::Transport;
 use common_lib::{
-    DATA_REQUEST, PING_WAIT_PERIOD, PING_REQUEST, PONG_REQUEST, UDP_SERVER_RECEIVE_PERIOD,
+    DATA_REQUEST, DENIED_REQUEST, MAX_DATAGRAM_SIZE, PING_WAIT_PERIOD, PING_REQUEST, PONG_REQUEST, REGISTER_REQUEST, SNAPSHOT_REQUEST,
+    SUBSCRIBE_REQUEST, UNSUBSCRIBE_REQUEST,
 };
-use crossbeam_channel::{Receiver, RecvTimeoutError};
+use crossbeam_channel::TryRecvError;
+use mio::{Interest, Registry, Token};
+use std::collections::HashMap;
 use std::io;
-use std::net::UdpSocket;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Instant;
-use std::{thread, thread::JoinHandle};
 
+/// Соединение с клиентом, через которое отправляются котировки. Своего потока у соединения нет,
+/// его события обрабатывает один из циклов событий сервера
 pub struct ServerWriter {
-    pub stop: Arc<AtomicBool>,
+    /// Номер сессии клиента в хранителе котировок
+    pub session: SessionId,
     pub remote_address: String,
-    join_handle: Option<JoinHandle<()>>,
+    /// Счетчики сессии клиента
+    pub stats: Arc<SessionStats>,
+    transport: Box<dyn Transport>,
+    tickers: Vec<String>,
+    receiver: QuotesReceiver,
+    /// Хранитель котировок. Из него берутся снимки, через него меняется подписка
+    stocks: Arc<QuoteHandler>,
+    options: SubscriptionOptions,
+    limiter: RateLimiter,
+    /// Последние отправленные клиенту значения. С ними сравниваются условия фильтров
+    sent: HashMap<String, SharedQuote>,
+    ping_time: Instant,
+    /// Место в лимите сессий, освобождается вместе с соединением
+    permit: Option<SessionPermit>,
+    /// Права клиента на котировки. None если права не ограничены
+    entitlement: Option<Arc<Entitlement>>,
 }
 
 impl ServerWriter {
     ///
-    /// Создать поток в котором будет поддерживаться соединение udp соединения для передачи котировок
+    /// Создать соединение для передачи котировок
     ///
     /// # Arguments
     ///
-    /// * `addr`: хост порт для подключения. Т.е. адрес клиента
+    /// * `transport`: соединение с клиентом, udp или tcp
+    /// * `addr`: адрес клиента для логов
     /// * `tickers`: список котировок которые ожидает клиент
-    /// * `receiver`: канал откуда получаем полный список котировок
+    /// * `channel`: канал сессии клиента, откуда получаем котировки, вместе с номером сессии и счетчиками
+    /// * `stocks`: хранитель котировок для ответа на запрос снимка и изменения подписки
+    /// * `options`: параметры подписки клиента
     ///
-    /// returns: Result<ServerWriter, ErrType>
+    /// returns: ServerWriter
     ///
-    pub fn start(
+    pub fn new(
+        transport: Box<dyn Transport>,
         addr: String,
         tickers: Vec<String>,
-        receiver: Receiver<Arc<Vec<StockQuote>>>,
-    ) -> Result<Self, ErrType> {
-        let stop = Arc::new(AtomicBool::new(false));
-        let stop_clone = stop.clone();
-
-        let mut result = Self {
-            stop,
-            remote_address: addr.clone(),
-            join_handle: None,
-        };
-
-        result.join_handle = Some(thread::spawn(move || {
-            Self::send(stop_clone, addr.clone(), tickers, receiver)
-        }));
-
-        Ok(result)
-    }
-
-    ///
-    /// Установить флаг для завершения работы потоков которые поддерживают соединение
-    ///
-    pub fn stop(&mut self) -> Result<(), ErrType> {
-        self.stop.store(true, Ordering::Release);
-        if let Some(h) = self.join_handle.take() {
-            match h.join() {
-                Ok(_) => (),
-                Err(_) => {
-                    log::error!(
-                        "Ошибка разрыва соединения с клиентом {}",
-                        self.remote_address
-                    );
-                    return Err(NoAccess(
-                        format!(
-                            "Не удалось завершить работу потока обеспечивающего соединение с {}",
-                            self.remote_address
-                        )
-                        .to_string(),
-                    ));
-                }
-            }
+        channel: SubscriberChannel,
+        stocks: Arc<QuoteHandler>,
+        options: SubscriptionOptions,
+    ) -> Self {
+        Self {
+            session: channel.session,
+            remote_address: addr,
+            stats: channel.stats,
+            transport,
+            tickers,
+            receiver: channel.receiver,
+            stocks,
+            limiter: RateLimiter::new(&options),
+            options,
+            sent: HashMap::new(),
+            ping_time: Instant::now(),
+            permit: None,
+            entitlement: None,
         }
-        Ok(())
     }
 
     ///
-    /// Метод для потока в котором будет поддерживаться udp соединение с клиентом
+    /// Занять соединением место в лимите сессий. Место освобождается, когда соединение закрывается
+    ///
+    pub fn with_permit(mut self, permit: SessionPermit) -> Self {
+        self.permit = Some(permit);
+        self
+    }
+
+    ///
+    /// Ограничить котировки, на которые клиент может подписаться запросом SUB
+    ///
+    pub fn with_entitlement(mut self, entitlement: Option<Arc<Entitlement>>) -> Self {
+        self.entitlement = entitlement;
+        self
+    }
+
+    ///
+    /// Зарегистрировать сокет соединения в цикле событий
     ///
     /// # Arguments
     ///
-    /// * `stop`: флаг для плавного завершения работы потока и закрытия соединение
-    /// * `addr`: адресс клиента
-    /// * `tickers`: список котировок
-    /// * `receiver`: канал для получения данных
+    /// * `registry`: регистр цикла событий
+    /// * `token`: по нему цикл событий находит соединение
     ///
-    /// returns: ()
+    /// returns: io::Result<()>
     ///
-    pub fn send(
-        stop: Arc<AtomicBool>,
-        addr: String,
-        tickers: Vec<String>,
-        receiver: Receiver<Arc<Vec<StockQuote>>>,
-    ) {
-        // Так как для udp у сервера должен быть отдельный сокет, адрес задается дефолтный, что бы ОС выдала свободный порт
-        let Ok(socket) = UdpSocket::bind("0.0.0.0:0") else {
-            log::error!("Не удалось установить соединение с {addr}");
-            return;
-        };
-        // ограничим ожидание по времени что бы успевать чекнуть новые данные котировок
-        let Ok(_) = socket.set_read_timeout(Some(UDP_SERVER_RECEIVE_PERIOD)) else {
-            log::error!("Не удалось огранить upd соединение c {addr} по времени");
-            return;
-        };
-        let mut ping_time = Instant::now();
-        let mut buf = [0u8; 2048];
+    pub fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        registry.register(
+            self.transport.source(),
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        )
+    }
+
+    ///
+    /// Забрать новые котировки из канала и отправить то, что разрешено ограничениями клиента.
+    /// Заодно проверяется, что клиент присылает ping
+    ///
+    /// returns: bool - false если соединение нужно закрыть
+    ///
+    pub fn on_tick(&mut self) -> bool {
+        let addr = &self.remote_address;
+        // Завершение когда долго не было ping от клиента. Потоковые соединения ping не присылают
+        if self.transport.needs_ping() && self.ping_time.elapsed() > PING_WAIT_PERIOD {
+            log::warn!("Разрываем соединение с {addr} потому что не получали ping больше {} сек", PING_WAIT_PERIOD.as_secs());
+            self.stocks.metrics().ping_timeout();
+            Self::say_bye(self.transport.as_mut(), addr, ByeReason::PingTimeout);
+            return false;
+        }
 
+        // Забираем все, что накопилось в канале. Сразу не отправляем, промежуточные значения заменяются последними
         loop {
-            // Завершение когда долго не было ping от клиента
-            if Instant::now() - ping_time > PING_WAIT_PERIOD {
-                log::warn!("Разрываем соединение с {addr} потому что не получали ping больше {} сек", PING_WAIT_PERIOD.as_secs());
-                stop.store(true, Ordering::Release);
+            match self.receiver.try_recv() {
+                Ok(all_stocks) => {
+                    let filters = &self.options.filters;
+                    let sent = &self.sent;
+                    self.limiter.push(Self::filter_quotes(&self.tickers, &all_stocks).filter(|quote| {
+                        filters
+                            .get(&quote.ticker)
+                            .is_none_or(|filter| {
+                                filter.matches(quote.quote(), sent.get(&quote.ticker).map(|q| q.quote()))
+                            })
+                    }));
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // Канал закрывается, когда клиент не успевал забирать котировки или сессию закрыл администратор
+                    if self.stats.is_kicked() {
+                        log::warn!("Сессия {addr} закрыта администратором");
+                        Self::say_bye(self.transport.as_mut(), addr, ByeReason::Kicked);
+                    } else {
+                        log::error!("Закрылся канал для получения котировок");
+                        Self::say_bye(self.transport.as_mut(), addr, ByeReason::SlowConsumer);
+                    }
+                    return false;
+                }
             }
+        }
 
-            // Завершение когда вызывали метод stop
-            if stop.load(Ordering::Acquire) {
-                log::info!("Закрываем соединение с {addr}");
-                break;
+        // Отправляем то, что разрешено ограничениями клиента
+        let ready = self.limiter.take_ready();
+        if !ready.is_empty() {
+            if Self::send_quotes(self.transport.as_mut(), &self.stats, self.stocks.metrics(), ready.iter()).is_err() {
+                return false;
             }
+            Self::remember_sent(&mut self.sent, ready.into_iter());
+        }
+        true
+    }
 
-            // проверяем нет ли новых данных для котировок
-            match receiver.recv_timeout(UDP_SERVER_RECEIVE_PERIOD) {
-                Ok(all_stocks) => {
-                    // Фильтруем и сериализуем котировки
-                    let filtered_stocks = all_stocks
-                        .iter()
-                        .filter(|x| tickers.contains(&x.ticker))
-                        .collect::<Vec<&StockQuote>>();
-                    let Ok(data) = bincode::serialize(&filtered_stocks) else {
-                        log::error!("Не удалось сериализовать котировки для отправки");
-                        break;
-                    };
-                    let mut response = DATA_REQUEST.to_vec();
-                    response.extend_from_slice(&data);
-                    let _ = socket.send_to(&response, &addr);
+    ///
+    /// Обработать все пришедшие запросы клиента
+    ///
+    /// returns: bool - false если соединение нужно закрыть
+    ///
+    pub fn on_readable(&mut self) -> bool {
+        let mut buf = [0u8; 2048];
+        loop {
+            let addr = &self.remote_address;
+            let transport = self.transport.as_mut();
+            let n = match transport.receive(&mut buf) {
+                Ok(Some(n)) => n,
+                Ok(None) => {
+                    // Все пришедшее прочитано, ждем следующего события
+                    return true;
                 }
-                Err(RecvTimeoutError::Timeout) => {
-                    // Не получили котировки продолжаем цикл
+                Err(e) => {
+                    log::error!("Произошла ошибка при получении сообщения от {addr}. {e}");
+                    return false;
                 }
-                Err(RecvTimeoutError::Disconnected) => {
-                    log::error!("Закрылся канал для получения котировок");
-                    break;
+            };
+            // Сравниваем только пришедшие байты, в остальной части буфера остатки прошлых сообщений
+            let message = &buf[..n];
+            if message.starts_with(PING_REQUEST) {
+                log::info!("Клиент {} прислал PING сообщение", addr);
+                if let Err(e) = transport.send(PONG_REQUEST) {
+                    log::error!("Не удалось отправить PONG клиенту {addr}. {e}");
+                    return false;
                 }
-            }
-
-            // Проверяем ping от клиента
-            match socket.recv_from(&mut buf) {
-                Ok((n, from)) => {
-                    if from.to_string() == addr && &buf[..PING_REQUEST.len()] == PING_REQUEST {
-                        log::info!("Клиент {} прислал PING сообщение", addr);
-                        let _ = socket.send_to(PONG_REQUEST, from);
-                        ping_time = Instant::now(); // Обновляем время для последнего ping сообщения
-                    } else {
-                        // Если прислали что-то другое тогда ничего не меняем. Если ping так и не придет, тогда завершимся по таймауту
-                        log::warn!(
-                            "Получен неизвестный запрос {from}: {}",
-                            String::from_utf8_lossy(&buf[..n])
-                        )
-                    }
+                self.ping_time = Instant::now(); // Обновляем время для последнего ping сообщения
+                self.stats.ping();
+            } else if message.starts_with(SNAPSHOT_REQUEST) || message.starts_with(REGISTER_REQUEST) {
+                // Клиент за NAT после регистрации получает снимок, который не могли отправить раньше
+                log::info!("Клиент {} запросил снимок котировок", addr);
+                let Some(snapshot) = self.stocks.snapshot_of(&self.tickers) else {
+                    log::error!("Не удалось прочитать снимок котировок для {addr}");
+                    continue;
+                };
+                // Снимок отправляется без условий фильтров, клиент просит полное состояние
+                if Self::send_quotes(transport, &self.stats, self.stocks.metrics(), snapshot.iter()).is_err() {
+                    return false;
                 }
-                Err(e)
-                    if e.kind() == io::ErrorKind::WouldBlock
-                        || e.kind() == io::ErrorKind::TimedOut =>
-                {
-                    // За таймаут не получили ping. Если время остается, то на следующем цикле попробуем еще раз
+                Self::remember_sent(&mut self.sent, snapshot.iter().cloned());
+            } else if let Some(list) = message.strip_prefix(SUBSCRIBE_REQUEST.as_slice()) {
+                let list = Self::parse_tickers(list);
+                if !self.subscribe(list) {
+                    return false;
                 }
-                Err(_) => {
-                    log::error!("Произошла ошибка при получении сообщения PING от {}", addr);
-                    break;
+            } else if let Some(list) = message.strip_prefix(UNSUBSCRIBE_REQUEST.as_slice()) {
+                let list = Self::parse_tickers(list);
+                if !self.unsubscribe(list) {
+                    return false;
                 }
+            } else if let Some(reason) = ByeReason::from_message(message) {
+                // Клиент сам закрыл соединение, дальше отправлять нет смысла
+                log::info!("Клиент {addr} закрыл соединение. Причина: {reason}");
+                return false;
+            } else {
+                // Если прислали что-то другое тогда ничего не меняем. Если ping так и не придет, тогда завершимся по таймауту
+                log::warn!(
+                    "Получен неизвестный запрос {addr}: {}",
+                    String::from_utf8_lossy(message)
+                )
             }
         }
     }
+
+    ///
+    /// Добавить котировки в подписку. Клиент сразу получает их последние значения, а о неизвестных котировках,
+    /// котировках без прав и сверх MAX_SESSION_TICKERS узнает из сообщения DENY
+    ///
+    /// # Arguments
+    ///
+    /// * `tickers`: новые котировки
+    ///
+    /// returns: bool - false если соединение нужно закрыть
+    ///
+    fn subscribe(&mut self, tickers: Vec<String>) -> bool {
+        let checked = TickerCheck::check(tickers, &self.tickers, &self.stocks.tickers(), self.entitlement.as_deref());
+        // Неизвестные, запрещенные и сверх лимита котировки клиент получает одним сообщением DENY
+        let denied = checked.denied();
+        if !denied.is_empty() {
+            log::warn!("Клиенту {} отказано в котировках {}", self.remote_address, denied.join(","));
+            let mut message = DENIED_REQUEST.to_vec();
+            message.extend_from_slice(format!(" {}", denied.join(",")).as_bytes());
+            if let Err(e) = self.transport.send(&message) {
+                log::error!("Не удалось отправить DENY клиенту {}. {e}", self.remote_address);
+                return false;
+            }
+        }
+        let added = checked.allowed;
+        if added.is_empty() {
+            return true;
+        }
+        self.tickers.extend(added.iter().cloned());
+        if !self.stocks.set_tickers(self.session, &self.tickers) {
+            log::error!("Не удалось изменить подписку {}", self.remote_address);
+            return false;
+        }
+        log::info!("Клиент {} подписался на {}", self.remote_address, added.join(","));
+
+        let Some(snapshot) = self.stocks.snapshot_of(&added) else {
+            return true;
+        };
+        if Self::send_quotes(self.transport.as_mut(), &self.stats, self.stocks.metrics(), snapshot.iter()).is_err() {
+            return false;
+        }
+        Self::remember_sent(&mut self.sent, snapshot.iter().cloned());
+        true
+    }
+
+    ///
+    /// Убрать котировки из подписки
+    ///
+    /// # Arguments
+    ///
+    /// * `tickers`: котировки, от которых клиент отписался
+    ///
+    /// returns: bool - false если соединение нужно закрыть
+    ///
+    fn unsubscribe(&mut self, tickers: Vec<String>) -> bool {
+        self.tickers.retain(|t| !tickers.contains(t));
+        self.limiter.retain(&self.tickers);
+        self.sent.retain(|ticker, _| !tickers.contains(ticker));
+        if !self.stocks.set_tickers(self.session, &self.tickers) {
+            log::error!("Не удалось изменить подписку {}", self.remote_address);
+            return false;
+        }
+        log::info!("Клиент {} отписался от {}", self.remote_address, tickers.join(","));
+        true
+    }
+
+    ///
+    /// Разобрать список котировок из запроса SUB или UNSUB. Котировки перечисляются через запятую
+    ///
+    fn parse_tickers(list: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(list)
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    ///
+    /// Дописать сообщения, которые не поместились в буфер сокета
+    ///
+    /// returns: bool - false если соединение нужно закрыть
+    ///
+    pub fn on_writable(&mut self) -> bool {
+        match self.transport.flush() {
+            Ok(_) => true,
+            Err(e) => {
+                log::error!("Не удалось отправить данные клиенту {}. {e}", self.remote_address);
+                false
+            }
+        }
+    }
+
+    ///
+    /// Есть ли котировки, которые ждут ограничений частоты. Тогда цикл событий проверяет соединение чаще
+    ///
+    pub fn has_pending(&self) -> bool {
+        self.limiter.has_pending()
+    }
+
+    ///
+    /// Закрыть соединение при остановке сервера
+    ///
+    pub fn close(&mut self) {
+        log::info!("Закрываем соединение с {}", self.remote_address);
+        Self::say_bye(self.transport.as_mut(), &self.remote_address, ByeReason::ServerShutdown);
+    }
+
+    ///
+    /// Сообщить клиенту о закрытии соединения
+    ///
+    fn say_bye(transport: &mut dyn Transport, addr: &str, reason: ByeReason) {
+        if let Err(e) = transport.send(&reason.to_message()) {
+            log::warn!("Не удалось отправить BYE клиенту {addr}. {e}");
+        }
+    }
+
+    ///
+    /// Оставить только котировки клиента
+    ///
+    fn filter_quotes<'a>(
+        tickers: &'a [String],
+        all_stocks: &'a [SharedQuote],
+    ) -> impl Iterator<Item = &'a SharedQuote> {
+        all_stocks.iter().filter(|x| tickers.contains(&x.ticker))
+    }
+
+    ///
+    /// Запомнить отправленные значения котировок
+    ///
+    fn remember_sent(
+        sent: &mut HashMap<String, SharedQuote>,
+        quotes: impl Iterator<Item = SharedQuote>,
+    ) {
+        for quote in quotes {
+            sent.insert(quote.ticker.clone(), quote);
+        }
+    }
+
+    ///
+    /// Собрать из котировок DATA сообщения и отправить
+    ///
+    /// # Arguments
+    ///
+    /// * `transport`: соединение через которое идет отправка
+    /// * `stats`: счетчики сессии, в них учитываются отправленные пакеты
+    /// * `metrics`: общие счетчики отправленных сообщений и байт
+    /// * `quotes`: котировки для отправки
+    ///
+    /// returns: Result<(), ErrType>
+    ///
+    fn send_quotes<'a>(
+        transport: &mut dyn Transport,
+        stats: &SessionStats,
+        metrics: &Metrics,
+        quotes: impl Iterator<Item = &'a SharedQuote>,
+    ) -> Result<(), ErrType> {
+        let filtered_stocks = quotes.map(|q| q.as_ref()).collect::<Vec<&EncodedQuote>>();
+        let messages = encode_quotes(&filtered_stocks);
+        let sent = transport.send_batch(&messages).map_err(|e| {
+            log::error!("Не удалось отправить котировки. {e}");
+            ErrType::ConnectionError(format!("Не удалось отправить котировки. {e}"))
+        })?;
+        // Считаем только сообщения, которые ушли в сокет
+        stats.add_sent(sent as u64);
+        metrics.add_sent(&messages[..sent]);
+        Ok(())
+    }
+}
+
+/// Размер заголовка DATA сообщения вместе с длиной списка котировок, которую пишет bincode
+const DATA_HEADER_SIZE: usize = DATA_REQUEST.len() + size_of::<u64>();
+/// Максимальный размер DATA сообщения. К udp датаграмме еще дописываются номер и подпись
+const MAX_DATA_SIZE: usize = MAX_DATAGRAM_SIZE - FRAME_OVERHEAD;
+
+///
+/// Собрать DATA сообщения из закодированных котировок. Котировки раскладываются по нескольким сообщениям так,
+/// что бы каждое вместе с подписью было не больше MAX_DATAGRAM_SIZE. Сообщение это DATA, количество котировок в u64 little endian
+/// и склеенные байты котировок, то есть ровно bincode список котировок. Даже если котировок нет, возвращается одно сообщение
+///
+/// # Arguments
+///
+/// * `quotes`: котировки для отправки
+///
+/// returns: Vec<Vec<u8>>
+///
+pub fn encode_quotes(quotes: &[&EncodedQuote]) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut start = 0;
+    let mut size = DATA_HEADER_SIZE;
+    for (i, quote) in quotes.iter().enumerate() {
+        // Одна котировка больше датаграммы уходит отдельным сообщением
+        if i > start && size + quote.bytes().len() > MAX_DATA_SIZE {
+            messages.push(data_message(&quotes[start..i], size));
+            start = i;
+            size = DATA_HEADER_SIZE;
+        }
+        size += quote.bytes().len();
+    }
+    messages.push(data_message(&quotes[start..], size));
+    messages
+}
+
+///
+/// Склеить одно DATA сообщение
+///
+fn data_message(quotes: &[&EncodedQuote], size: usize) -> Vec<u8> {
+    let mut message = Vec::with_capacity(size);
+    message.extend_from_slice(DATA_REQUEST);
+    message.extend_from_slice(&(quotes.len() as u64).to_le_bytes());
+    for quote in quotes {
+        message.extend_from_slice(quote.bytes());
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_lib::stock_quote::StockQuote;
+
+    fn quotes(count: usize) -> Vec<EncodedQuote> {
+        (0..count)
+            .map(|i| {
+                EncodedQuote::new(StockQuote {
+                    ticker: format!("TICKER{i}"),
+                    price: i as u32,
+                    volume: 100,
+                    timestamp: i as i64,
+                })
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn decode(message: &[u8]) -> Vec<StockQuote> {
+        assert!(message.starts_with(DATA_REQUEST));
+        bincode::deserialize::<Vec<StockQuote>>(&message[DATA_REQUEST.len()..]).unwrap()
+    }
+
+    #[test]
+    fn split_into_datagrams() {
+        let quotes = quotes(500);
+        let messages = encode_quotes(&quotes.iter().collect::<Vec<_>>());
+        assert!(messages.len() > 1);
+
+        let mut decoded = Vec::new();
+        for message in &messages {
+            assert!(message.len() <= MAX_DATA_SIZE, "{}", message.len());
+            let part = decode(message);
+            assert!(!part.is_empty());
+            decoded.extend(part);
+        }
+        assert_eq!(decoded.len(), quotes.len());
+        for (decoded, quote) in decoded.iter().zip(&quotes) {
+            assert_eq!(decoded.ticker, quote.ticker);
+            assert_eq!(decoded.timestamp, quote.timestamp);
+        }
+    }
+
+    #[test]
+    fn empty_quotes() {
+        let messages = encode_quotes(&[]);
+        assert_eq!(messages.len(), 1);
+        assert!(decode(&messages[0]).is_empty());
+    }
 }
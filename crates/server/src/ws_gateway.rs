@@ -0,0 +1,509 @@
+use crate::connection_limits::{ConnectionLimits, HandshakePermit};
+use crate::credentials::Credentials;
+use crate::entitlements::{Entitlement, Entitlements};
+use crate::stock_quotes_handler::{QuoteHandler, SubscriberChannel};
+use crate::subscription::{SlowConsumerPolicy, SubscriptionOptions, TickerCheck};
+use crate::tcp_server;
+use common_lib::errors::ErrType;
+use common_lib::errors::ErrType::{ConnectionError, NoAccess};
+use crate::encoded_quote::SharedQuote;
+use crate::metrics::{HandshakeRejection, Metrics};
+use common_lib::stock_quote::StockQuote;
+use common_lib::{
+    AUTH_FAILED_ERROR, AUTH_REQUIRED_ERROR, TCP_CONNECTION_WAIT_PERIOD, TCP_WRITE_WAIT_PERIOD, UDP_SERVER_RECEIVE_PERIOD,
+};
+use crossbeam_channel::RecvTimeoutError;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{thread, thread::JoinHandle};
+use tungstenite::protocol::CloseFrame;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::{Message, WebSocket};
+
+/// Запрос от клиента websocket
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum WsRequest {
+    /// Авторизоваться токеном из файла токенов. Если авторизация включена, то это первое сообщение клиента
+    Auth { token: String },
+    /// Подписаться на котировки. Повторная подписка заменяет список котировок
+    Subscribe { tickers: Vec<String> },
+    /// Запросить последние значения котировок подписки
+    Snapshot,
+}
+
+/// Сообщение для клиента websocket
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum WsMessage<'a> {
+    Authenticated,
+    Subscribed { tickers: &'a [String] },
+    Quotes { quotes: Vec<JsonQuote<'a>> },
+    Error { message: String },
+}
+
+/// Котировка в json. Цена передается числом с копейками, а не целым как в udp
+#[derive(Debug, Serialize)]
+struct JsonQuote<'a> {
+    ticker: &'a str,
+    price: f64,
+    volume: u32,
+    timestamp: i64,
+}
+
+impl<'a> From<&'a StockQuote> for JsonQuote<'a> {
+    fn from(quote: &'a StockQuote) -> Self {
+        Self {
+            ticker: &quote.ticker,
+            price: quote.price as f64 / 100.0,
+            volume: quote.volume,
+            timestamp: quote.timestamp,
+        }
+    }
+}
+
+/// Проверка websocket клиентов. Токены и права те же, что и у клиентов tcp сервера
+#[derive(Default)]
+pub struct WsAccess {
+    /// Токены клиентов. Если заданы, то первым сообщением клиент должен прислать auth
+    pub credentials: Option<Arc<Credentials>>,
+    /// Права клиентов на котировки. Если не заданы, то клиент может получать любые котировки
+    pub entitlements: Option<Arc<Entitlements>>,
+}
+
+/// Websocket шлюз для браузеров и скриптов. Котировки берутся из тех же каналов хранителя котировок,
+/// что и для udp клиентов, и отправляются в json
+pub struct WsGateway {
+    stop: Arc<AtomicBool>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl WsGateway {
+    ///
+    /// Запустить поток, который принимает websocket соединения
+    ///
+    /// # Arguments
+    ///
+    /// * `address`: адрес на котором слушать соединения
+    /// * `stocks`: хранитель котировок
+    /// * `access`: токены и права клиентов
+    /// * `limits`: ограничения подключений, общие с tcp сервером
+    ///
+    /// returns: Result<WsGateway, ErrType>
+    ///
+    pub fn start(
+        address: SocketAddr,
+        stocks: Arc<QuoteHandler>,
+        access: WsAccess,
+        limits: Arc<ConnectionLimits>,
+    ) -> Result<Self, ErrType> {
+        let listener = tcp_server::bind(address).map_err(|e| {
+            ConnectionError(format!("Не удалось запустить websocket шлюз на {address}. {e}"))
+        })?;
+        listener.set_nonblocking(true).map_err(|e| {
+            ConnectionError(format!("Не удалось настроить websocket шлюз на {address}. {e}"))
+        })?;
+        log::info!("Websocket шлюз начал работу и слушает {address}");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = stop.clone();
+        Ok(Self {
+            stop,
+            join_handle: Some(thread::spawn(move || {
+                Self::accept(listener, stop_clone, stocks, Arc::new(access), limits)
+            })),
+        })
+    }
+
+    ///
+    /// Остановить шлюз. Клиентам отправляется закрытие соединения
+    ///
+    pub fn stop(&mut self) -> Result<(), ErrType> {
+        self.stop.store(true, Ordering::Release);
+        if let Some(h) = self.join_handle.take()
+            && h.join().is_err()
+        {
+            log::error!("Ошибка остановки websocket шлюза");
+            return Err(NoAccess(
+                "Не удалось завершить работу потока websocket шлюза".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Цикл приема соединений. Каждое соединение обслуживается в своем потоке
+    fn accept(
+        listener: TcpListener,
+        stop: Arc<AtomicBool>,
+        stocks: Arc<QuoteHandler>,
+        access: Arc<WsAccess>,
+        limits: Arc<ConnectionLimits>,
+    ) {
+        let mut clients: Vec<JoinHandle<()>> = Vec::new();
+        for stream in listener.incoming() {
+            if stop.load(Ordering::Acquire) {
+                log::info!("Остановка работы websocket шлюза");
+                break;
+            }
+            clients.retain(|c| !c.is_finished());
+
+            match stream {
+                Ok(stream) => {
+                    let Ok(peer) = stream.peer_addr() else {
+                        // Клиент уже отключился
+                        continue;
+                    };
+                    // Websocket клиенты входят в те же лимиты подключений и сессий, что и клиенты tcp сервера
+                    let permit = match limits.begin_handshake(peer.ip()) {
+                        Ok(permit) => permit,
+                        Err(rejection) => {
+                            log::warn!("Websocket клиенту {peer} отказано в подключении. {rejection:?}");
+                            stocks.metrics().handshake_rejected(rejection);
+                            continue;
+                        }
+                    };
+                    let stop = stop.clone();
+                    let stocks = stocks.clone();
+                    let access = access.clone();
+                    clients.push(thread::spawn(move || {
+                        if let Err(e) = Self::serve(stream, permit, stop, stocks, &access) {
+                            log::warn!("Websocket соединение закрыто с ошибкой. {e}");
+                        }
+                    }));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(TCP_CONNECTION_WAIT_PERIOD);
+                }
+                Err(e) => log::error!("Не удалось принять websocket соединение. {e}"),
+            }
+        }
+        for client in clients {
+            let _ = client.join();
+        }
+    }
+
+    ///
+    /// Обслуживание одного websocket соединения
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: tcp соединение клиента
+    /// * `permit`: разрешение на рукопожатие, после него клиент занимает место в лимите сессий
+    /// * `stop`: флаг остановки шлюза
+    /// * `stocks`: хранитель котировок
+    /// * `access`: токены и права клиентов
+    ///
+    /// returns: Result<(), ErrType>
+    ///
+    fn serve(
+        stream: TcpStream,
+        permit: HandshakePermit,
+        stop: Arc<AtomicBool>,
+        stocks: Arc<QuoteHandler>,
+        access: &WsAccess,
+    ) -> Result<(), ErrType> {
+        let error = |e: io::Error| ConnectionError(format!("Ошибка websocket соединения. {e}"));
+        stream.set_nonblocking(false).map_err(error)?;
+        let peer = stream.peer_addr().map_err(error)?;
+        let address = format!("ws://{peer}");
+        // Рукопожатие не должно висеть вечно
+        stream
+            .set_read_timeout(Some(TCP_WRITE_WAIT_PERIOD))
+            .map_err(error)?;
+        stream
+            .set_write_timeout(Some(TCP_WRITE_WAIT_PERIOD))
+            .map_err(error)?;
+        let mut socket = tungstenite::accept(stream).map_err(|e| {
+            ConnectionError(format!("Не удалось выполнить рукопожатие websocket. {e}"))
+        })?;
+        // Сессия живет до конца функции, место в лимите освобождается вместе с соединением
+        let _session = match permit.begin_session(peer.ip()) {
+            Ok(session) => session,
+            Err(e) => {
+                log::warn!("Websocket клиенту {address} отказано в сессии. {e}");
+                stocks.metrics().handshake_rejected(HandshakeRejection::SessionLimit);
+                let _ = socket.close(Some(CloseFrame {
+                    code: CloseCode::Again,
+                    reason: "превышен лимит сессий".into(),
+                }));
+                let _ = socket.flush();
+                return Err(e);
+            }
+        };
+        drop(permit);
+
+        // Первое сообщение ждем с тем же ограничением по времени, что и рукопожатие
+        let user = match &access.credentials {
+            Some(credentials) => Some(Self::authenticate(&mut socket, &address, credentials, stocks.metrics())?),
+            None => None,
+        };
+        let entitlement = access.entitlements.as_ref().map(|e| e.of(user.as_deref()));
+        // Дальше читаем с коротким ожиданием, что бы успевать отправлять котировки
+        socket
+            .get_ref()
+            .set_read_timeout(Some(UDP_SERVER_RECEIVE_PERIOD))
+            .map_err(error)?;
+        log::info!("Подключен websocket клиент {address}");
+
+        let mut tickers: Vec<String> = Vec::new();
+        let mut receiver: Option<SubscriberChannel> = None;
+        let result = Self::serve_loop(
+            &mut socket,
+            &address,
+            &stop,
+            &stocks,
+            entitlement.as_deref(),
+            &mut tickers,
+            &mut receiver,
+        );
+        if let Some(channel) = receiver {
+            stocks.remove_channel(channel.session);
+        }
+        log::info!("Websocket клиент {address} отключен");
+        result
+    }
+
+    ///
+    /// Проверить токен из первого сообщения клиента. Если токена нет или он неверный, то клиент
+    /// получает ошибку и соединение закрывается
+    ///
+    /// # Arguments
+    ///
+    /// * `socket`: websocket соединение
+    /// * `address`: адрес клиента
+    /// * `credentials`: токены клиентов
+    /// * `metrics`: счетчики сервера, в них считаются отказы
+    ///
+    /// returns: Result<String, ErrType> - имя клиента
+    ///
+    fn authenticate(
+        socket: &mut WebSocket<TcpStream>,
+        address: &str,
+        credentials: &Credentials,
+        metrics: &Metrics,
+    ) -> Result<String, ErrType> {
+        let request = loop {
+            match socket.read() {
+                Ok(Message::Text(text)) => break serde_json::from_str::<WsRequest>(&text).ok(),
+                Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => {
+                    return Err(ConnectionError(format!(
+                        "Websocket клиент {address} отключился до авторизации"
+                    )));
+                }
+                // ping/pong websocket обрабатывает сам
+                Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => {}
+                Ok(_) => break None,
+                Err(e) => {
+                    return Err(ConnectionError(format!(
+                        "Websocket клиент {address} не прислал токен. {e}"
+                    )));
+                }
+            }
+        };
+        let (error, message) = match request {
+            Some(WsRequest::Auth { token }) => match credentials.authenticate(&token) {
+                Some(name) => {
+                    log::info!("Websocket клиент {address} авторизован как {name}");
+                    Self::send(socket, &WsMessage::Authenticated)?;
+                    return Ok(name.to_string());
+                }
+                None => (AUTH_FAILED_ERROR, "Неверный токен"),
+            },
+            _ => (AUTH_REQUIRED_ERROR, "Первым сообщением нужно отправить auth с токеном"),
+        };
+        log::warn!("Websocket клиенту {address} отказано в авторизации: {error}");
+        metrics.handshake_rejected(HandshakeRejection::Unauthorized);
+        let _ = Self::send(
+            socket,
+            &WsMessage::Error {
+                message: format!("{error} {message}"),
+            },
+        );
+        let _ = socket.close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: error.into(),
+        }));
+        let _ = socket.flush();
+        Err(NoAccess(format!("Websocket клиент {address} не авторизован")))
+    }
+
+    fn serve_loop(
+        socket: &mut WebSocket<TcpStream>,
+        address: &String,
+        stop: &AtomicBool,
+        stocks: &QuoteHandler,
+        entitlement: Option<&Entitlement>,
+        tickers: &mut Vec<String>,
+        receiver: &mut Option<SubscriberChannel>,
+    ) -> Result<(), ErrType> {
+        loop {
+            if stop.load(Ordering::Acquire) {
+                let _ = socket.close(Some(CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "сервер остановлен".into(),
+                }));
+                let _ = socket.flush();
+                return Ok(());
+            }
+
+            match socket.read() {
+                Ok(Message::Text(text)) => match serde_json::from_str::<WsRequest>(&text) {
+                    Ok(WsRequest::Auth { .. }) => {
+                        // Авторизация проходит только первым сообщением, имя клиента после нее не меняется
+                        Self::send(
+                            socket,
+                            &WsMessage::Error {
+                                message: "Авторизация не требуется или уже выполнена".to_string(),
+                            },
+                        )?;
+                    }
+                    Ok(WsRequest::Subscribe { tickers: new }) => {
+                        if new.is_empty() {
+                            Self::send(
+                                socket,
+                                &WsMessage::Error {
+                                    message: "Не передан список котировок".to_string(),
+                                },
+                            )?;
+                            continue;
+                        }
+                        // Подписка заменяется целиком, поэтому при отклоненных котировках остается прежней
+                        let checked = TickerCheck::check(new, &[], &stocks.tickers(), entitlement);
+                        if let Some(error) = checked.error() {
+                            log::warn!("Websocket клиенту {address} отказано в подписке. {error}");
+                            Self::send(socket, &WsMessage::Error { message: error })?;
+                            continue;
+                        }
+                        *tickers = checked.allowed;
+                        if let Some(channel) = receiver {
+                            // Подписка меняется без пересоздания канала, сразу отдаем значения новых котировок
+                            if !stocks.set_tickers(channel.session, tickers) {
+                                return Err(NoAccess(format!(
+                                    "Не удалось изменить подписку websocket клиента {address}"
+                                )));
+                            }
+                            Self::send(socket, &WsMessage::Subscribed { tickers })?;
+                            if let Some(snapshot) = stocks.snapshot() {
+                                Self::send_quotes(socket, stocks.metrics(), tickers, &snapshot)?;
+                            }
+                        } else {
+                            // Браузеру нужны только последние значения, поэтому в очереди держим один снимок
+                            let options = SubscriptionOptions {
+                                queue_policy: SlowConsumerPolicy::Conflate,
+                                ..SubscriptionOptions::default()
+                            };
+                            let Some(channel) =
+                                stocks.create_channel(address, tickers, &options)
+                            else {
+                                return Err(NoAccess(
+                                    "Не удалось создать канал для передачи котировок".to_string(),
+                                ));
+                            };
+                            *receiver = Some(channel);
+                            Self::send(socket, &WsMessage::Subscribed { tickers })?;
+                        }
+                    }
+                    Ok(WsRequest::Snapshot) => {
+                        if let Some(snapshot) = stocks.snapshot() {
+                            Self::send_quotes(socket, stocks.metrics(), tickers, &snapshot)?;
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("Websocket клиент {address} прислал неверный запрос {text}. {e}");
+                        Self::send(
+                            socket,
+                            &WsMessage::Error {
+                                message: format!("Неверный запрос. {e}"),
+                            },
+                        )?;
+                    }
+                },
+                Ok(Message::Close(_)) => return Ok(()),
+                Ok(_) => {
+                    // ping/pong websocket обрабатывает сам, бинарные сообщения не поддерживаются
+                }
+                Err(tungstenite::Error::Io(e))
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                Err(e) => {
+                    return Err(ConnectionError(format!(
+                        "Ошибка чтения websocket {address}. {e}"
+                    )));
+                }
+            }
+
+            let Some(SubscriberChannel {
+                receiver, stats, ..
+            }) = receiver
+            else {
+                continue;
+            };
+            match receiver.recv_timeout(UDP_SERVER_RECEIVE_PERIOD) {
+                Ok(all_stocks) => {
+                    if Self::send_quotes(socket, stocks.metrics(), tickers, &all_stocks)? {
+                        stats.add_sent(1);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) if stats.is_kicked() => {
+                    log::warn!("Сессия websocket клиента {address} закрыта администратором");
+                    let _ = socket.close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: "сессия закрыта администратором".into(),
+                    }));
+                    let _ = socket.flush();
+                    return Ok(());
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    log::error!("Закрылся канал котировок websocket клиента {address}");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    ///
+    /// Отправить котировки подписки одним json сообщением. Сообщение учитывается в метриках только после отправки
+    ///
+    /// # Arguments
+    ///
+    /// * `socket`: websocket соединение
+    /// * `metrics`: счетчики отправленных сообщений и байт
+    /// * `tickers`: котировки подписки
+    /// * `all_stocks`: новые значения котировок
+    ///
+    /// returns: Result<bool, ErrType> - false если котировок подписки в пакете нет и отправлять нечего
+    ///
+    fn send_quotes(
+        socket: &mut WebSocket<TcpStream>,
+        metrics: &Metrics,
+        tickers: &[String],
+        all_stocks: &[SharedQuote],
+    ) -> Result<bool, ErrType> {
+        let quotes = all_stocks
+            .iter()
+            .filter(|x| tickers.contains(&x.ticker))
+            .map(|x| JsonQuote::from(x.quote()))
+            .collect::<Vec<JsonQuote>>();
+        if quotes.is_empty() {
+            return Ok(false);
+        }
+        let bytes = Self::send(socket, &WsMessage::Quotes { quotes })?;
+        metrics.add_sent_message(bytes);
+        Ok(true)
+    }
+
+    fn send(socket: &mut WebSocket<TcpStream>, message: &WsMessage) -> Result<usize, ErrType> {
+        let text = serde_json::to_string(message)
+            .map_err(|e| ErrType::ParseError(format!("Не удалось сериализовать в json. {e}")))?;
+        let bytes = text.len();
+        socket
+            .send(Message::text(text))
+            .map_err(|e| ConnectionError(format!("Не удалось отправить websocket сообщение. {e}")))?;
+        Ok(bytes)
+    }
+}